@@ -1,13 +1,17 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use base64::Engine;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use crossterm::{
 
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    cursor::Show,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use bytes::Bytes;
+use futures::future::{BoxFuture, FusedFuture, FutureExt};
 use futures::stream::{self, StreamExt};
-use m3u8_rs::Playlist;
+use m3u8_rs::{AlternativeMediaType, Playlist};
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout, Rect},
@@ -16,828 +20,8375 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph},
     Frame, Terminal,
 };
-use std::collections::VecDeque;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::{self, File};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
-// Surge 配色方案
-const COLOR_NEON_PURPLE: Color = Color::Magenta;
-const COLOR_NEON_PINK: Color = Color::LightMagenta;
-const COLOR_NEON_CYAN: Color = Color::Cyan;
-const COLOR_COMPLETED: Color = Color::Green;
-const COLOR_FAILED: Color = Color::Red;
-const COLOR_GRAY: Color = Color::DarkGray;
+/// Chunks 面板最多显示的方块数，片段更多时每个方块代表连续的一组片段
+const MAX_CHUNKS: usize = 100;
+
+/// `--json` 或 `-o -` 时标准输出只留给 JSON 事件或视频数据
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// 收到过 Ctrl-C：取消后按惯例以 130 退出
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// run_tui 正占用备用屏幕，这时直接打印提示会弄乱画面
+static TUI_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// 面向用户的提示信息，标准输出被占用时改写到标准错误
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STDOUT_RESERVED.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// 向标准输出写一行 JSON 事件（`--json`）
+fn emit_json(event: serde_json::Value) {
+    println!("{}", event);
+}
+
+/// 探测服务器连接上限时的起始并发数与每档的观察时长
+const CONN_PROBE_START: usize = 2;
+const CONN_PROBE_INTERVAL: Duration = Duration::from_secs(4);
+/// `--concurrent auto` 允许调到的最大并发数
+const AUTO_CONCURRENCY_MAX: usize = 32;
+/// 界面中按 + 默认最多调到的并发数，见 --max-concurrent
+const MAX_CONCURRENCY: u64 = 64;
+/// 自动并发回退后保持不变的观察轮数，之后再尝试加大
+const AUTO_CONCURRENCY_HOLD: usize = 3;
+/// 估算 ETA 时取平均的最近速度采样数，单次采样波动较大
+const ETA_SPEED_SAMPLES: usize = 10;
+/// 速度采样间隔，下载中的片段收到的数据也会计入
+const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// 检查界面中按 +/- 选定的并发数的间隔
+const CONCURRENCY_KEY_POLL: Duration = Duration::from_millis(100);
+/// ffmpeg 失败时错误信息中保留的标准错误行数
+const FFMPEG_STDERR_LINES: usize = 10;
+/// 收集 ffmpeg 标准错误时最多保留的字节数，长时间合并的进度输出不会一直占用内存
+const FFMPEG_STDERR_KEEP: usize = 64 * 1024;
+/// `--dry-run` 估算大小时探测的片段数，均匀分布在整个播放列表中
+const DRY_RUN_SAMPLES: usize = 3;
+/// 片段数达到此值时默认使用 --single-file-temp：成千上万个小文件的创建、删除和合并前的逐个检查
+/// 明显慢于顺序追加到一个文件，片段少时两者相差不大，保留逐个文件以便断点续传和补下载
+const SINGLE_FILE_TEMP_MIN_SEGMENTS: usize = 2000;
+/// --merge-jobs 大于 1 时每个中间文件包含的片段数
+const MERGE_GROUP_SIZE: usize = 500;
+
+/// 按终端显示宽度截断，超出时以 "..." 结尾；按字符截取，不会切在 UTF-8 字符中间，
+/// 中日韩文字和 emoji 占两列
+fn truncate_display(text: &str, max_width: usize) -> String {
+    use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+    if text.width() <= max_width {
+        return text.to_string();
+    }
+    let budget = max_width.saturating_sub(3);
+    let mut width = 0;
+    let mut truncated: String = text
+        .chars()
+        .take_while(|c| {
+            width += c.width().unwrap_or(0);
+            width <= budget
+        })
+        .collect();
+    truncated.push_str("...");
+    truncated
+}
+
+/// 第一个片段之后的 EXT-X-DISCONTINUITY 数量，第一个片段上的标记不造成跳变
+fn count_discontinuities(segments: &[Segment]) -> usize {
+    segments.iter().skip(1).filter(|segment| segment.discontinuity).count()
+}
+
+/// 按大小自动选择 B/KB/MB/GB，保留一位小数，与速度显示一样按 1024 进制
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn gigabytes(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
 
 /// 展开路径中的 ~ 符号
 fn expand_path(path: &str) -> PathBuf {
     PathBuf::from(shellexpand::tilde(path).as_ref())
 }
 
+/// `-o -` 表示把合并结果写到标准输出
+fn is_stdout(output: &str) -> bool {
+    output == "-"
+}
+
+/// `--output-template` 支持的变量
+const OUTPUT_TEMPLATE_VARS: &[&str] = &["title", "resolution", "date", "index", "ext"];
+
+/// 解析 `--output-template`：只检查 {…} 中的变量名，分辨率和格式要等拿到播放列表后才能展开
+fn parse_output_template(value: &str) -> Result<String, String> {
+    if value.trim().is_empty() {
+        return Err("output template is empty".to_string());
+    }
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err(format!("unclosed '{{' in output template '{}'", value));
+        };
+        let name = &rest[start + 1..start + len];
+        if !OUTPUT_TEMPLATE_VARS.contains(&name) {
+            return Err(format!(
+                "unknown variable {{{}}} in output template, expected one of {}",
+                name,
+                OUTPUT_TEMPLATE_VARS.iter().map(|var| format!("{{{}}}", var)).collect::<Vec<_>>().join(", ")
+            ));
+        }
+        rest = &rest[start + len + 1..];
+    }
+    Ok(value.to_string())
+}
+
+/// `--output-template` 中各变量的值
+struct TemplateVars<'a> {
+    title: &'a str,
+    /// 直接给出媒体播放列表时没有分辨率
+    resolution: Option<&'a str>,
+    date: String,
+    index: usize,
+    ext: &'a str,
+}
+
+/// 展开 `--output-template`；结尾的 .{ext} 会去掉，扩展名由各输出步骤按格式自己加上
+fn expand_output_template(template: &str, vars: &TemplateVars) -> String {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        // parse_output_template 已保证括号成对、变量名有效
+        let len = rest[start..].find('}').unwrap_or(rest.len() - start);
+        match &rest[start + 1..start + len] {
+            "title" => name.push_str(vars.title),
+            "resolution" => name.push_str(vars.resolution.unwrap_or("unknown")),
+            "date" => name.push_str(&vars.date),
+            "index" => name.push_str(&vars.index.to_string()),
+            "ext" => name.push_str(vars.ext),
+            other => name.push_str(other),
+        }
+        rest = rest.get(start + len + 1..).unwrap_or("");
+    }
+    name.push_str(rest);
+
+    let suffix = format!(".{}", vars.ext);
+    match name.strip_suffix(&suffix) {
+        Some(stem) if !stem.is_empty() => stem.to_string(),
+        _ => name,
+    }
+}
+
+/// Unix 时间对应的 UTC 日期 YYYY-MM-DD（Howard Hinnant 的 civil_from_days 算法）
+fn utc_date(unix_secs: u64) -> String {
+    let z = (unix_secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// 省略 -o 时的 {title}：播放列表地址中的文件名去掉扩展名
+fn playlist_title(url: &str) -> String {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.path_segments()?.next_back().map(str::to_string))
+        .and_then(|name| Path::new(&name).file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| "video".to_string())
+}
+
+/// ffmpeg 写入中的输出文件：成功退出后才改名为 path，最终文件名下的文件总是完整的
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// 用单引号包裹字符串，内部的单引号写成 '\''（shell 与 concat 列表通用）
+fn quote_single(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// 仅在包含特殊字符时才加引号，让打印出的命令保持可读
+fn shell_quote(s: &str) -> String {
+    let safe = !s.is_empty()
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=+,@%".contains(c));
+    if safe {
+        s.to_string()
+    } else {
+        quote_single(s)
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about = "M3U8下载器 - Surge四象限布局")]
 struct Args {
+    /// 配置文件路径（默认 $XDG_CONFIG_HOME/surge-wave/config.toml），其中的值作为默认值，命令行参数优先
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
     /// M3U8链接URL
-    url: String,
+    #[arg(required_unless_present_any = ["batch", "retry_file"])]
+    url: Option<String>,
 
     /// 输出文件名（不含扩展名）
-    #[arg(short, long)]
-    output: String,
+    #[arg(short, long, required_unless_present_any = ["batch", "list_formats", "dry_run", "retry_file", "output_template"])]
+    output: Option<String>,
+
+    /// 输出文件名模板，可用 {title}（-o 或批量文件中的名称，省略 -o 时取播放列表的文件名）、{resolution}（所选变体的分辨率）、
+    /// {date}（当天的 UTC 日期）、{index}（批量下载中的序号）和 {ext}（输出格式的扩展名），如 "{title}-{resolution}-{date}.{ext}"
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_output_template, conflicts_with = "retry_file")]
+    output_template: Option<String>,
+
+    /// 列出主播放列表中的所有清晰度（分辨率、码率、编码、时长）后退出，不下载
+    #[arg(short = 'F', long, conflicts_with = "batch")]
+    list_formats: bool,
+
+    /// 只解析播放列表并抽样几个片段估算总大小，不下载也不写入任何文件
+    #[arg(long, conflicts_with_all = ["batch", "list_formats"])]
+    dry_run: bool,
+
+    /// 批量下载：文件中每行一个 "URL 输出文件名"，按顺序逐个下载，共用一个 HTTP 客户端；空行和 # 开头的行会被忽略
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["url", "output"])]
+    batch: Option<String>,
+
+    /// 只重新下载上次失败的片段：读取输出目录中的 <输出文件名>.failed.json，下载到原有临时目录后重新合并。
+    /// 其它选项（--quality、--from/--to 等）需与上次相同
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["url", "output", "batch", "list_formats", "dry_run", "live", "stream_merge"])]
+    retry_file: Option<String>,
 
     /// 下载目录
     #[arg(short, long, default_value = "downloads")]
     dir: String,
 
-    /// 并发下载数
-    #[arg(short, long, default_value = "10")]
-    concurrent: usize,
+    /// 并发下载数，或 auto：从少量连接开始，按下载速度的变化自动增减；界面中按 +/- 在 1 到 --max-concurrent 之间调整
+    #[arg(short, long, default_value = "10", value_parser = parse_concurrency)]
+    concurrent: Concurrency,
+
+    /// 界面中按 + 最多能调到的并发数，小于 --concurrent 时按 --concurrent
+    #[arg(long, value_name = "N", default_value_t = MAX_CONCURRENCY, value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrent: u64,
 
     /// 自定义请求头 (可多次使用, 格式: "Key: Value")
-    #[arg(short = 'H', long = "header")]
-    headers: Vec<String>,
+    #[arg(short = 'H', long = "header", value_parser = parse_header)]
+    headers: Vec<(http::HeaderName, http::HeaderValue)>,
+
+    /// Cookie：原始的 "name=value; ..." 字符串，或 Netscape 格式 cookies.txt 的路径
+    #[arg(long, value_name = "COOKIE|FILE")]
+    cookie: Option<String>,
+
+    /// 代理地址，支持 http://、https:// 与 socks5://
+    #[arg(long, value_name = "URL", value_parser = parse_proxy_url)]
+    proxy: Option<Url>,
+
+    /// 代理认证，格式 user:pass
+    #[arg(long, value_name = "USER:PASS", requires = "proxy")]
+    proxy_auth: Option<String>,
+
+    /// 自定义 User-Agent（默认使用模拟浏览器的 UA）
+    #[arg(long, value_name = "UA", value_parser = parse_user_agent)]
+    user_agent: Option<http::HeaderValue>,
+
+    /// 写入片段文件时的缓冲区大小（字节）
+    #[arg(long, default_value = "65536", value_name = "BYTES")]
+    write_buffer_size: usize,
+
+    /// 输出格式：mp4、mkv 或 ts（默认根据编码自动选择；ts 直接拼接片段，不调用 ffmpeg）
+    #[arg(long, value_name = "FORMAT", value_parser = parse_container)]
+    format: Option<Container>,
+
+    /// ffmpeg 可执行文件的路径（默认从 PATH 中查找）
+    #[arg(long, value_name = "PATH", default_value = "ffmpeg")]
+    ffmpeg_path: String,
+
+    /// ffprobe 可执行文件的路径（默认取 --ffmpeg-path 所在目录下的 ffprobe）
+    #[arg(long, value_name = "PATH")]
+    ffprobe_path: Option<String>,
+
+    /// 合并时缩放到指定分辨率，如 1280x720（会重新编码）
+    #[arg(long, value_name = "WxH", value_parser = parse_scale)]
+    scale: Option<String>,
+
+    /// 合并时转换为指定帧率，如 30 或 30000/1001（会重新编码）
+    #[arg(long, value_parser = parse_fps)]
+    fps: Option<String>,
+
+    /// -c copy 合并失败时（如各片段的编码参数不一致）改用 libx264/aac 重新编码再试一次，耗时较长；
+    /// --stream-merge 的片段送进 ffmpeg 后无法重来，不能同时使用
+    #[arg(long)]
+    allow_reencode: bool,
+
+    /// 合并时把 ffmpeg 的输出实时显示在终端；不加时只在失败时报告最后几行（--stream-merge 同样只在失败时报告）
+    #[arg(long)]
+    verbose: bool,
+
+    /// 合并时存放文件列表和中间文件的目录（默认与片段临时目录相同）
+    #[arg(long, value_name = "DIR")]
+    merge_temp_dir: Option<String>,
+
+    /// 并行合并使用的 ffmpeg 进程数：片段较多时先分组合并成中间文件再拼接，1 为单次合并
+    #[arg(long, value_name = "N", default_value = "1", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    merge_jobs: usize,
+
+    /// 下载结束后重新获取播放列表，用新地址重试失败的片段
+    #[arg(long)]
+    refresh_failed: bool,
+
+    /// 不合并，把片段和改写后的本地 M3U8 保存到输出目录下的同名文件夹
+    #[arg(long, conflicts_with_all = ["append", "verify", "scale", "fps", "emit_concat_only"])]
+    hls_output: bool,
+
+    /// 边下载边按顺序把片段送入 ffmpeg，不在磁盘上保留完整的片段副本
+    #[arg(long, conflicts_with_all = [
+        "append", "refresh_failed", "min_segment_ratio", "hls_output", "emit_concat_only", "allow_reencode",
+    ])]
+    stream_merge: bool,
+
+    /// 片段按顺序追加到一个临时 TS 文件，而不是每个片段一个文件；失败的片段无法再补进去，也不能断点续传。
+    /// 不加值时开启，=false 关闭；默认在片段数达到 2000 且没有与之冲突的选项时自动开启
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", conflicts_with_all = [
+        "append", "refresh_failed", "min_segment_ratio", "hls_output", "emit_concat_only", "stream_merge", "resume",
+        "retry_file",
+    ])]
+    single_file_temp: Option<bool>,
+
+    /// 只下载片段并生成 filelist.txt，打印合并所需的 ffmpeg 命令后退出
+    #[arg(long, conflicts_with_all = ["append", "verify"])]
+    emit_concat_only: bool,
+
+    /// 合并后用 ffprobe 校验输出时长是否与播放列表一致
+    #[arg(long)]
+    verify: bool,
+
+    /// 合并完成后计算输出文件的 SHA-256，与文件大小一起打印
+    #[arg(long)]
+    checksum: bool,
+
+    /// 成功后也保留片段临时目录（合并失败或有片段失败时总会保留）
+    #[arg(long)]
+    keep_temp: bool,
+
+    /// 下载结束后把分块状态图保存为 PNG；--batch 时文件名后加上各条目的输出名
+    #[arg(long, value_name = "PATH")]
+    save_chunkmap: Option<String>,
+
+    /// 下载结束后把每个片段的大小和耗时写入报告，扩展名为 .json 时写 JSON，否则写 CSV；--batch 时文件名后加上各条目的输出名
+    #[arg(long, value_name = "PATH")]
+    report: Option<String>,
+
+    /// 下载前用 HEAD 请求检查所有片段是否可访问
+    #[arg(long)]
+    preflight_check: bool,
+
+    /// 预估的磁盘空间不足时仍然继续下载
+    #[arg(long)]
+    force: bool,
+
+    /// 从文件读取 Bearer token，避免出现在 shell 历史中
+    #[arg(long, value_name = "PATH", conflicts_with = "bearer_command")]
+    bearer_file: Option<String>,
+
+    /// 执行命令并使用其标准输出作为 Bearer token
+    #[arg(long, value_name = "CMD")]
+    bearer_command: Option<String>,
+
+    /// 请求片段使用的 HTTP 方法，如 POST（默认 GET）
+    #[arg(long, value_name = "METHOD", default_value = "GET", value_parser = parse_method)]
+    segment_method: http::Method,
+
+    /// 片段请求体模板，{index} 替换为片段序号，{url} 替换为片段地址；需要同时用 --segment-method 指定 GET 以外的方法
+    #[arg(long, value_name = "TEMPLATE")]
+    segment_body: Option<String>,
+
+    /// 从少量连接开始逐步加倍，吞吐量不再提升时停在服务器实际允许的连接数
+    #[arg(long)]
+    detect_conn_limit: bool,
+
+    /// 开始下载时在该秒数内把并发从 1 逐步加到 --concurrent，避免同时向 CDN 发起全部连接；0 为不限。
+    /// --concurrent auto 会自己从少量连接开始探测，不能与之同时使用
+    #[arg(long, value_name = "SECS", default_value = "0", conflicts_with = "detect_conn_limit")]
+    ramp_up: u64,
+
+    /// 清晰度：best、worst、分辨率如 720p，或码率上限如 3000k
+    #[arg(short = 'q', long, default_value = "best", value_parser = parse_quality)]
+    quality: Quality,
+
+    /// 变体使用独立音轨 (EXT-X-MEDIA) 时优先选择的语言，如 en、zh；默认选择 DEFAULT=YES 的音轨
+    #[arg(long, value_name = "LANG")]
+    audio_lang: Option<String>,
+
+    /// 下载 EXT-X-MEDIA 声明的 WebVTT 字幕，可指定语言；MKV 输出时封装进视频，
+    /// 其它情况写到输出文件旁（MP4 转为 .srt，其余为 .vtt）
+    #[arg(long, value_name = "LANG", num_args = 0..=1, default_missing_value = "")]
+    subs: Option<String>,
+
+    /// 总下载速度上限，如 500K、2M（单位为字节/秒）
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// 建立连接的超时秒数，超时算作一次失败并按 --retries 重试
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+
+    /// 等待服务器数据的超时秒数（两次读取之间的间隔，不限制整个片段的下载时长），超时后按 --retries 重试
+    #[arg(long, value_name = "SECS", default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    read_timeout: u64,
+
+    /// 片段下载失败后的重试次数
+    #[arg(long, default_value = "3")]
+    retries: u32,
+
+    /// 第一次重试前等待的毫秒数，之后每次翻倍并加入随机抖动
+    #[arg(long, value_name = "MS", default_value = "500")]
+    retry_delay_ms: u64,
+
+    /// 有片段缺失时仍然合并（默认会列出缺失片段并中止）
+    #[arg(long)]
+    allow_partial: bool,
+
+    /// 最终失败的片段超过该数量时立即停止下载并以错误退出，不再合并
+    #[arg(long, value_name = "N")]
+    max_failures: Option<usize>,
+
+    /// 最终失败的片段超过总数的该百分比时立即停止下载并以错误退出（如 5 或 5%）
+    #[arg(long, value_name = "PCT", value_parser = parse_percent)]
+    max_failure_pct: Option<f64>,
+
+    /// 有片段失败时不再询问，直接执行 --on-failure 指定的动作
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// 有片段失败且无法询问时的动作：retry（重试一轮后合并）、merge（需配合 --allow-partial）或 abort
+    #[arg(long, value_name = "ACTION", default_value = "merge", value_parser = parse_failure_action)]
+    on_failure: FailureAction,
+
+    /// 请求播放列表时使用的 Accept 头（不影响片段请求）
+    #[arg(long, default_value = "application/vnd.apple.mpegurl,*/*")]
+    accept: String,
+
+    /// 相对地址的变体、片段继承所在播放列表 URL 的查询参数（如 ?token=...），绝对地址不受影响
+    #[arg(long)]
+    inherit_query: bool,
+
+    /// 追加到已存在的输出文件末尾（用于分多次录制的直播）；点播播放列表同样会把全部片段追加上去
+    #[arg(long)]
+    append: bool,
+
+    /// 输出文件已存在时报错，而不是覆盖
+    #[arg(long, conflicts_with_all = ["append", "auto_rename"])]
+    no_overwrite: bool,
+
+    /// 输出文件已存在时改用 "名称 (1)"、"名称 (2)" 等新文件名
+    #[arg(long, conflicts_with = "append")]
+    auto_rename: bool,
+
+    /// 片段小于已完成片段大小中位数的该比例时视为截断并重新下载 (如 0.1)，取值 (0, 1]；
+    /// 重新下载失败的片段按下载失败处理
+    #[arg(long, value_name = "RATIO", value_parser = parse_ratio)]
+    min_segment_ratio: Option<f64>,
+
+    /// 直播录制：下载当前片段的同时持续刷新播放列表下载新片段，直到 EXT-X-ENDLIST 或按 q。
+    /// 播放列表没有 EXT-X-ENDLIST 时不加这个选项也会自动录制
+    #[arg(long)]
+    live: bool,
+
+    /// 直播流只下载当前窗口中的片段，不持续录制
+    #[arg(long, conflicts_with = "live")]
+    no_live: bool,
+
+    /// 直播录制低延迟 HLS（有 #EXT-X-PART-INF）：优先选择低延迟变体，按分片目标时长刷新播放列表，
+    /// 末尾未完成的片段先按部分片段 (#EXT-X-PART) 下载
+    #[arg(long, conflicts_with = "no_live")]
+    low_latency: bool,
+
+    /// 直播录制只从当前播放列表的最后 N 个片段开始，跳过 DVR 窗口中更早的内容
+    #[arg(long, value_name = "N", conflicts_with = "no_live")]
+    tail: Option<usize>,
+
+    /// 只下载从这个时间开始的片段，如 90、1:30、01:02:03.5；start 表示播放列表 EXT-X-START 建议的起点。
+    /// 裁剪在合并时作用于整个输出，--append 时会连已有文件一起裁掉，所以不能同用
+    #[arg(long, value_name = "TIME", value_parser = parse_clip_start, conflicts_with_all = ["live", "refresh_failed", "append"])]
+    from: Option<ClipStart>,
+
+    /// 只下载到这个时间为止的片段，格式同 --from
+    #[arg(long, value_name = "TIME", value_parser = parse_clip_time, conflicts_with_all = ["live", "refresh_failed", "append"])]
+    to: Option<f64>,
+
+    /// 按顺序下载，第 N 个片段完成前不会开始第 N+WINDOW 个；窗口用 --sequential=WINDOW 指定，默认为 1
+    #[arg(long, value_name = "WINDOW", num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+    sequential: Option<usize>,
+
+    /// 使用纯 ASCII 字符绘制界面（默认根据终端自动检测）
+    #[arg(long)]
+    ascii: bool,
+
+    /// 每秒向 unix socket 或命名管道（也可以是普通文件）写一行 JSON 进度事件，供监控进程读取；
+    /// 命名管道需要读取方先打开，否则不输出进度
+    #[arg(long, value_name = "PATH")]
+    progress_socket: Option<String>,
+
+    /// 活动日志保留的条数，界面中用 ↑/↓、PageUp/PageDown 翻看，End 回到最新
+    #[arg(long, value_name = "N", default_value = "500")]
+    log_history: usize,
+
+    /// 终端颜色数：mono、8 或 16（默认根据 TERM/COLORTERM 自动检测）
+    #[arg(long, value_name = "DEPTH", value_parser = parse_color_depth)]
+    color_depth: Option<ColorDepth>,
+
+    /// 界面配色：surge、solarized，或不使用颜色的 mono
+    #[arg(long, value_name = "THEME", default_value = "surge", value_parser = parse_theme)]
+    theme: ThemeName,
+
+    /// 不使用颜色，只用粗体/反显/下划线区分状态；设置了 NO_COLOR 环境变量时同样生效
+    #[arg(long)]
+    no_color: bool,
+
+    /// 断点续传：临时目录中已存在且非空的片段视为已完成，不再重新下载（与上次的 --concurrent 无关）
+    #[arg(long, conflicts_with = "live")]
+    resume: bool,
+
+    /// 速度以 Mbps（十进制比特）显示，便于与宽带套餐对比；界面中按 b 切换
+    #[arg(long)]
+    bits: bool,
+
+    /// 进度百分比按已下载的媒体时长计算，而不是按片段数；片段时长差异大时更准确。界面中按 t 切换
+    #[arg(long)]
+    progress_by_time: bool,
+
+    /// 隐藏界面中的面板：info、speed、activity、stats、chunks（可多次使用）；界面中按 1-5 切换
+    #[arg(long = "hide-panel", value_name = "PANEL", value_parser = parse_panel)]
+    hide_panels: Vec<Panel>,
+
+    /// 不显示界面，向标准输出逐行打印 JSON 事件（片段完成/失败、进度、最终结果），提示信息改写到标准错误；
+    /// 有片段失败时以非零状态退出
+    #[arg(long)]
+    json: bool,
+
+    /// 不使用全屏界面，像 curl 一样在标准错误定期输出一行进度（适合 CI 日志和 SSH）
+    #[arg(long, conflicts_with = "json")]
+    no_tui: bool,
 }
 
-#[derive(Clone)]
-struct ActivityItem {
-    name: String,
-    status: ActivityStatus,
+/// 解析 `Key: Value` 形式的请求头，缺少冒号或包含非法字符时在启动时报错
+fn parse_header(value: &str) -> Result<(http::HeaderName, http::HeaderValue), String> {
+    let (key, val) = value
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{}', expected \"Key: Value\"", value))?;
+    let name = http::HeaderName::from_bytes(key.trim().as_bytes())
+        .map_err(|_| format!("invalid header name '{}' in '{}'", key.trim(), value))?;
+    let val = http::HeaderValue::from_str(val.trim())
+        .map_err(|_| format!("invalid header value in '{}'", value))?;
+    Ok((name, val))
 }
 
-#[derive(Clone, PartialEq)]
-enum ActivityStatus {
-    Success,
-    Failed,
-    Downloading,
+fn parse_proxy_url(value: &str) -> Result<Url, String> {
+    let url = Url::parse(value).map_err(|e| format!("invalid proxy URL '{}': {}", value, e))?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => {}
+        scheme => return Err(format!("unsupported proxy scheme '{}', expected http, https or socks5", scheme)),
+    }
+    if url.host_str().is_none() {
+        return Err(format!("proxy URL '{}' has no host", value));
+    }
+    Ok(url)
 }
 
-struct DownloadStats {
-    total_segments: usize,
-    downloaded_segments: usize,
-    failed_segments: usize,
-    downloaded_bytes: u64,
-    start_time: Instant,
-    current_speed: f64,
-    speed_history: VecDeque<f64>,
-    chunk_states: Vec<ChunkState>,
-    activity_log: VecDeque<ActivityItem>,
-    last_update: Instant,
-    bytes_since_update: u64,
+/// 根据 `--proxy`/`--proxy-auth` 构造代理，所有请求（包括 HTTPS）都经过它
+fn build_proxy(url: &Url, auth: Option<&str>) -> Result<wreq::Proxy> {
+    let mut proxy = wreq::Proxy::all(url.as_str())
+        .with_context(|| format!("Invalid proxy {}", url))?;
+    if let Some(auth) = auth {
+        let (user, pass) = auth
+            .split_once(':')
+            .context("--proxy-auth expects user:pass")?;
+        proxy = proxy.basic_auth(user, pass);
+    }
+    Ok(proxy)
+}
+
+fn parse_user_agent(value: &str) -> Result<http::HeaderValue, String> {
+    http::HeaderValue::from_str(value.trim()).map_err(|_| format!("invalid user agent '{}'", value))
+}
+
+/// 解析速度上限，K/M/G 后缀按 1024 进制，与界面中的 MB/s 一致
+fn parse_rate(value: &str) -> Result<u64, String> {
+    let upper = value.trim().to_ascii_uppercase();
+    let upper = upper.strip_suffix("/S").unwrap_or(&upper);
+    let upper = upper.strip_suffix('B').unwrap_or(upper);
+    let (number, multiplier) = match upper.char_indices().last() {
+        Some((i, 'K')) => (&upper[..i], 1024.0),
+        Some((i, 'M')) => (&upper[..i], 1024.0 * 1024.0),
+        Some((i, 'G')) => (&upper[..i], 1024.0 * 1024.0 * 1024.0),
+        _ => (upper, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && (n * multiplier) >= 1.0 => Ok((n * multiplier) as u64),
+        _ => Err(format!("invalid rate '{}', expected a value such as 500K or 2M", value)),
+    }
+}
+
+/// 解析 `WxH`，返回 ffmpeg scale 滤镜使用的 `W:H`；单边可用 -1/-2 保持宽高比
+fn parse_scale(value: &str) -> Result<String, String> {
+    let (w, h) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("invalid scale '{}', expected WxH such as 1280x720", value))?;
+    let parse_side = |side: &str| -> Result<i32, String> {
+        match side.trim().parse::<i32>() {
+            Ok(n) if n > 0 || n == -1 || n == -2 => Ok(n),
+            _ => Err(format!("invalid scale dimension '{}' in '{}'", side, value)),
+        }
+    };
+    let (w, h) = (parse_side(w)?, parse_side(h)?);
+    if w < 0 && h < 0 {
+        return Err(format!("invalid scale '{}', at most one side may be -1/-2", value));
+    }
+    Ok(format!("{}:{}", w, h))
+}
+
+/// 解析帧率，接受正数 (30, 29.97) 或分数 (30000/1001)
+fn parse_fps(value: &str) -> Result<String, String> {
+    let valid = match value.split_once('/') {
+        Some((num, den)) => matches!(
+            (num.parse::<u32>(), den.parse::<u32>()),
+            (Ok(n), Ok(d)) if n > 0 && d > 0
+        ),
+        None => value.parse::<f64>().map(|fps| fps.is_finite() && fps > 0.0).unwrap_or(false),
+    };
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!("invalid fps '{}', expected a positive number such as 30 or 30000/1001", value))
+    }
+}
+
+/// 解析 (0, 1] 之间的比例
+fn parse_ratio(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => Ok(ratio),
+        _ => Err(format!("invalid ratio '{}', expected a number greater than 0 and at most 1", value)),
+    }
+}
+
+/// 解析 HTTP 方法名，只接受标准方法，避免拼写错误被当作扩展方法发出
+fn parse_method(value: &str) -> Result<http::Method, String> {
+    let method = value.to_ascii_uppercase();
+    match method.as_str() {
+        "GET" | "POST" | "PUT" | "PATCH" | "DELETE" | "OPTIONS" => {
+            http::Method::from_bytes(method.as_bytes()).map_err(|e| e.to_string())
+        }
+        _ => Err(format!("invalid segment method '{}', expected GET, POST, PUT, PATCH, DELETE or OPTIONS", value)),
+    }
+}
+
+/// `--concurrent` 的取值
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Concurrency {
+    Fixed(usize),
+    Auto,
+}
+
+fn parse_concurrency(value: &str) -> Result<Concurrency, String> {
+    if value.trim().eq_ignore_ascii_case("auto") {
+        return Ok(Concurrency::Auto);
+    }
+    match value.trim().parse::<usize>() {
+        Ok(n) if n > 0 => Ok(Concurrency::Fixed(n)),
+        _ => Err(format!("invalid concurrency '{}', expected a positive number or auto", value)),
+    }
+}
+
+/// 多码率播放列表中要下载的清晰度
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Quality {
+    Best,
+    Worst,
+    /// 指定高度，如 720p
+    Height(u64),
+    /// 码率上限（bit/s），选不超过上限的最高码率
+    MaxBandwidth(u64),
+}
+
+/// `--from` 的起点
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ClipStart {
+    Time(f64),
+    /// EXT-X-START 的 TIME-OFFSET
+    Playlist,
+}
+
+/// 解析 `--from`/`--to` 的时间：秒数或 [hh:]mm:ss[.fff]
+fn parse_clip_time(value: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid time '{}', expected seconds like 90 or a timestamp like 1:30", value);
+    let mut seconds = 0.0;
+    let parts: Vec<&str> = value.trim().split(':').collect();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+    for (i, part) in parts.iter().enumerate() {
+        let number: f64 = part.parse().map_err(|_| invalid())?;
+        // 只有最后一段可以带小数，前面的分、秒不能超过 59
+        if !number.is_finite() || number < 0.0 || (i > 0 && number >= 60.0) || (i + 1 < parts.len() && number.fract() != 0.0) {
+            return Err(invalid());
+        }
+        seconds = seconds * 60.0 + number;
+    }
+    Ok(seconds)
+}
+
+/// 解析 `--max-failure-pct`：0 到 100 之间的数，可以带 %
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let number = value.trim().trim_end_matches('%').trim();
+    match number.parse::<f64>() {
+        Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(pct),
+        _ => Err(format!("invalid percentage '{}', expected a number between 0 and 100", value)),
+    }
+}
+
+fn parse_clip_start(value: &str) -> Result<ClipStart, String> {
+    if value.trim().eq_ignore_ascii_case("start") {
+        return Ok(ClipStart::Playlist);
+    }
+    parse_clip_time(value).map(ClipStart::Time)
+}
+
+/// 选出与 [from, to) 有重叠的片段，返回片段范围和第一个选中片段的开始时间。
+/// 片段边界通常不与请求的时间重合，剩下的部分由 ffmpeg 按 -ss/-to 精确裁掉
+fn select_clip(segments: &[Segment], from: f64, to: Option<f64>) -> (std::ops::Range<usize>, f64) {
+    let mut start = 0.0;
+    let mut first = None;
+    let mut end = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        let segment_end = start + segment.duration;
+        if to.is_some_and(|to| start >= to) {
+            break;
+        }
+        if segment_end > from {
+            first.get_or_insert((i, start));
+            end = i + 1;
+        }
+        start = segment_end;
+    }
+    match first {
+        Some((i, first_start)) => (i..end, first_start),
+        None => (segments.len()..segments.len(), start),
+    }
+}
+
+/// 解析 `--quality`：best、worst、720p 或 3000k / 3M 这样的码率上限
+fn parse_quality(value: &str) -> Result<Quality, String> {
+    let lower = value.trim().to_ascii_lowercase();
+    let invalid = || format!("invalid quality '{}', expected best, worst, a resolution like 720p or a bandwidth like 3000k", value);
+    match lower.as_str() {
+        "best" => return Ok(Quality::Best),
+        "worst" => return Ok(Quality::Worst),
+        _ => {}
+    }
+    if let Some(height) = lower.strip_suffix('p') {
+        return height.parse().map(Quality::Height).map_err(|_| invalid());
+    }
+    let (number, multiplier) = if let Some(n) = lower.strip_suffix('k') {
+        (n, 1_000)
+    } else if let Some(n) = lower.strip_suffix('m') {
+        (n, 1_000_000)
+    } else {
+        (lower.as_str(), 1)
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 => Ok(Quality::MaxBandwidth((n * multiplier as f64) as u64)),
+        _ => Err(invalid()),
+    }
+}
+
+fn describe_variant(variant: &m3u8_rs::VariantStream) -> String {
+    match &variant.resolution {
+        Some(res) => format!("{}x{} @ {} kbps", res.width, res.height, variant.bandwidth / 1000),
+        None => format!("{} kbps", variant.bandwidth / 1000),
+    }
+}
+
+/// `--list-formats` 中的一个变体，时长和片段数来自它的媒体播放列表
+struct VariantInfo {
+    variant: m3u8_rs::VariantStream,
+    /// 媒体播放列表获取失败时为 None
+    duration: Option<f64>,
+    segments: Option<usize>,
+}
+
+/// 打印 `--list-formats` 的表格，估算大小按声明码率 × 时长
+fn print_formats(infos: &[VariantInfo]) {
+    println!("{:<11} {:>10} {:>8} {:>8} {:>10}  CODECS", "RESOLUTION", "BANDWIDTH", "DURATION", "SEGMENTS", "SIZE");
+    for info in infos {
+        let variant = &info.variant;
+        let resolution = match &variant.resolution {
+            Some(res) => format!("{}x{}", res.width, res.height),
+            None => "audio only".to_string(),
+        };
+        let unknown = || "-".to_string();
+        println!(
+            "{:<11} {:>10} {:>8} {:>8} {:>10}  {}",
+            resolution,
+            format!("{} kbps", variant.bandwidth / 1000),
+            info.duration.map(|d| format_duration(Duration::from_secs_f64(d))).unwrap_or_else(unknown),
+            info.segments.map(|n| n.to_string()).unwrap_or_else(unknown),
+            info.duration
+                .map(|d| format!("~{}", format_bytes((variant.bandwidth as f64 * d / 8.0) as u64)))
+                .unwrap_or_else(unknown),
+            variant.codecs.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+/// 与视频一起交给 ffmpeg 混流的独立音轨和字幕
+#[derive(Default)]
+struct ExtraTracks {
+    /// 片段列表中从这里开始是独立音轨
+    audio_start: Option<usize>,
+    /// 音轨第一个片段比视频早开始的秒数，合并时跳过
+    audio_skip: f64,
+    /// 拼接好的 WebVTT 字幕
+    subtitles: Option<PathBuf>,
+}
+
+/// WebVTT 中的一条字幕
+#[derive(Debug, Clone, PartialEq)]
+struct Cue {
+    start: f64,
+    end: f64,
+    text: String,
+}
+
+/// 解析 `hh:mm:ss.mmm` 或 `mm:ss.mmm`
+fn parse_vtt_time(value: &str) -> Option<f64> {
+    let (clock, millis) = value.trim().split_once('.')?;
+    let mut seconds = 0.0;
+    for part in clock.split(':') {
+        seconds = seconds * 60.0 + part.parse::<u32>().ok()? as f64;
+    }
+    Some(seconds + millis.parse::<u32>().ok()? as f64 / 1000.0)
+}
+
+/// 格式化为 `hh:mm:ss.mmm`，SRT 的毫秒分隔符是逗号
+fn format_cue_time(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+/// 解析一个 WebVTT 片段，返回 X-TIMESTAMP-MAP 给出的偏移（MPEGTS 时间减去 LOCAL 时间，秒）和其中的字幕
+fn parse_vtt(content: &str) -> (Option<f64>, Vec<Cue>) {
+    let content = content.replace("\r\n", "\n");
+    let mut offset = None;
+    let mut cues = Vec::new();
+    for block in content.split("\n\n") {
+        let lines: Vec<&str> = block.lines().filter(|line| !line.trim().is_empty()).collect();
+        if let Some(map) = lines.iter().find_map(|line| line.strip_prefix("X-TIMESTAMP-MAP=")) {
+            let mut mpegts = None;
+            let mut local = None;
+            for field in map.split(',') {
+                match field.split_once(':') {
+                    Some(("MPEGTS", value)) => mpegts = value.trim().parse::<u64>().ok(),
+                    Some(("LOCAL", value)) => local = parse_vtt_time(value),
+                    _ => {}
+                }
+            }
+            if let (Some(mpegts), Some(local)) = (mpegts, local) {
+                offset = Some(mpegts as f64 / 90_000.0 - local);
+            }
+            continue;
+        }
+        // 时间行前可以有一行标识符，时间行之后都是字幕文本
+        let Some(timing) = lines.iter().position(|line| line.contains("-->")) else { continue };
+        let Some((start, rest)) = lines[timing].split_once("-->") else { continue };
+        let end = rest.split_whitespace().next().unwrap_or_default();
+        if let (Some(start), Some(end)) = (parse_vtt_time(start), parse_vtt_time(end)) {
+            cues.push(Cue { start, end, text: lines[timing + 1..].join("\n") });
+        }
+    }
+    (offset, cues)
+}
+
+/// 拼接各个片段的字幕：按 X-TIMESTAMP-MAP 把时间换算到以第一个片段为零点的时间轴，
+/// 并去掉跨片段边界时在相邻片段里重复出现的字幕
+fn merge_vtt_segments(segments: &[String]) -> Vec<Cue> {
+    let mut base = None;
+    let mut seen = HashSet::new();
+    let mut merged = Vec::new();
+    for content in segments {
+        let (offset, cues) = parse_vtt(content);
+        let offset = offset.unwrap_or(0.0);
+        let shift = offset - *base.get_or_insert(offset);
+        for cue in cues {
+            let cue = Cue { start: cue.start + shift, end: cue.end + shift, ..cue };
+            let key = ((cue.start * 1000.0).round() as i64, (cue.end * 1000.0).round() as i64, cue.text.clone());
+            if seen.insert(key) {
+                merged.push(cue);
+            }
+        }
+    }
+    merged
+}
+
+fn write_vtt(cues: &[Cue]) -> String {
+    let mut out = String::from("WEBVTT\n");
+    for cue in cues {
+        out.push_str(&format!(
+            "\n{} --> {}\n{}\n",
+            format_cue_time(cue.start, '.'),
+            format_cue_time(cue.end, '.'),
+            cue.text
+        ));
+    }
+    out
+}
+
+/// 把字幕提前 shift 秒，丢掉移到零点之前结束或 length 之后才开始的字幕
+fn shift_cues(cues: Vec<Cue>, shift: f64, length: Option<f64>) -> Vec<Cue> {
+    cues.into_iter()
+        .filter(|cue| cue.end > shift && length.is_none_or(|length| cue.start - shift < length))
+        .map(|cue| Cue { start: (cue.start - shift).max(0.0), end: cue.end - shift, ..cue })
+        .collect()
+}
+
+/// MP4 不能直接携带 WebVTT，旁挂字幕写成播放器普遍支持的 SRT；SRT 不认识 WebVTT 的样式标签，只保留文本
+fn write_srt(cues: &[Cue]) -> String {
+    let mut out = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        out.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            format_cue_time(cue.start, ','),
+            format_cue_time(cue.end, ','),
+            strip_vtt_tags(&cue.text)
+        ));
+    }
+    out
+}
+
+/// 去掉 `<c.yellow>`、`<v Speaker>`、`<00:00:01.000>` 等标签，保留 SRT 也支持的 `<i>`、`<b>`、`<u>`
+fn strip_vtt_tags(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        let Some(close) = rest[open..].find('>') else {
+            rest = &rest[open..];
+            break;
+        };
+        let tag = &rest[open..open + close + 1];
+        if matches!(tag, "<i>" | "</i>" | "<b>" | "</b>" | "<u>" | "</u>") {
+            out.push_str(tag);
+        }
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&amp;", "&").replace("&lt;", "<").replace("&gt;", ">")
+}
+
+/// 从变体引用的 AUDIO/SUBTITLES 组中选择一个 rendition：语言匹配的优先，其次是 DEFAULT=YES，最后取第一个。
+/// 没有 URI 的音轨表示音频已经封装在变体流中，不需要单独下载
+fn select_rendition<'a>(
+    alternatives: &'a [m3u8_rs::AlternativeMedia],
+    media_type: m3u8_rs::AlternativeMediaType,
+    group_id: &str,
+    language: Option<&str>,
+) -> Option<&'a m3u8_rs::AlternativeMedia> {
+    let candidates: Vec<&m3u8_rs::AlternativeMedia> = alternatives
+        .iter()
+        .filter(|media| media.media_type == media_type && media.group_id == group_id)
+        .collect();
+    let by_language = language.filter(|language| !language.is_empty()).and_then(|language| {
+        let language = language.to_ascii_lowercase();
+        candidates.iter().find(|media| {
+            media.language.as_deref().is_some_and(|lang| {
+                let lang = lang.to_ascii_lowercase();
+                lang == language || lang.starts_with(&format!("{}-", language))
+            })
+        })
+    });
+    let selected = by_language
+        .or_else(|| candidates.iter().find(|media| media.default))
+        .or_else(|| candidates.first())?;
+    selected.uri.is_some().then_some(*selected)
+}
+
+/// 按 `--quality` 从主播放列表中挑选变体，没有匹配项时列出可选项并报错
+fn select_variant(variants: &[m3u8_rs::VariantStream], quality: Quality) -> Result<&m3u8_rs::VariantStream> {
+    let candidates: Vec<&m3u8_rs::VariantStream> = variants.iter().filter(|v| !v.is_i_frame).collect();
+    let selected = match quality {
+        Quality::Best => candidates.iter().max_by_key(|v| v.bandwidth),
+        Quality::Worst => candidates.iter().min_by_key(|v| v.bandwidth),
+        Quality::Height(height) => candidates.iter()
+            .filter(|v| v.resolution.as_ref().map(|r| r.height) == Some(height))
+            .max_by_key(|v| v.bandwidth),
+        Quality::MaxBandwidth(ceiling) => candidates.iter()
+            .filter(|v| v.bandwidth <= ceiling)
+            .max_by_key(|v| v.bandwidth),
+    };
+    if let Some(variant) = selected {
+        return Ok(variant);
+    }
+    if candidates.is_empty() {
+        anyhow::bail!("No variants found");
+    }
+
+    status!("  ✗ 没有符合要求的清晰度，可选项:");
+    let mut sorted = candidates;
+    sorted.sort_by_key(|v| std::cmp::Reverse(v.bandwidth));
+    for variant in sorted {
+        status!("    {}", describe_variant(variant));
+    }
+    anyhow::bail!("No variant matches quality {:?}", quality)
+}
+
+/// 下载结束仍有失败片段时的处理方式
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FailureAction {
+    Retry,
+    Merge,
+    Abort,
+}
+
+fn parse_failure_action(value: &str) -> Result<FailureAction, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "retry" | "r" => Ok(FailureAction::Retry),
+        "merge" | "m" => Ok(FailureAction::Merge),
+        "abort" | "a" => Ok(FailureAction::Abort),
+        _ => Err(format!("invalid failure action '{}', expected retry, merge or abort", value)),
+    }
+}
+
+fn parse_container(value: &str) -> Result<Container, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "mp4" => Ok(Container::Mp4),
+        "mkv" => Ok(Container::Mkv),
+        "ts" => Ok(Container::Ts),
+        _ => Err(format!("invalid format '{}', expected mp4, mkv or ts", value)),
+    }
+}
+
+/// 解析 `--color-depth`，256 色与真彩色终端按 16 色处理
+fn parse_color_depth(value: &str) -> Result<ColorDepth, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "mono" | "1" | "2" => Ok(ColorDepth::Mono),
+        "8" => Ok(ColorDepth::Basic),
+        "16" | "256" | "truecolor" | "24bit" => Ok(ColorDepth::Full),
+        _ => Err(format!("invalid color depth '{}', expected mono, 8 or 16", value)),
+    }
+}
+
+fn parse_panel(value: &str) -> Result<Panel, String> {
+    Panel::ALL
+        .into_iter()
+        .find(|panel| panel.name() == value.to_ascii_lowercase())
+        .ok_or_else(|| format!("invalid panel '{}', expected info, speed, activity, stats or chunks", value))
+}
+
+/// 合并时的重新编码参数，全部为空时直接 -c copy
+#[derive(Clone, Default)]
+struct TranscodeOptions {
+    /// ffmpeg scale 滤镜参数，形如 `1280:720`
+    scale: Option<String>,
+    fps: Option<String>,
+    /// `--from`/`--to` 换算到第一个下载片段为零点后的裁剪范围（秒）；
+    /// -c copy 时切点落在最近的关键帧，配合 --scale 或 --fps 重新编码才能精确到帧
+    trim_start: f64,
+    trim_end: Option<f64>,
+    /// 没有缩放或变换帧率也重新编码，-c copy 失败后的回退使用
+    reencode: bool,
+    /// `--allow-reencode`
+    allow_reencode: bool,
+}
+
+impl TranscodeOptions {
+    fn is_copy(&self) -> bool {
+        !self.reencode && self.scale.is_none() && self.fps.is_none()
+    }
+
+    /// 其余设置不变，改为重新编码
+    fn reencoded(&self) -> Self {
+        Self { reencode: true, ..self.clone() }
+    }
+
+    fn ffmpeg_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+        if self.trim_start > 0.0 {
+            args.extend(["-ss".into(), format!("{:.3}", self.trim_start)]);
+        }
+        if let Some(end) = self.trim_end {
+            args.extend(["-to".into(), format!("{:.3}", end)]);
+        }
+        if self.is_copy() {
+            args.extend(["-c".into(), "copy".into()]);
+            return args;
+        }
+        args.extend(["-c:v".into(), "libx264".into(), "-c:a".into(), "aac".into()]);
+        if let Some(scale) = &self.scale {
+            args.extend(["-vf".into(), format!("scale={}", scale)]);
+        }
+        if let Some(fps) = &self.fps {
+            args.extend(["-r".into(), fps.clone()]);
+        }
+        args
+    }
+}
+
+/// `--theme` 可选的配色
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ThemeName {
+    Surge,
+    Solarized,
+    /// 不使用颜色，等同于 --no-color
+    Mono,
+}
+
+fn parse_theme(value: &str) -> Result<ThemeName, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "surge" => Ok(ThemeName::Surge),
+        "solarized" => Ok(ThemeName::Solarized),
+        "mono" => Ok(ThemeName::Mono),
+        _ => Err(format!("invalid theme '{}', expected surge, solarized or mono", value)),
+    }
+}
+
+/// TUI 配色，字段是颜色在界面中的用途
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Theme {
+    /// 边框与次要强调
+    accent: Color,
+    /// 数值、进度条等需要突出的内容
+    highlight: Color,
+    /// 字段名与面板标题
+    label: Color,
+    completed: Color,
+    partial: Color,
+    failed: Color,
+    muted: Color,
+}
+
+impl Theme {
+    /// Surge 霓虹配色：紫、粉、青
+    const SURGE: Theme = Theme {
+        accent: Color::Magenta,
+        highlight: Color::LightMagenta,
+        label: Color::Cyan,
+        completed: Color::Green,
+        partial: Color::Yellow,
+        failed: Color::Red,
+        muted: Color::DarkGray,
+    };
+
+    /// Solarized 强调色在 256 色调色板中的近似值
+    const SOLARIZED: Theme = Theme {
+        accent: Color::Indexed(61),
+        // 常用的 125 在 8 色终端上会和 failed 一样退成红色，162 仍是洋红
+        highlight: Color::Indexed(162),
+        label: Color::Indexed(33),
+        completed: Color::Indexed(64),
+        partial: Color::Indexed(136),
+        failed: Color::Indexed(160),
+        muted: Color::Indexed(240),
+    };
+
+    fn named(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Solarized => Theme::SOLARIZED,
+            // mono 不显示颜色，保留 Surge 的配色只用来区分各用途
+            ThemeName::Surge | ThemeName::Mono => Theme::SURGE,
+        }
+    }
+}
+
+/// 终端可用的颜色数量
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ColorDepth {
+    /// 无颜色，只能靠粗体/反显/下划线区分状态
+    Mono,
+    /// 只有 8 种基本色，Light* 与 DarkGray 不可用
+    Basic,
+    /// 16 色及以上
+    Full,
+}
+
+/// TUI 中可以单独隐藏的面板，顺序即切换用的数字键 1-5
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Panel {
+    Info,
+    Speed,
+    Activity,
+    Stats,
+    Chunks,
+}
+
+impl Panel {
+    const ALL: [Panel; 5] = [Panel::Info, Panel::Speed, Panel::Activity, Panel::Stats, Panel::Chunks];
+
+    fn name(self) -> &'static str {
+        match self {
+            Panel::Info => "info",
+            Panel::Speed => "speed",
+            Panel::Activity => "activity",
+            Panel::Stats => "stats",
+            Panel::Chunks => "chunks",
+        }
+    }
+
+    /// 所在行中的宽度占比，与原来的固定百分比一致
+    fn weight(self) -> u32 {
+        match self {
+            Panel::Info | Panel::Activity => 30,
+            Panel::Speed => 70,
+            Panel::Stats => 20,
+            Panel::Chunks => 50,
+        }
+    }
+}
+
+/// 小于这个尺寸时四象限布局会互相挤压，改为只显示一行进度
+const MIN_TUI_WIDTH: u16 = 60;
+const MIN_TUI_HEIGHT: u16 = 16;
+
+/// TUI 渲染选项
+#[derive(Clone)]
+struct UiOptions {
+    /// 终端无法显示方块/符号字符时改用 ASCII
+    ascii: bool,
+    color_depth: ColorDepth,
+    theme: Theme,
+    /// 速度单位：false 为 MB/s，true 为 Mbps
+    bits: bool,
+    /// 进度按媒体时长而不是片段数计算
+    by_time: bool,
+    hidden_panels: Vec<Panel>,
+}
+
+impl UiOptions {
+    fn detect(
+        force_ascii: bool,
+        color_depth: Option<ColorDepth>,
+        theme: ThemeName,
+        no_color: bool,
+        bits: bool,
+        by_time: bool,
+        hidden_panels: Vec<Panel>,
+    ) -> Self {
+        // https://no-color.org：NO_COLOR 非空时不输出颜色
+        let no_color = no_color
+            || theme == ThemeName::Mono
+            || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        Self {
+            ascii: force_ascii || !terminal_supports_unicode(),
+            color_depth: if no_color { ColorDepth::Mono } else { color_depth.unwrap_or_else(terminal_color_depth) },
+            theme: Theme::named(theme),
+            bits,
+            by_time,
+            hidden_panels,
+        }
+    }
+
+    fn progress_percent(&self, stats: &DownloadStats) -> f64 {
+        if self.by_time {
+            stats.time_progress_percent()
+        } else {
+            stats.progress_percent()
+        }
+    }
+
+    fn shows(&self, panel: Panel) -> bool {
+        !self.hidden_panels.contains(&panel)
+    }
+
+    fn toggle_panel(&mut self, panel: Panel) {
+        match self.hidden_panels.iter().position(|&p| p == panel) {
+            Some(pos) => {
+                self.hidden_panels.remove(pos);
+            }
+            None => self.hidden_panels.push(panel),
+        }
+    }
+
+    /// 把内部的 MiB/s 换算为当前显示单位的数值
+    fn speed_value(&self, mib_per_sec: f64) -> f64 {
+        if self.bits {
+            // 运营商的 Mbps 是 10^6 bit/s，先还原为字节再 ×8
+            mib_per_sec * 1024.0 * 1024.0 * 8.0 / 1_000_000.0
+        } else {
+            mib_per_sec
+        }
+    }
+
+    fn speed_unit(&self) -> &'static str {
+        if self.bits { "Mbps" } else { "MB/s" }
+    }
+
+    /// 按终端颜色数给出前景样式，颜色不足时用修饰符保持各状态可区分
+    fn fg(&self, color: Color) -> Style {
+        let style = Style::default();
+        let theme = &self.theme;
+        match self.color_depth {
+            ColorDepth::Full => style.fg(color),
+            ColorDepth::Basic => match color {
+                // 8 色终端上粗体通常显示为亮色，用来区分紫色与粉色
+                Color::LightMagenta => style.fg(Color::Magenta).add_modifier(Modifier::BOLD),
+                Color::DarkGray => style.add_modifier(Modifier::DIM),
+                Color::Indexed(_) | Color::Rgb(..) if color == theme.muted => style.add_modifier(Modifier::DIM),
+                Color::Indexed(_) | Color::Rgb(..) => style.fg(nearest_basic_color(color)),
+                _ => style.fg(color),
+            },
+            ColorDepth::Mono => match color {
+                c if c == theme.completed => style.add_modifier(Modifier::REVERSED),
+                c if c == theme.partial => style.add_modifier(Modifier::REVERSED | Modifier::DIM),
+                c if c == theme.failed => style.add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+                c if c == theme.highlight || c == theme.accent => style.add_modifier(Modifier::BOLD),
+                c if c == theme.muted => style.add_modifier(Modifier::DIM),
+                _ => style,
+            },
+        }
+    }
+}
+
+fn terminal_color_depth() -> ColorDepth {
+    if let Ok(colorterm) = std::env::var("COLORTERM") {
+        if !colorterm.is_empty() {
+            return ColorDepth::Full;
+        }
+    }
+    if cfg!(windows) {
+        return ColorDepth::Full;
+    }
+    let term = std::env::var("TERM").unwrap_or_default();
+    // terminfo 中以 -m / -mono 结尾的条目表示单色终端
+    if term.is_empty()
+        || term == "dumb"
+        || term.starts_with("vt")
+        || term.ends_with("-m")
+        || term.ends_with("-mono")
+    {
+        return ColorDepth::Mono;
+    }
+    if term.contains("256color") || term.contains("direct") {
+        return ColorDepth::Full;
+    }
+    match term.as_str() {
+        "linux" | "ansi" | "cons25" | "sun" | "screen" => ColorDepth::Basic,
+        _ => ColorDepth::Full,
+    }
+}
+
+/// 根据 TERM 和 locale 环境变量粗略判断终端能否显示 Unicode 方块字符
+fn terminal_supports_unicode() -> bool {
+    if let Ok(term) = std::env::var("TERM") {
+        if term == "linux" || term == "dumb" || term == "vt100" {
+            return false;
+        }
+    }
+    if cfg!(windows) {
+        return true;
+    }
+    // 第一个非空的 locale 变量决定字符集
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|key| std::env::var(key).ok())
+        .find(|value| !value.is_empty())
+        .map(|value| {
+            let value = value.to_uppercase();
+            value.contains("UTF-8") || value.contains("UTF8")
+        })
+        .unwrap_or(false)
+}
+
+/// 媒体播放列表中的单个片段
+#[derive(Clone)]
+struct Segment {
+    url: String,
+    /// #EXTINF 声明的时长（秒）
+    duration: f64,
+    /// 片段前有 #EXT-X-DISCONTINUITY，时间戳/编码可能从这里重新开始
+    discontinuity: bool,
+    /// #EXT-X-BYTERANGE：片段只是资源中的一段
+    byte_range: Option<ByteRange>,
+    /// #EXT-X-MAP 声明的初始化片段（fMP4/CMAF）
+    init: Option<InitSegment>,
+}
+
+/// 资源中的一段字节，offset 已按播放列表顺序补全
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ByteRange {
+    offset: u64,
+    length: u64,
+}
+
+impl ByteRange {
+    fn header(&self) -> String {
+        format!("bytes={}-{}", self.offset, self.offset + self.length.max(1) - 1)
+    }
+
+    /// 服务器忽略 Range 返回了完整内容时，自行截取这一段
+    fn slice<'a>(&self, data: &'a [u8]) -> Result<&'a [u8]> {
+        let start = self.offset as usize;
+        data.get(start..start + self.length as usize).with_context(|| {
+            format!("Response has {} bytes, shorter than byte range {}@{}", data.len(), self.length, self.offset)
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct InitSegment {
+    url: String,
+    byte_range: Option<ByteRange>,
+}
+
+/// 低延迟 HLS 的 #EXT-X-PART-INF:PART-TARGET（秒）。m3u8-rs 不认识这个标签，
+/// 会把它放进第一个片段的 unknown_tags
+fn part_target(playlist: &m3u8_rs::MediaPlaylist) -> Option<f64> {
+    playlist
+        .unknown_tags
+        .iter()
+        .chain(playlist.segments.iter().flat_map(|segment| &segment.unknown_tags))
+        .find(|tag| tag.tag == "X-PART-INF")?
+        .rest
+        .as_deref()?
+        .split(',')
+        .find_map(|attribute| attribute.trim().strip_prefix("PART-TARGET="))?
+        .parse()
+        .ok()
+        .filter(|&target: &f64| target > 0.0)
+}
+
+fn parse_media_playlist(content: &str) -> Result<m3u8_rs::MediaPlaylist> {
+    let parsed = m3u8_rs::parse_playlist_res(content.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Failed to parse: {:?}", e))?;
+    match parsed {
+        Playlist::MediaPlaylist(media_pl) => Ok(media_pl),
+        _ => anyhow::bail!("Invalid media playlist"),
+    }
+}
+
+/// 取属性列表中的一个值，带引号的值去掉引号（引号内可以有逗号）
+fn tag_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=')?;
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, next) = quoted.split_once('"')?;
+                (value, next.strip_prefix(',').unwrap_or(next))
+            }
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = next;
+    }
+    None
+}
+
+/// 低延迟 HLS 的部分片段 (#EXT-X-PART)，父片段完成前就能下载，按顺序拼起来等于父片段
+#[derive(Clone, Debug, PartialEq)]
+struct PartialSegment {
+    /// 父片段的媒体序号
+    sequence: u64,
+    uri: String,
+    duration: f64,
+    byte_range: Option<ByteRange>,
+    discontinuity: bool,
+    /// GAP=YES：服务器没有这部分数据
+    gap: bool,
+}
+
+/// m3u8-rs 不认识 #EXT-X-PART，而且末尾未完成片段的部分片段后面没有片段 URI，解析后会丢失，
+/// 所以直接读播放列表文本。部分片段列在父片段的 URI 之前
+fn partial_segments(content: &str, media_sequence: u64) -> Vec<PartialSegment> {
+    let mut sequence = media_sequence;
+    let mut discontinuity = false;
+    let mut previous_end: Option<(&str, u64)> = None;
+    let mut parts = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line == "#EXT-X-DISCONTINUITY" {
+            discontinuity = true;
+        } else if let Some(attributes) = line.strip_prefix("#EXT-X-PART:") {
+            let uri = tag_attribute(attributes, "URI");
+            let duration = tag_attribute(attributes, "DURATION").and_then(|duration| duration.parse().ok());
+            let (Some(uri), Some(duration)) = (uri, duration) else { continue };
+            let byte_range = tag_attribute(attributes, "BYTERANGE").and_then(|range| {
+                let (length, offset) = match range.split_once('@') {
+                    Some((length, offset)) => (length, Some(offset.parse().ok()?)),
+                    None => (range, None),
+                };
+                let offset = offset.unwrap_or(match previous_end {
+                    Some((previous_uri, end)) if previous_uri == uri => end,
+                    _ => 0,
+                });
+                Some(ByteRange { offset, length: length.parse().ok()? })
+            });
+            previous_end = byte_range.map(|range| (uri, range.offset + range.length));
+            parts.push(PartialSegment {
+                sequence,
+                uri: uri.to_string(),
+                duration,
+                byte_range,
+                discontinuity: std::mem::take(&mut discontinuity),
+                gap: tag_attribute(attributes, "GAP") == Some("YES"),
+            });
+        } else if !line.is_empty() && !line.starts_with('#') {
+            sequence += 1;
+            discontinuity = false;
+        }
+    }
+    parts
+}
+
+/// 直播录制时刷新媒体播放列表所需的信息
+struct LivePlaylist {
+    playlist_url: String,
+    /// 刷新间隔，取 #EXT-X-TARGETDURATION
+    target_duration: f64,
+    /// 低延迟 HLS 的 PART-TARGET，仅在 --low-latency 时用作刷新间隔
+    part_target: Option<f64>,
+    /// 下一个尚未下载的片段的媒体序号
+    next_sequence: u64,
+    /// next_sequence 片段已经提前下载的部分片段数
+    next_part: usize,
+}
+
+impl LivePlaylist {
+    /// 取出刷新后的播放列表中还没下载的内容。parts 为 (父片段序号, 部分片段)，GAP 为 None，
+    /// 只在 --low-latency 时非空：末尾未完成片段的部分片段先下载，父片段出现后只补下其余的
+    /// 部分片段，不再重复下载整个片段
+    fn take_new(&mut self, media_sequence: u64, segments: Vec<Segment>, parts: &[(u64, Option<Segment>)]) -> Vec<Segment> {
+        if media_sequence > self.next_sequence {
+            self.next_sequence = media_sequence;
+            self.next_part = 0;
+        }
+        let parts_of = |sequence: u64, skip: usize| {
+            parts.iter()
+                .filter(move |(parent, _)| *parent == sequence)
+                .skip(skip)
+                .filter_map(|(_, part)| part.clone())
+        };
+
+        let in_progress = media_sequence + segments.len() as u64;
+        let mut new = Vec::new();
+        for (sequence, segment) in (media_sequence..).zip(segments) {
+            if sequence < self.next_sequence {
+                continue;
+            }
+            if self.next_part > 0 {
+                new.extend(parts_of(sequence, self.next_part));
+                self.next_part = 0;
+            } else {
+                new.push(segment);
+            }
+            self.next_sequence = sequence + 1;
+        }
+        if in_progress == self.next_sequence {
+            let listed = parts.iter().filter(|(parent, _)| *parent == in_progress).count();
+            new.extend(parts_of(in_progress, self.next_part));
+            self.next_part = self.next_part.max(listed);
+        }
+        new
+    }
+}
+
+/// fetch_m3u8 解析得到的媒体流信息
+struct StreamInfo {
+    segments: Vec<Segment>,
+    /// 所选的媒体播放列表地址（主播放列表时为所选变体流的地址）
+    playlist_url: String,
+    /// 所选变体流声明的 CODECS 属性
+    codecs: Option<String>,
+    /// 所选变体流声明的分辨率，如 1280x720
+    resolution: Option<String>,
+    /// 没有 EXT-X-ENDLIST 时为直播，记录刷新播放列表所需的信息
+    live: Option<LivePlaylist>,
+    /// 所选变体通过 EXT-X-MEDIA 引用的独立音轨
+    audio: Option<Rendition>,
+    /// 使用 --subs 时所选的字幕
+    subtitles: Option<Rendition>,
+    /// EXT-X-START 的 TIME-OFFSET，负数表示从结尾倒数
+    start_offset: Option<f64>,
+}
+
+/// EXT-X-MEDIA 声明的独立音轨或字幕
+struct Rendition {
+    /// 名称与语言，用于提示
+    label: String,
+    segments: Vec<Segment>,
+}
+
+/// 输出文件已存在时的处理方式
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OnCollision {
+    Overwrite,
+    /// `--no-overwrite`
+    Fail,
+    /// `--auto-rename`
+    Rename,
+}
+
+impl OnCollision {
+    /// 返回实际要写入的路径
+    fn resolve(self, path: &Path) -> Result<PathBuf> {
+        if !path.exists() {
+            return Ok(path.to_path_buf());
+        }
+        match self {
+            OnCollision::Overwrite => Ok(path.to_path_buf()),
+            OnCollision::Fail => anyhow::bail!("Output file {} already exists (--no-overwrite)", path.display()),
+            OnCollision::Rename => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+                (1..)
+                    .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension)))
+                    .find(|candidate| !candidate.exists())
+                    .context("No free output file name")
+            }
+        }
+    }
+}
+
+/// 合并输出的容器格式
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Container {
+    Mp4,
+    Mkv,
+    /// 直接拼接 TS 片段，不经过 ffmpeg
+    Ts,
+}
+
+impl Container {
+    fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "mkv",
+            Container::Ts => "ts",
+        }
+    }
+
+    /// ffmpeg 的 muxer 名称，输出文件名以 .part 结尾时无法从扩展名推断
+    fn muxer(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+            Container::Ts => "mpegts",
+        }
+    }
+
+    /// 输出到管道时的 ffmpeg 格式参数：管道不能回写文件头，MP4 改用分片格式
+    fn pipe_format_args(self) -> &'static [&'static str] {
+        match self {
+            Container::Mp4 => &["-f", "mp4", "-movflags", "frag_keyframe+empty_moov"],
+            Container::Mkv => &["-f", "matroska"],
+            Container::Ts => &["-f", "mpegts"],
+        }
+    }
+
+    /// 根据 CODECS 选择容器：全部编码都能放进 MP4 时用 MP4，否则退回 MKV
+    fn for_codecs(codecs: Option<&str>) -> (Self, String) {
+        const MP4_CODECS: &[&str] = &[
+            "avc1", "avc3", "hvc1", "hev1", "dvh1", "dvhe", "av01", "vp09",
+            "mp4a", "ac-3", "ec-3", "opus", "flac", "alac",
+        ];
+
+        let Some(codecs) = codecs else {
+            return (Container::Mp4, "播放列表未声明 CODECS，默认使用 MP4".to_string());
+        };
+
+        let unsupported: Vec<&str> = codecs
+            .split(',')
+            .map(str::trim)
+            .filter(|codec| !codec.is_empty())
+            .filter(|codec| {
+                let family = codec.split('.').next().unwrap_or(codec).to_ascii_lowercase();
+                !MP4_CODECS.contains(&family.as_str())
+            })
+            .collect();
+
+        if unsupported.is_empty() {
+            (Container::Mp4, format!("编码 {} 均兼容 MP4", codecs))
+        } else {
+            (Container::Mkv, format!("编码 {} 无法直接封装进 MP4", unsupported.join(", ")))
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ActivityItem {
+    name: String,
+    status: ActivityStatus,
+}
+
+/// 下载过程中的事件，通过 `M3U8Downloader::subscribe` 订阅，不依赖 DownloadStats 的内部字段
+#[derive(Clone, Debug, PartialEq)]
+enum ProgressEvent {
+    SegmentStarted { index: usize },
+    SegmentCompleted { index: usize, bytes: u64 },
+    /// 所有尝试都用尽后才发出
+    SegmentFailed { index: usize },
+    /// 下载与失败重试都已结束，之后不再有片段事件
+    Finished,
+}
+
+/// 订阅方来不及处理时最多积压的事件数，超出后最早的事件被丢弃
+const PROGRESS_EVENT_CAPACITY: usize = 4096;
+
+/// `--batch` 中当前条目的位置，显示在 Info 面板
+#[derive(Clone, Copy)]
+struct BatchProgress {
+    /// 从 1 开始
+    current: usize,
+    total: usize,
+    /// 之前的条目中失败的数量
+    failed: usize,
+}
+
+impl BatchProgress {
+    /// 整个批量任务的完成百分比：之前的条目都算完成，当前条目按自身进度计入
+    fn overall_percent(&self, item_percent: f64) -> f64 {
+        ((self.current - 1) as f64 + item_percent.clamp(0.0, 100.0) / 100.0) / self.total.max(1) as f64 * 100.0
+    }
+}
+
+/// 单个片段的下载记录，用于 --report
+#[derive(Clone)]
+struct SegmentRecord {
+    index: usize,
+    bytes: u64,
+    /// 包括重试和退避等待在内的耗时
+    elapsed: Duration,
+}
+
+#[derive(Clone, PartialEq)]
+enum ActivityStatus {
+    Success,
+    Failed,
+    Downloading,
+}
+
+struct DownloadStats {
+    total_segments: usize,
+    downloaded_segments: usize,
+    failed_segments: usize,
+    downloaded_bytes: u64,
+    total_duration: f64,
+    downloaded_duration: f64,
+    failed_duration: f64,
+    failed_indices: Vec<usize>,
+    active_downloads: usize,
+    start_time: Instant,
+    current_speed: f64,
+    speed_history: VecDeque<f64>,
+    /// 每个片段的状态，显示时再按 MAX_CHUNKS 分组
+    segment_states: Vec<ChunkState>,
+    activity_log: VecDeque<ActivityItem>,
+    /// 活动日志保留的条数
+    activity_capacity: usize,
+    /// Activity 面板向上滚动的条数，0 表示停在最新一条
+    activity_scroll: usize,
+    last_update: Instant,
+    /// 上次采样时的 transferred，速度按两次采样之间收到的字节计算
+    transferred_at_update: u64,
+    /// 正在录制直播，片段总数还会增长
+    live: bool,
+    /// 用户按了 q，直播录制在下一次刷新前停止
+    stop_requested: bool,
+    /// 直播录制落后时，下载前就滑出 DVR 窗口的片段数
+    lost_segments: usize,
+    /// `--max-failures` / `--max-failure-pct`
+    max_failures: Option<usize>,
+    max_failure_pct: Option<f64>,
+    /// 失败片段超过上限，下载应当停止
+    failure_limit_exceeded: bool,
+    /// --resume 时临时目录中已存在的片段，不再下载
+    resumed_indices: HashSet<usize>,
+    /// 已存在片段的字节数，不计入平均速度
+    resumed_bytes: u64,
+    /// 按第一个片段推算的总大小与输出目录的可用空间，显示在 Info 面板
+    estimated_size: Option<u64>,
+    available_space: Option<u64>,
+    /// 探测到的服务器连接上限
+    connection_limit: Option<usize>,
+    /// --batch 时当前条目的位置
+    batch: Option<BatchProgress>,
+    /// 播放列表中的 discontinuity 数量，显示在 Info 面板，解释合并后可能的卡顿
+    discontinuities: usize,
+    /// 与下载器共享的网络传输总量；downloaded_bytes 只统计写入的片段数据
+    transferred: Arc<AtomicU64>,
+    /// `--concurrent auto` 当前使用的并发数
+    concurrency_level: Option<usize>,
+    /// 当前放出的 permit 数，即实际生效的并发上限；max_concurrency 是按 + 能调到的上限
+    concurrency: usize,
+    max_concurrency: usize,
+    /// 用户在界面中按 +/- 选定的并发数，设置后自动调整停止
+    concurrency_target: Option<usize>,
+    /// 累计重试次数，自动并发据此判断是否被限流
+    retried_requests: usize,
+    /// 暂停时不再派发新片段
+    paused: bool,
+    /// 每个成功下载的片段的大小与耗时，按完成顺序
+    segment_records: Vec<SegmentRecord>,
+}
+
+#[derive(Clone, PartialEq)]
+enum ChunkState {
+    Pending,
+    Downloading,
+    /// 方块中只有部分片段完成
+    Partial,
+    Completed,
+    Failed,
+}
+
+impl ChunkState {
+    /// Chunks 面板与导出图片共用的状态配色
+    fn color(&self, theme: &Theme) -> Color {
+        match self {
+            ChunkState::Completed => theme.completed,
+            ChunkState::Partial => theme.partial,
+            ChunkState::Downloading => theme.highlight,
+            ChunkState::Failed => theme.failed,
+            ChunkState::Pending => theme.muted,
+        }
+    }
+}
+
+impl DownloadStats {
+    fn new(total: usize, total_duration: f64) -> Self {
+        Self {
+            total_segments: total,
+            downloaded_segments: 0,
+            failed_segments: 0,
+            downloaded_bytes: 0,
+            total_duration,
+            downloaded_duration: 0.0,
+            failed_duration: 0.0,
+            failed_indices: Vec::new(),
+            active_downloads: 0,
+            start_time: Instant::now(),
+            current_speed: 0.0,
+            speed_history: VecDeque::with_capacity(50),
+            segment_states: vec![ChunkState::Pending; total],
+            activity_log: VecDeque::new(),
+            activity_capacity: 500,
+            activity_scroll: 0,
+            last_update: Instant::now(),
+            transferred_at_update: 0,
+            estimated_size: None,
+            available_space: None,
+            connection_limit: None,
+            batch: None,
+            discontinuities: 0,
+            transferred: Arc::new(AtomicU64::new(0)),
+            concurrency_level: None,
+            concurrency: 0,
+            max_concurrency: 0,
+            concurrency_target: None,
+            retried_requests: 0,
+            paused: false,
+            segment_records: Vec::new(),
+            live: false,
+            stop_requested: false,
+            lost_segments: 0,
+            max_failures: None,
+            max_failure_pct: None,
+            failure_limit_exceeded: false,
+            resumed_indices: HashSet::new(),
+            resumed_bytes: 0,
+        }
+    }
+
+    fn log_activity(&mut self, name: String, status: ActivityStatus) {
+        self.activity_log.push_back(ActivityItem { name, status });
+        if self.activity_log.len() > self.activity_capacity.max(1) {
+            self.activity_log.pop_front();
+        }
+        // 用户向上翻看时保持视图不动，新条目不会把内容顶走
+        if self.activity_scroll > 0 {
+            self.activity_scroll = (self.activity_scroll + 1).min(self.activity_log.len().saturating_sub(1));
+        }
+    }
+
+    /// 调整 Activity 面板的滚动位置，正数向更早的条目滚动
+    fn scroll_activity(&mut self, delta: isize) {
+        let max = self.activity_log.len().saturating_sub(1);
+        self.activity_scroll = self.activity_scroll.saturating_add_signed(delta).min(max);
+    }
+
+    /// 直播刷新出新片段时扩充总数
+    fn add_segments(&mut self, count: usize, duration: f64) {
+        self.total_segments += count;
+        self.total_duration += duration;
+        self.segment_states.resize(self.total_segments, ChunkState::Pending);
+    }
+
+    fn set_state(&mut self, segment_id: usize, state: ChunkState) {
+        if let Some(current) = self.segment_states.get_mut(segment_id) {
+            *current = state;
+        }
+    }
+
+    /// 把片段状态分成最多 MAX_CHUNKS 组：有失败的组显示失败，全部完成才显示完成，
+    /// 只完成了一部分显示为部分完成
+    fn chunk_states(&self) -> Vec<ChunkState> {
+        let total = self.segment_states.len();
+        let chunk_count = total.min(MAX_CHUNKS);
+        (0..chunk_count)
+            .map(|chunk| {
+                let group = &self.segment_states[chunk * total / chunk_count..(chunk + 1) * total / chunk_count];
+                let completed = group.iter().filter(|&state| *state == ChunkState::Completed).count();
+                if group.contains(&ChunkState::Failed) {
+                    ChunkState::Failed
+                } else if group.contains(&ChunkState::Downloading) {
+                    ChunkState::Downloading
+                } else if completed == group.len() {
+                    ChunkState::Completed
+                } else if completed > 0 {
+                    ChunkState::Partial
+                } else {
+                    ChunkState::Pending
+                }
+            })
+            .collect()
+    }
+
+    /// 直播窗口已经滑过尚未下载的片段
+    fn lose_segments(&mut self, count: usize) {
+        self.lost_segments += count;
+        self.log_activity(format!("下载跟不上直播，{} 个片段已滑出窗口", count), ActivityStatus::Failed);
+    }
+
+    fn start(&mut self, segment_id: usize) {
+        self.active_downloads += 1;
+
+        if self.segment_states.get(segment_id) == Some(&ChunkState::Pending) {
+            self.set_state(segment_id, ChunkState::Downloading);
+        }
+    }
+
+    /// 下载被取消，片段回到未开始状态
+    fn cancel(&mut self, segment_id: usize) {
+        self.active_downloads = self.active_downloads.saturating_sub(1);
+
+        if self.segment_states.get(segment_id) == Some(&ChunkState::Downloading) {
+            self.set_state(segment_id, ChunkState::Pending);
+        }
+    }
+
+    /// 断点续传时把已存在的片段记为已完成，在启动界面前调用，界面一开始就显示真实进度
+    fn resume(&mut self, segment_id: usize, bytes: u64, duration: f64) {
+        self.downloaded_segments += 1;
+        self.downloaded_bytes += bytes;
+        self.resumed_bytes += bytes;
+        self.downloaded_duration += duration;
+        self.resumed_indices.insert(segment_id);
+
+        self.set_state(segment_id, ChunkState::Completed);
+    }
+
+    fn update(&mut self, segment_id: usize, bytes: u64, duration: f64, elapsed: Duration, segment_name: String) {
+        self.active_downloads = self.active_downloads.saturating_sub(1);
+        self.downloaded_segments += 1;
+        self.downloaded_bytes += bytes;
+        self.downloaded_duration += duration;
+        self.segment_records.push(SegmentRecord { index: segment_id, bytes, elapsed });
+
+        // 添加活动日志
+        self.log_activity(segment_name, ActivityStatus::Success);
+
+        self.set_state(segment_id, ChunkState::Completed);
+    }
+
+    /// 按网络上实际收到的字节更新速度，下载中的片段也计算在内，大片段不会让速度图停在原地
+    fn sample_speed(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        if elapsed >= SPEED_SAMPLE_INTERVAL.as_secs_f64() {
+            let transferred = self.transferred.load(Ordering::Relaxed);
+            let bytes = transferred.saturating_sub(self.transferred_at_update);
+            self.current_speed = (bytes as f64) / elapsed / (1024.0 * 1024.0);
+            self.speed_history.push_back(self.current_speed);
+            if self.speed_history.len() > 50 {
+                self.speed_history.pop_front();
+            }
+            self.last_update = now;
+            self.transferred_at_update = transferred;
+        }
+    }
+
+    /// 片段下载失败、即将重试，只记录到活动日志，不计入失败
+    fn retry(&mut self, segment_name: String) {
+        self.retried_requests += 1;
+        self.log_activity(segment_name, ActivityStatus::Downloading);
+    }
+
+    /// 只在片段的所有尝试都用尽后调用，失败是最终结果
+    fn fail(&mut self, segment_id: usize, duration: f64, segment_name: String) {
+        self.active_downloads = self.active_downloads.saturating_sub(1);
+        self.failed_segments += 1;
+        self.failed_duration += duration;
+        self.failed_indices.push(segment_id);
+
+        self.log_activity(segment_name, ActivityStatus::Failed);
+
+        self.set_state(segment_id, ChunkState::Failed);
+
+        // 百分比按播放列表的片段总数计算，越过上限后剩下的片段全部成功也救不回来
+        let too_many = self.max_failures.is_some_and(|max| self.failed_segments > max);
+        let too_high = self.max_failure_pct.is_some_and(|pct| {
+            self.total_segments > 0 && self.failed_segments as f64 / self.total_segments as f64 * 100.0 > pct
+        });
+        self.failure_limit_exceeded |= too_many || too_high;
+    }
+
+    /// 已完成的片段事后判定为损坏（截断后重新下载也失败），改记为失败，之后可以重试或写入失败列表
+    fn invalidate(&mut self, segment_id: usize, duration: f64, segment_name: String) {
+        self.downloaded_segments = self.downloaded_segments.saturating_sub(1);
+        self.downloaded_duration -= duration;
+        self.fail(segment_id, duration, segment_name);
+    }
+
+    /// 之前失败的片段在补救重试中下载成功
+    fn recover(&mut self, segment_id: usize, bytes: u64, duration: f64, elapsed: Duration) {
+        if let Some(pos) = self.failed_indices.iter().position(|&i| i == segment_id) {
+            self.failed_indices.remove(pos);
+            self.failed_segments -= 1;
+            self.failed_duration -= duration;
+        }
+        self.downloaded_segments += 1;
+        self.downloaded_bytes += bytes;
+        self.downloaded_duration += duration;
+        self.segment_records.push(SegmentRecord { index: segment_id, bytes, elapsed });
+
+        self.set_state(segment_id, ChunkState::Completed);
+    }
+
+    fn progress_percent(&self) -> f64 {
+        if self.total_segments > 0 {
+            (self.downloaded_segments as f64 / self.total_segments as f64) * 100.0
+        } else {
+            0.0
+        }
+    }
+
+    /// 按已下载媒体时长计算的进度；播放列表没有时长信息时退回按片段数
+    fn time_progress_percent(&self) -> f64 {
+        if self.total_duration > 0.0 {
+            (self.downloaded_duration / self.total_duration * 100.0).min(100.0)
+        } else {
+            self.progress_percent()
+        }
+    }
+
+    fn average_speed(&self) -> f64 {
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            ((self.downloaded_bytes - self.resumed_bytes) as f64) / elapsed / (1024.0 * 1024.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// 平均每个连接的吞吐 (MB/s)，用来判断增加并发是否还有意义
+    fn per_connection_speed(&self) -> Option<f64> {
+        if self.active_downloads > 0 {
+            Some(self.current_speed / self.active_downloads as f64)
+        } else {
+            None
+        }
+    }
+
+    fn elapsed_time(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    /// 界面中按 +/- 调整并发，在 1 到 --max-concurrent 之间
+    fn request_concurrency(&mut self, delta: isize) {
+        if self.max_concurrency == 0 {
+            return;
+        }
+        let current = self.concurrency_target.unwrap_or(self.concurrency);
+        self.concurrency_target = Some(current.saturating_add_signed(delta).clamp(1, self.max_concurrency));
+    }
+
+    /// 尚无最终结果的片段数：未开始、下载中以及仍在重试的片段都算在内
+    fn outstanding_segments(&self) -> usize {
+        self.total_segments
+            .saturating_sub(self.downloaded_segments + self.failed_segments)
+    }
+
+    /// 最近 ETA_SPEED_SAMPLES 次速度采样的平均值 (MB/s)；还没有采样时退回全程平均速度
+    fn recent_speed(&self) -> f64 {
+        let samples = self.speed_history.len().min(ETA_SPEED_SAMPLES);
+        if samples == 0 {
+            return self.average_speed();
+        }
+        self.speed_history.iter().rev().take(samples).sum::<f64>() / samples as f64
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        // 直播的片段总数还会增长，没有可预估的结束时间
+        if self.live {
+            return None;
+        }
+        // 用近期速度而不是全程平均：开头慢、后来变快时全程平均会长时间偏悲观
+        let speed = self.recent_speed();
+        if speed > 0.0 && self.downloaded_segments > 0 {
+            // 片段时长不一致时按剩余媒体时长估算，比按片段数更准确；
+            // 已最终失败的片段不会再下载，不计入剩余量
+            let remaining_bytes = if self.total_duration > 0.0 && self.downloaded_duration > 0.0 {
+                let bytes_per_second = self.downloaded_bytes as f64 / self.downloaded_duration;
+                let remaining_duration = self.total_duration - self.downloaded_duration - self.failed_duration;
+                remaining_duration.max(0.0) * bytes_per_second
+            } else {
+                let remaining = self.outstanding_segments();
+                let avg_size = self.downloaded_bytes as f64 / self.downloaded_segments as f64;
+                remaining as f64 * avg_size
+            };
+            let eta_seconds = remaining_bytes / (speed * 1024.0 * 1024.0);
+            Duration::try_from_secs_f64(eta_seconds).ok()
+        } else {
+            None
+        }
+    }
+}
+
+/// 格式化媒体时长，例如 "1h23m" 或 "4m05s"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// 格式化为时钟形式，例如 "01:23:45"
+fn format_clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, (secs % 3600) / 60, secs % 60)
+}
+
+fn draw_ui(f: &mut Frame, stats: &DownloadStats, url: &str, output: &str, ui: &UiOptions) {
+    let size = f.size();
+    if size.width < MIN_TUI_WIDTH || size.height < MIN_TUI_HEIGHT || Panel::ALL.iter().all(|&p| !ui.shows(p)) {
+        draw_compact(f, size, stats, ui);
+        return;
+    }
+
+    // 主布局：顶部Logo + 主体
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),  // Logo
+            Constraint::Min(0),     // Main
+        ])
+        .split(size);
+
+    // Logo
+    let logo = Paragraph::new(Line::from(vec![
+        Span::styled("S", ui.fg(ui.theme.accent).add_modifier(Modifier::BOLD)),
+        Span::styled("U", ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)),
+        Span::styled("R", ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)),
+        Span::styled("G", ui.fg(ui.theme.accent).add_modifier(Modifier::BOLD)),
+        Span::styled("E", ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)),
+        Span::styled(" M3U8 ", ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)),
+        Span::styled("Quad", ui.fg(ui.theme.muted).add_modifier(Modifier::ITALIC)),
+    ]))
+    .block(Block::default().borders(Borders::ALL).border_style(ui.fg(ui.theme.label)))
+    .alignment(ratatui::layout::Alignment::Center);
+    f.render_widget(logo, chunks[0]);
+
+    // 主体：上排 Info + Speed，下排 Activity + Stats + ChunkMap，隐藏的面板让出宽度给同排的其它面板
+    let rows: Vec<Vec<Panel>> = [
+        vec![Panel::Info, Panel::Speed],
+        vec![Panel::Activity, Panel::Stats, Panel::Chunks],
+    ]
+    .into_iter()
+    .map(|row| row.into_iter().filter(|&panel| ui.shows(panel)).collect::<Vec<_>>())
+    .filter(|row| !row.is_empty())
+    .collect();
+    let row_count = rows.len() as u32;
+    let main_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(rows.iter().map(|_| Constraint::Ratio(1, row_count)))
+        .split(chunks[1]);
+
+    for (row, row_area) in rows.iter().zip(main_chunks.iter()) {
+        let total: u32 = row.iter().map(|panel| panel.weight()).sum();
+        let areas = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints(row.iter().map(|panel| Constraint::Ratio(panel.weight(), total)))
+            .split(*row_area);
+        for (&panel, &area) in row.iter().zip(areas.iter()) {
+            match panel {
+                Panel::Info => draw_info_panel(f, area, stats, url, output, ui),
+                Panel::Speed => draw_graph_panel(f, area, stats, ui),
+                Panel::Activity => draw_activity_panel(f, area, stats, ui),
+                Panel::Stats => draw_stats_panel(f, area, stats, ui),
+                Panel::Chunks => draw_chunkmap_panel(f, area, stats, ui),
+            }
+        }
+    }
+}
+
+/// 终端太小或所有面板都被隐藏时，只显示一行进度
+fn draw_compact(f: &mut Frame, area: Rect, stats: &DownloadStats, ui: &UiOptions) {
+    let line = Line::from(vec![
+        Span::styled("SURGE ", ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)),
+        Span::raw(progress_summary(stats, ui)),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+}
+
+/// 按百分比画 width 格宽的进度条
+fn progress_bar(percent: f64, width: usize, ascii: bool) -> String {
+    let filled = ((percent / 100.0 * width as f64) as usize).min(width);
+    let (fill_char, empty_char) = if ascii { ("#", ".") } else { ("█", "░") };
+    format!("{}{}", fill_char.repeat(filled), empty_char.repeat(width - filled))
+}
+
+fn draw_info_panel(f: &mut Frame, area: Rect, stats: &DownloadStats, url: &str, output: &str, ui: &UiOptions) {
+    let url_display = truncate_display(url, 25);
+
+    let percent = ui.progress_percent(stats);
+
+    let mut text = vec![
+        Line::from(vec![
+            Span::styled("URL: ", ui.fg(ui.theme.label)),
+            Span::raw(url_display),
+        ]),
+        Line::from(vec![
+            Span::styled("Output: ", ui.fg(ui.theme.label)),
+            Span::raw(output.to_string()),
+        ]),
+        if stats.total_duration > 0.0 {
+            Line::from(vec![
+                Span::styled("Duration: ", ui.fg(ui.theme.label)),
+                Span::raw(format_clock(Duration::from_secs_f64(stats.total_duration))),
+            ])
+        } else {
+            Line::from("")
+        },
+        Line::from(vec![
+            Span::styled("Progress: ", ui.fg(ui.theme.label)),
+            Span::styled(progress_bar(percent, 20, ui.ascii), ui.fg(ui.theme.highlight)),
+            Span::raw(format!(" {:.1}%", percent)),
+        ]),
+        Line::from(vec![
+            Span::styled("Segments: ", ui.fg(ui.theme.label)),
+            Span::styled(
+                format!("{}", stats.downloaded_segments),
+                ui.fg(ui.theme.completed)
+            ),
+            Span::raw("/"),
+            Span::raw(format!("{}", stats.total_segments)),
+            if stats.failed_segments > 0 {
+                Span::styled(
+                    format!(" ({}{})", stats.failed_segments, if ui.ascii { "x" } else { "✗" }),
+                    ui.fg(ui.theme.failed)
+                )
+            } else {
+                Span::raw("")
+            },
+        ]),
+    ];
+    if stats.discontinuities > 0 {
+        text.push(Line::from(vec![
+            Span::styled("Discontinuities: ", ui.fg(ui.theme.label)),
+            Span::styled(stats.discontinuities.to_string(), ui.fg(ui.theme.partial)),
+        ]));
+    }
+    if let Some(batch) = stats.batch {
+        let overall = batch.overall_percent(percent);
+        let mut spans = vec![
+            Span::styled("Batch: ", ui.fg(ui.theme.label)),
+            Span::styled(progress_bar(overall, 10, ui.ascii), ui.fg(ui.theme.accent)),
+            Span::raw(format!(" {:.1}% {}/{}", overall, batch.current, batch.total)),
+        ];
+        if batch.failed > 0 {
+            spans.push(Span::styled(
+                format!(" ({}{})", batch.failed, if ui.ascii { "x" } else { "✗" }),
+                ui.fg(ui.theme.failed),
+            ));
+        }
+        text.insert(0, Line::from(spans));
+    }
+    if let (Some(estimated), Some(available)) = (stats.estimated_size, stats.available_space) {
+        text.push(Line::from(vec![
+            Span::styled("Disk: ", ui.fg(ui.theme.label)),
+            Span::raw(format!("~{} / {} free", format_bytes(estimated), format_bytes(available))),
+        ]));
+    }
+
+    let paragraph = Paragraph::new(text)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(ui.fg(ui.theme.highlight))
+                .title(Line::from(vec![
+                    Span::styled("Info", ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)),
+                    if stats.live {
+                        Span::styled(
+                            if ui.ascii { " * LIVE " } else { " ● LIVE " },
+                            ui.fg(ui.theme.failed).add_modifier(Modifier::BOLD)
+                        )
+                    } else {
+                        Span::raw("")
+                    },
+                    if stats.paused {
+                        Span::styled(" PAUSED ", ui.fg(ui.theme.failed).add_modifier(Modifier::BOLD | Modifier::REVERSED))
+                    } else {
+                        Span::raw("")
+                    },
+                ]))
+        );
+    f.render_widget(paragraph, area);
+}
+
+fn draw_graph_panel(f: &mut Frame, area: Rect, stats: &DownloadStats, ui: &UiOptions) {
+    let max_speed = stats.speed_history.iter().cloned().fold(0.0f64, f64::max).max(1.0);
+    let avg_speed = stats.average_speed();
+
+    let mut lines = vec![
+        Line::from(vec![
+            Span::styled(
+                if ui.ascii { "v Speed  " } else { "▼ Speed  " },
+                ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)
+            ),
+            Span::styled(
+                format!("Peak: {:.2} {}  ", ui.speed_value(max_speed), ui.speed_unit()),
+                ui.fg(ui.theme.highlight)
+            ),
+            Span::styled(
+                format!("Avg: {:.2} {}", ui.speed_value(avg_speed), ui.speed_unit()),
+                ui.fg(ui.theme.accent)
+            ),
+        ]),
+    ];
+
+    // 绘制速度图表：边框占两行两列，标题再占一行；缩放时面板可能只有零宽或一行
+    let graph_height = (area.height as usize).saturating_sub(3);
+    let graph_width = (area.width as usize).saturating_sub(2);
+    let points: Vec<f64> = stats.speed_history.iter().cloned().collect();
+    let display_points = &points[points.len().saturating_sub(graph_width)..];
+
+    let block_chars = if ui.ascii {
+        [' ', '.', '.', '.', '*', '*', '*', '#', '#']
+    } else {
+        [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█']
+    };
+
+    let row_step = max_speed / graph_height.max(1) as f64;
+    for row in (0..graph_height).rev() {
+        // 每行覆盖 (floor, floor + row_step]，速度落在行内时按比例画部分方块
+        let floor = row as f64 * row_step;
+        let mut line_spans = Vec::new();
+
+        for &speed in display_points {
+            if speed > floor {
+                let ratio = ((speed - floor) / row_step).clamp(0.0, 1.0);
+                let ch = block_chars[(ratio * 8.0).round() as usize];
+
+                let color = if speed > max_speed * 0.7 {
+                    ui.theme.highlight
+                } else if speed > max_speed * 0.4 {
+                    ui.theme.accent
+                } else {
+                    ui.theme.label
+                };
+
+                line_spans.push(Span::styled(ch.to_string(), ui.fg(color)));
+            } else {
+                line_spans.push(Span::raw(" "));
+            }
+        }
+
+        lines.push(Line::from(line_spans));
+    }
+
+    let paragraph = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(ui.fg(ui.theme.label))
+        );
+    f.render_widget(paragraph, area);
+}
+
+fn draw_activity_panel(f: &mut Frame, area: Rect, stats: &DownloadStats, ui: &UiOptions) {
+    // 只显示能放进面板的最后几条，向上滚动时窗口随之上移
+    let visible = (area.height as usize).saturating_sub(2);
+    let end = stats.activity_log.len().saturating_sub(stats.activity_scroll);
+    let start = end.saturating_sub(visible);
+
+    let lines: Vec<Line> = if stats.activity_log.is_empty() {
+        vec![Line::from(Span::styled("Waiting...", ui.fg(ui.theme.muted)))]
+    } else {
+        stats.activity_log.range(start..end).map(|item| {
+            let (icon, color) = match (&item.status, ui.ascii) {
+                (ActivityStatus::Success, false) => ("✓ ", ui.theme.completed),
+                (ActivityStatus::Failed, false) => ("✗ ", ui.theme.failed),
+                (ActivityStatus::Downloading, false) => ("⟳ ", ui.theme.label),
+                (ActivityStatus::Success, true) => ("+ ", ui.theme.completed),
+                (ActivityStatus::Failed, true) => ("x ", ui.theme.failed),
+                (ActivityStatus::Downloading, true) => ("~ ", ui.theme.label),
+            };
+
+            let name = truncate_display(&item.name, 20);
+
+            Line::from(vec![
+                Span::styled(icon, ui.fg(color)),
+                Span::raw(name),
+            ])
+        }).collect()
+    };
+
+    let paragraph = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(ui.fg(ui.theme.accent))
+                .title(Line::from(vec![
+                    Span::styled("Activity", ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)),
+                    if stats.activity_scroll > 0 {
+                        Span::styled(
+                            format!(" {}{} ", if ui.ascii { "^" } else { "↑" }, stats.activity_scroll),
+                            ui.fg(ui.theme.muted)
+                        )
+                    } else {
+                        Span::raw("")
+                    },
+                ]))
+        );
+    f.render_widget(paragraph, area);
+}
+
+fn draw_stats_panel(f: &mut Frame, area: Rect, stats: &DownloadStats, ui: &UiOptions) {
+    let elapsed = stats.elapsed_time();
+    let eta = stats.eta();
+
+    let lines = vec![
+        Line::from(vec![
+            Span::styled("Speed: ", ui.fg(ui.theme.label)),
+            Span::styled(format!("{:.1}", ui.speed_value(stats.current_speed)), ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)),
+        ]),
+        Line::from(vec![
+            Span::styled("Conn: ", ui.fg(ui.theme.label)),
+            Span::styled(
+                match stats.per_connection_speed() {
+                    Some(per_conn) => format!("{}/{} × {:.1}", stats.active_downloads, stats.concurrency, ui.speed_value(per_conn)),
+                    None => format!("0/{}", stats.concurrency),
+                },
+                ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("Down: ", ui.fg(ui.theme.label)),
+            Span::styled(
+                format_bytes(stats.downloaded_bytes),
+                ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("Net: ", ui.fg(ui.theme.label)),
+            Span::styled(
+                format_bytes(stats.transferred.load(Ordering::Relaxed)),
+                ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("Time: ", ui.fg(ui.theme.label)),
+            Span::styled(
+                format!("{}m{}s", elapsed.as_secs() / 60, elapsed.as_secs() % 60),
+                ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)
+            ),
+        ]),
+        if let Some(eta_duration) = eta {
+            Line::from(vec![
+                Span::styled("ETA: ", ui.fg(ui.theme.label)),
+                Span::styled(
+                    format!("{}m{}s", eta_duration.as_secs() / 60, eta_duration.as_secs() % 60),
+                    ui.fg(ui.theme.highlight).add_modifier(Modifier::BOLD)
+                ),
+            ])
+        } else {
+            Line::from("")
+        },
+    ];
+
+    let paragraph = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(ui.fg(ui.theme.accent))
+                .title(Span::styled("Stats", ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)))
+        );
+    f.render_widget(paragraph, area);
+}
+
+fn draw_chunkmap_panel(f: &mut Frame, area: Rect, stats: &DownloadStats, ui: &UiOptions) {
+    // 每个方块占两列，窄到放不下一个时也按一个一行处理，避免除以零
+    let chunks_per_row = ((area.width as usize).saturating_sub(2) / 2).max(1);
+    let mut lines = Vec::new();
+    let mut current_line = Vec::new();
+
+    for (i, state) in stats.chunk_states().iter().enumerate() {
+        if i > 0 && i % chunks_per_row == 0 {
+            lines.push(Line::from(current_line.clone()));
+            current_line.clear();
+        }
+
+        let ascii_symbol = match state {
+            ChunkState::Completed => "# ",
+            ChunkState::Partial => "+ ",
+            ChunkState::Downloading => "* ",
+            ChunkState::Failed => "x ",
+            ChunkState::Pending => ". ",
+        };
+        // 单色终端上方块无法靠颜色区分，改用各状态的字符
+        let symbol = if ui.ascii || ui.color_depth == ColorDepth::Mono { ascii_symbol } else { "■ " };
+
+        current_line.push(Span::styled(symbol, ui.fg(state.color(&ui.theme))));
+    }
+
+    if !current_line.is_empty() {
+        lines.push(Line::from(current_line));
+    }
+
+    let paragraph = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(ui.fg(ui.theme.accent))
+                .title(Span::styled("Chunks", ui.fg(ui.theme.label).add_modifier(Modifier::BOLD)))
+        );
+    f.render_widget(paragraph, area);
+}
+
+/// 片段数据的去向。下载引擎只负责取回并校验数据，每个片段完整收到后连同序号交给 sink：
+/// 命令行用 TempFileSink 写入临时目录再合并，嵌入方可以接到自己的存储或封装器
+trait SegmentSink: Send + Sync {
+    /// 收下第 index 个片段的完整数据；返回错误时该片段按下载失败处理并重试
+    fn write(&self, index: usize, bytes: Bytes) -> BoxFuture<'_, Result<()>>;
+}
+
+/// `FnMut(usize, Bytes)` 闭包包在 Mutex 里即可作为 sink，片段按完成的先后交给闭包
+impl<F: FnMut(usize, Bytes) + Send> SegmentSink for std::sync::Mutex<F> {
+    fn write(&self, index: usize, bytes: Bytes) -> BoxFuture<'_, Result<()>> {
+        (self.lock().unwrap())(index, bytes);
+        Box::pin(std::future::ready(Ok(())))
+    }
+}
+
+/// 默认的 sink：每个片段写成临时目录下的一个文件，合并、--resume 和片段校验都读取这些文件
+struct TempFileSink {
+    dir: PathBuf,
+    write_buffer_size: usize,
+    /// 各片段临时文件的扩展名，按序号排列；没有登记的片段用 ts
+    extensions: std::sync::RwLock<Vec<&'static str>>,
+    /// 各片段使用的初始化片段编号，按序号排列
+    segment_inits: std::sync::RwLock<Vec<Option<usize>>>,
+    /// 出现过的初始化片段，下标即 init_NN.mp4 的编号
+    inits: std::sync::Mutex<Vec<InitSegment>>,
+}
+
+impl TempFileSink {
+    fn new(dir: PathBuf, write_buffer_size: usize) -> Self {
+        Self {
+            dir,
+            write_buffer_size,
+            extensions: std::sync::RwLock::new(Vec::new()),
+            segment_inits: std::sync::RwLock::new(Vec::new()),
+            inits: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 登记从 first_index 开始的片段，之后它们的临时文件沿用原始的扩展名，合并时找得到各自的初始化片段
+    fn register_segments(&self, first_index: usize, segments: &[Segment]) {
+        let ids: Vec<Option<usize>> = segments.iter()
+            .map(|segment| segment.init.as_ref().map(|init| self.init_index(init)))
+            .collect();
+        let mut extensions = self.extensions.write().unwrap();
+        let mut segment_inits = self.segment_inits.write().unwrap();
+        if extensions.len() < first_index + segments.len() {
+            extensions.resize(first_index + segments.len(), "ts");
+            segment_inits.resize(first_index + segments.len(), None);
+        }
+        for (i, (segment, id)) in segments.iter().zip(ids).enumerate() {
+            extensions[first_index + i] = segment_extension(segment);
+            segment_inits[first_index + i] = id;
+        }
+    }
+
+    /// 初始化片段的编号，第一次出现时分配
+    fn init_index(&self, init: &InitSegment) -> usize {
+        let mut inits = self.inits.lock().unwrap();
+        match inits.iter().position(|known| known == init) {
+            Some(index) => index,
+            None => {
+                inits.push(init.clone());
+                inits.len() - 1
+            }
+        }
+    }
+
+    fn init_file_name(&self, id: usize) -> String {
+        format!("init_{:02}.mp4", id)
+    }
+
+    fn segment_init(&self, index: usize) -> Option<usize> {
+        self.segment_inits.read().unwrap().get(index).copied().flatten()
+    }
+
+    /// 每个初始化片段只写一个文件，片段文件中只有片段本身的数据
+    async fn write_init(&self, init: &InitSegment, bytes: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(self.init_file_name(self.init_index(init)));
+        let part = part_path(&path);
+        fs::write(&part, bytes).await?;
+        fs::rename(&part, &path).await?;
+        Ok(())
+    }
+
+    /// 序号补零到 5 位，按文件名排序就是片段顺序
+    fn file_name(&self, index: usize) -> String {
+        let extension = self.extensions.read().unwrap().get(index).copied().unwrap_or("ts");
+        format!("segment_{:05}.{}", index, extension)
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        self.dir.join(self.file_name(index))
+    }
+
+    /// 缺失或为空的片段序号
+    fn missing_segments(&self, segment_count: usize) -> Vec<usize> {
+        (0..segment_count).filter(|&i| !self.has_segment(i)).collect()
+    }
+
+    fn has_segment(&self, index: usize) -> bool {
+        std::fs::metadata(self.path(index))
+            .map(|meta| meta.len() > 0)
+            .unwrap_or(false)
+    }
+
+    /// range 中已下载片段在 concat 列表中的输入（绝对路径），按片段顺序排列。fMP4 片段没有 moov，concat demuxer
+    /// 不能单独打开：使用同一初始化片段的连续片段写进一个 concatf 列表，初始化片段只在开头列一次，
+    /// 整组按字节拼接成一个完整的 fMP4，作为 concat 列表中的一项
+    async fn merge_inputs(&self, range: std::ops::Range<usize>) -> Result<Vec<PathBuf>> {
+        let mut runs: Vec<(Option<usize>, usize, Vec<PathBuf>)> = Vec::new();
+        for i in range.filter(|&i| self.has_segment(i)) {
+            let path = self.path(i).canonicalize()?;
+            let init = self.segment_init(i);
+            match runs.last_mut() {
+                Some((run_init, _, paths)) if init.is_some() && *run_init == init => paths.push(path),
+                _ => runs.push((init, i, vec![path])),
+            }
+        }
+
+        let mut inputs = Vec::new();
+        for (init, first_index, paths) in runs {
+            let Some(id) = init else {
+                inputs.extend(paths);
+                continue;
+            };
+            let init_path = self.dir.join(self.init_file_name(id)).canonicalize()?;
+            let list_path = self.dir.join(format!("fmp4_{:05}.txt", first_index));
+            let list: String = std::iter::once(&init_path)
+                .chain(&paths)
+                .map(|path| format!("{}\n", plain_path(path)))
+                .collect();
+            fs::write(&list_path, list).await?;
+            inputs.push(PathBuf::from(format!("concatf:{}", plain_path(&list_path.canonicalize()?))));
+        }
+        Ok(inputs)
+    }
+}
+
+impl SegmentSink for TempFileSink {
+    /// 先写到 .part，完整写完才改名：出错、Ctrl-C 或超出失败上限而中途取消时，
+    /// 写了一半的文件不会被 --resume 当成已完成的片段
+    fn write(&self, index: usize, bytes: Bytes) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let path = self.path(index);
+            let part = part_path(&path);
+            let result = async {
+                let mut file = File::create(&part).await?;
+                // 按 write_buffer_size 分块写入
+                for chunk in bytes.chunks(self.write_buffer_size) {
+                    file.write_all(chunk).await?;
+                }
+                file.flush().await?;
+                fs::rename(&part, &path).await
+            }
+            .await;
+            if result.is_err() {
+                let _ = fs::remove_file(&part).await;
+            }
+            Ok(result?)
+        })
+    }
+}
+
+/// `--stream-merge`：片段按序号顺序写入 ffmpeg 的标准输入，写入后立即删除临时文件，
+/// 磁盘上只保留乱序完成、还没轮到的片段。`--single-file-temp` 用同样的方式按顺序追加到一个临时文件
+struct StreamMerger {
+    /// 写入单个临时文件时没有 ffmpeg 进程
+    child: Option<tokio::process::Child>,
+    stdin: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+    next_index: usize,
+    /// 已结束但还没轮到的片段，None 表示最终失败、直接跳过
+    pending: BTreeMap<usize, Option<PathBuf>>,
+    error: Option<anyhow::Error>,
+    /// 在后台读取 ffmpeg 的标准错误，失败时用于报错；不及时读取时管道写满会让 ffmpeg 卡住
+    stderr: Option<tokio::task::JoinHandle<Vec<u8>>>,
+    /// ffmpeg 的最终输出文件，合并期间写在它的 .part 上，成功后才改名
+    output_path: Option<PathBuf>,
+}
+
+impl StreamMerger {
+    fn spawn(ffmpeg: &Path, output_path: &Path, container: Container, transcode: &TranscodeOptions) -> Result<Self> {
+        let mut child = tokio::process::Command::new(ffmpeg)
+            .args(["-f", "mpegts", "-i", "pipe:0"])
+            .args(transcode.ffmpeg_args())
+            .args(["-f", container.muxer(), "-y"])
+            .arg(part_path(output_path))
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("Failed to start ffmpeg for --stream-merge")?;
+        let stdin = child.stdin.take().map(|stdin| Box::new(stdin) as Box<dyn AsyncWrite + Send + Unpin>);
+        // 下载期间 TUI 占着终端，不转发，只留着失败时报告
+        let stderr = child.stderr.take().map(|stderr| tokio::spawn(collect_stderr(stderr, false)));
+        Ok(Self {
+            child: Some(child),
+            stdin,
+            next_index: 0,
+            pending: BTreeMap::new(),
+            error: None,
+            stderr,
+            output_path: Some(output_path.to_path_buf()),
+        })
+    }
+
+    /// 按顺序把片段追加到 path，下载结束后再整体交给 ffmpeg
+    async fn to_file(path: &Path, buffer_size: usize) -> Result<Self> {
+        let file = File::create(path).await?;
+        Ok(Self {
+            child: None,
+            stdin: Some(Box::new(BufWriter::with_capacity(buffer_size, file))),
+            next_index: 0,
+            pending: BTreeMap::new(),
+            error: None,
+            stderr: None,
+            output_path: None,
+        })
+    }
+
+    /// 记录片段结束，并把从 next_index 起连续就绪的片段写入管道
+    async fn complete(&mut self, index: usize, path: Option<PathBuf>) {
+        self.pending.insert(index, path);
+        while let Some(path) = self.pending.remove(&self.next_index) {
+            self.next_index += 1;
+            let Some(path) = path else { continue };
+            if self.error.is_some() {
+                continue;
+            }
+            if let Err(e) = self.write_segment(&path).await {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    async fn write_segment(&mut self, path: &Path) -> Result<()> {
+        let stdin = self.stdin.as_mut().context("ffmpeg stdin already closed")?;
+        let bytes = fs::read(path).await?;
+        stdin.write_all(&bytes).await.context("ffmpeg stopped reading segments")?;
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    /// 关闭管道并等待 ffmpeg 写完输出文件，成功后把 .part 改名为输出文件，失败时删除
+    async fn finish(mut self) -> Result<()> {
+        let output_path = self.output_path.take();
+        let result = self.wait().await;
+        if let Some(output_path) = output_path {
+            match &result {
+                Ok(()) => fs::rename(part_path(&output_path), &output_path).await?,
+                Err(_) => {
+                    let _ = fs::remove_file(part_path(&output_path)).await;
+                }
+            }
+        }
+        result
+    }
+
+    async fn wait(mut self) -> Result<()> {
+        if let Some(mut stdin) = self.stdin.take() {
+            if let Err(e) = stdin.shutdown().await {
+                self.error.get_or_insert(e.into());
+            }
+        }
+        let status = match &mut self.child {
+            Some(child) => Some(child.wait().await?),
+            None => None,
+        };
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        if status.is_some_and(|status| !status.success()) {
+            let stderr = match self.stderr {
+                Some(handle) => handle.await.unwrap_or_default(),
+                None => Vec::new(),
+            };
+            return Err(ffmpeg_failure(&stderr));
+        }
+        Ok(())
+    }
+
+    async fn abort(mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill().await;
+        }
+        if let Some(output_path) = &self.output_path {
+            let _ = fs::remove_file(part_path(output_path)).await;
+        }
+    }
+}
+
+/// 所有下载任务共享的令牌桶，限制总下载速度
+struct RateLimiter {
+    bytes_per_sec: f64,
+    /// 当前可用的字节数（可为负，表示欠下的额度）与上次补充的时间
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            bytes_per_sec: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// 扣除 `bytes` 个令牌，额度不足时等待补足；桶容量为一秒的流量
+    async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+            let refill = now.duration_since(state.1).as_secs_f64() * self.bytes_per_sec;
+            state.0 = (state.0 + refill).min(self.bytes_per_sec) - bytes as f64;
+            state.1 = now;
+            if state.0 < 0.0 {
+                Duration::from_secs_f64(-state.0 / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 构造 M3U8Downloader 时的可调参数
+#[derive(Clone)]
+struct DownloadOptions {
+    concurrent_limit: usize,
+    sequential_window: Option<usize>,
+    headers: Vec<(http::HeaderName, http::HeaderValue)>,
+    user_agent: Option<http::HeaderValue>,
+    cookies: Option<CookieSource>,
+    proxy: Option<wreq::Proxy>,
+    playlist_accept: String,
+    write_buffer_size: usize,
+    bearer_token: Option<String>,
+    /// 合并用的文件列表和中间文件目录，默认与片段目录相同
+    merge_temp_dir: Option<PathBuf>,
+    /// 并行合并分组时同时运行的 ffmpeg 进程数
+    merge_jobs: usize,
+    /// 合并时转发 ffmpeg 的标准错误
+    verbose: bool,
+    /// 请求片段使用的 HTTP 方法
+    segment_method: http::Method,
+    /// 片段请求体模板，`{index}` 与 `{url}` 会被替换
+    segment_body: Option<String>,
+    /// 逐步加大并发，吞吐量不再提升时停在服务器允许的连接数
+    detect_connection_limit: bool,
+    /// 开始下载时把并发从 1 逐步加到上限所用的时间
+    ramp_up: Duration,
+    /// 相对地址继承播放列表 URL 的查询参数
+    inherit_query: bool,
+    /// `--concurrent auto`：在 concurrent_limit 以内按速度趋势持续调整并发数
+    adaptive_concurrency: bool,
+    /// 界面中按 + 能调到的并发数，不低于 concurrent_limit
+    max_concurrency: usize,
+    /// 首次失败后的最大重试次数
+    retries: u32,
+    /// 第一次重试前的等待时间，之后每次翻倍
+    retry_delay: Duration,
+    /// 主播放列表中选择的清晰度
+    quality: Quality,
+    /// 直播优先选择低延迟变体，并提前下载末尾未完成片段的部分片段 (#EXT-X-PART)
+    low_latency: bool,
+    /// 独立音轨的首选语言
+    audio_lang: Option<String>,
+    /// 下载字幕时的首选语言，空字符串表示默认字幕
+    subs: Option<String>,
+    /// 总下载速度上限（字节/秒）
+    limit_rate: Option<u64>,
+    /// 建立连接的超时
+    connect_timeout: Duration,
+    /// 两次读到数据之间的最长间隔，不限制整个请求的总时长
+    read_timeout: Duration,
+    /// 合并使用的 ffmpeg 可执行文件
+    ffmpeg_path: PathBuf,
+    /// 输出文件已存在时的处理方式
+    on_collision: OnCollision,
+    /// 片段临时目录，默认为输出目录下的 temp
+    temp_dir: Option<PathBuf>,
+    /// 复用已有的 client（--batch 的各个条目共用一个），此时不再按代理、超时等选项新建
+    client: Option<wreq::Client>,
+    /// 片段数据的去向，默认写入临时目录（TempFileSink）；换成其它 sink 时临时目录中没有片段，
+    /// 合并、--resume 和片段校验都会报错，由嵌入方自行处理
+    sink: Option<Arc<dyn SegmentSink>>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            concurrent_limit: 10,
+            sequential_window: None,
+            headers: Vec::new(),
+            user_agent: None,
+            cookies: None,
+            proxy: None,
+            playlist_accept: "*/*".to_string(),
+            write_buffer_size: 64 * 1024,
+            bearer_token: None,
+            merge_temp_dir: None,
+            merge_jobs: 1,
+            verbose: false,
+            segment_method: http::Method::GET,
+            segment_body: None,
+            detect_connection_limit: false,
+            ramp_up: Duration::ZERO,
+            inherit_query: false,
+            adaptive_concurrency: false,
+            max_concurrency: MAX_CONCURRENCY as usize,
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            quality: Quality::Best,
+            low_latency: false,
+            audio_lang: None,
+            subs: None,
+            limit_rate: None,
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            ffmpeg_path: PathBuf::from("ffmpeg"),
+            on_collision: OnCollision::Overwrite,
+            temp_dir: None,
+            client: None,
+            sink: None,
+        }
+    }
+}
+
+struct M3U8Downloader {
+    url: String,
+    output_dir: PathBuf,
+    temp_dir: PathBuf,
+    merge_temp_dir: PathBuf,
+    merge_jobs: usize,
+    verbose: bool,
+    client: wreq::Client,
+    concurrent_limit: usize,
+    sequential_window: Option<usize>,
+    custom_headers: http::HeaderMap,
+    sink: Arc<dyn SegmentSink>,
+    /// 默认 sink 写下的片段文件；自定义 sink 时为 None
+    temp_files: Option<Arc<TempFileSink>>,
+    user_agent: Option<http::HeaderValue>,
+    cookie_header: Option<http::HeaderValue>,
+    playlist_accept: String,
+    write_buffer_size: usize,
+    bearer_token: Option<String>,
+    segment_method: http::Method,
+    segment_body: Option<String>,
+    detect_connection_limit: bool,
+    ramp_up: Duration,
+    inherit_query: bool,
+    adaptive_concurrency: bool,
+    max_concurrency: usize,
+    retries: u32,
+    retry_delay: Duration,
+    quality: Quality,
+    low_latency: bool,
+    audio_lang: Option<String>,
+    subs: Option<String>,
+    rate_limiter: Option<RateLimiter>,
+    ffmpeg_path: PathBuf,
+    on_collision: OnCollision,
+    /// 已下载的 EXT-X-MAP 初始化片段，按地址和字节范围缓存
+    init_segments: Mutex<HashMap<InitSegment, Arc<Vec<u8>>>>,
+    events: broadcast::Sender<ProgressEvent>,
+    /// 所有请求收到的响应体字节数，包括播放列表、初始化片段以及失败后丢弃的响应
+    transferred: Arc<AtomicU64>,
+}
+
+impl M3U8Downloader {
+    fn new(url: String, output_dir: PathBuf, options: DownloadOptions) -> Self {
+        let temp_dir = options.temp_dir.clone().unwrap_or_else(|| output_dir.join("temp"));
+        let merge_temp_dir = options.merge_temp_dir.clone().unwrap_or_else(|| temp_dir.clone());
+        let client = options.client.clone().unwrap_or_else(|| Self::build_client(&options));
+        let cookie_header = match options.cookies {
+            Some(CookieSource::Header(header)) => Some(header),
+            _ => None,
+        };
+
+        let mut custom_headers = http::HeaderMap::new();
+        for (name, value) in options.headers {
+            custom_headers.insert(name, value);
+        }
+        let write_buffer_size = options.write_buffer_size.max(1);
+        let (sink, temp_files) = match options.sink.clone() {
+            Some(sink) => (sink, None),
+            None => {
+                let files = Arc::new(TempFileSink::new(temp_dir.clone(), write_buffer_size));
+                (Arc::clone(&files) as Arc<dyn SegmentSink>, Some(files))
+            }
+        };
+
+        Self {
+            url,
+            output_dir,
+            temp_dir,
+            merge_temp_dir,
+            merge_jobs: options.merge_jobs.max(1),
+            verbose: options.verbose,
+            client,
+            concurrent_limit: options.concurrent_limit,
+            sequential_window: options.sequential_window,
+            custom_headers,
+            sink,
+            temp_files,
+            user_agent: options.user_agent,
+            cookie_header,
+            playlist_accept: options.playlist_accept,
+            write_buffer_size,
+            bearer_token: options.bearer_token,
+            segment_method: options.segment_method,
+            segment_body: options.segment_body,
+            detect_connection_limit: options.detect_connection_limit,
+            ramp_up: options.ramp_up,
+            inherit_query: options.inherit_query,
+            adaptive_concurrency: options.adaptive_concurrency,
+            max_concurrency: options.max_concurrency.max(options.concurrent_limit),
+            retries: options.retries,
+            retry_delay: options.retry_delay,
+            quality: options.quality,
+            low_latency: options.low_latency,
+            audio_lang: options.audio_lang,
+            subs: options.subs,
+            rate_limiter: options.limit_rate.map(RateLimiter::new),
+            ffmpeg_path: options.ffmpeg_path,
+            on_collision: options.on_collision,
+            init_segments: Mutex::new(HashMap::new()),
+            events: broadcast::channel(PROGRESS_EVENT_CAPACITY).0,
+            transferred: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn build_client(options: &DownloadOptions) -> wreq::Client {
+        // Emulate a real Chrome browser's TLS (JA3/JA4) and HTTP/2 fingerprints so the
+        // requests get past Cloudflare bot protection. A plain reqwest client is
+        // fingerprinted as a script and rejected with 403 regardless of headers.
+        let mut builder = wreq::Client::builder()
+            .connect_timeout(options.connect_timeout)
+            .read_timeout(options.read_timeout)
+            .emulation(wreq_util::Emulation::Chrome137)
+            // 模拟的 Chrome 会声明 Accept-Encoding: gzip, deflate, br, zstd，解码必须一并打开，
+            // 否则服务器压缩过的播放列表和片段会原样交给我们
+            .gzip(true)
+            .deflate(true)
+            .brotli(true)
+            .zstd(true);
+        // cookies.txt 按域名匹配，服务器后续下发的 Set-Cookie 也会保存到同一个 jar
+        if let Some(CookieSource::Jar(jar)) = &options.cookies {
+            builder = builder.cookie_provider(Arc::clone(jar));
+        }
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        builder
+            .build()
+            .expect("Failed to create HTTP client")
+    }
+
+    /// 订阅之后发生的下载事件
+    fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: ProgressEvent) {
+        // 没有订阅方时发送失败，直接忽略
+        let _ = self.events.send(event);
+    }
+
+    fn count_transfer(&self, bytes: usize) {
+        self.transferred.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn build_request(&self, url: &str) -> wreq::RequestBuilder {
+        self.with_default_headers(self.client.get(url), url)
+    }
+
+    fn with_default_headers(&self, mut req: wreq::RequestBuilder, url: &str) -> wreq::RequestBuilder {
+        if self.custom_headers.is_empty() {
+            let referer = if let Ok(parsed) = Url::parse(url) {
+                format!("{}://{}/", parsed.scheme(), parsed.host_str().unwrap_or(""))
+            } else {
+                String::new()
+            };
+            req = req
+                .header("Referer", &referer)
+                .header("Origin", referer.trim_end_matches('/'));
+        } else {
+            for (key, value) in &self.custom_headers {
+                req = req.header(key, value);
+            }
+        }
+        if let Some(user_agent) = &self.user_agent {
+            req = req.header(http::header::USER_AGENT, user_agent);
+        }
+        if let Some(cookie) = &self.cookie_header {
+            req = req.header(http::header::COOKIE, cookie);
+        }
+        if let Some(token) = &self.bearer_token {
+            req = req.bearer_auth(token);
+        }
+        req
+    }
+
+    /// 第 attempt 次重试前的等待时间：指数退避加随机抖动
+    fn retry_backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.retry_delay.saturating_mul(1 << (attempt - 1).min(16));
+        backoff + jitter(backoff / 2)
+    }
+
+    /// 获取播放列表文本，Accept 头取 --accept 的值
+    async fn fetch_playlist_text(&self, url: &str) -> Result<String> {
+        self.fetch_text(url, &self.playlist_accept, "Playlist").await
+    }
+
+    /// 获取播放列表、字幕等文本，网络错误、超时、429 和 5xx 与片段一样按 --retries 退避重试；
+    /// 其余 4xx 重试也不会成功，直接返回。what 用于错误信息
+    async fn fetch_text(&self, url: &str, accept: &str, what: &str) -> Result<String> {
+        let mut attempt = 0;
+        loop {
+            let (error, transient) = match self.build_request(url).header("Accept", accept).send().await {
+                Ok(response) if response.status().is_success() => match response.text().await {
+                    Ok(content) => {
+                        self.count_transfer(content.len());
+                        return Ok(content);
+                    }
+                    Err(e) => (anyhow::Error::from(e), true),
+                },
+                Ok(response) => {
+                    let status = response.status();
+                    let transient = status.is_server_error()
+                        || status == http::StatusCode::REQUEST_TIMEOUT
+                        || status == http::StatusCode::TOO_MANY_REQUESTS;
+                    (anyhow::anyhow!("HTTP request failed with status: {}", status), transient)
+                }
+                Err(e) => (anyhow::Error::from(e), true),
+            };
+            if !transient || attempt >= self.retries {
+                return Err(error.context(format!("{} {} is unreachable after {} attempt(s)", what, url, attempt + 1)));
+            }
+            attempt += 1;
+            tokio::time::sleep(self.retry_backoff(attempt)).await;
+        }
+    }
+
+    /// 下载全部字幕片段并拼接成一条时间轴
+    async fn download_subtitles(&self, segments: &[Segment]) -> Result<Vec<Cue>> {
+        let contents: Vec<String> = stream::iter(segments)
+            .map(|segment| self.fetch_text(&segment.url, "text/vtt, */*", "Subtitle segment"))
+            .buffered(self.concurrent_limit)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+        Ok(merge_vtt_segments(&contents))
+    }
+
+    /// 获取 EXT-X-MEDIA 引用的媒体播放列表并展开成片段
+    async fn fetch_rendition(&self, media: &m3u8_rs::AlternativeMedia) -> Result<Rendition> {
+        let url = self.resolve_url(media.uri.as_deref().unwrap_or_default())?;
+        let playlist = self.fetch_media_playlist(&url).await?;
+        let label = match &media.language {
+            Some(language) => format!("{} ({})", media.name, language),
+            None => media.name.clone(),
+        };
+        Ok(Rendition { label, segments: self.collect_segments(&playlist, &url)? })
+    }
+
+    /// 请求 self.url 并解析，主播放列表与媒体播放列表都原样返回
+    async fn fetch_playlist(&self) -> Result<Playlist> {
+        let content = self.fetch_playlist_text(&self.url).await?;
+
+        if content.trim_start().starts_with('<') {
+            if content.contains("<MPD") {
+                anyhow::bail!("This is a DASH (MPD) manifest; only HLS (M3U8) playlists are supported.");
+            }
+            if content.contains("cloudflare") || content.contains("Cloudflare") {
+                anyhow::bail!(
+                    "Request blocked by Cloudflare. The server is rejecting automated requests.\n\
+                     Try using a different network, VPN, or add custom headers with --header."
+                );
+            }
+            anyhow::bail!("Server returned HTML instead of M3U8 content. The URL may require authentication or is geo-restricted.");
+        }
+
+        m3u8_rs::parse_playlist_res(content.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to parse M3U8: {:?}", e))
+    }
+
+    /// 并发获取主播放列表中每个变体的媒体播放列表，得到时长和片段数，按码率从高到低排列
+    async fn list_variants(&self) -> Result<Vec<VariantInfo>> {
+        let master = match self.fetch_playlist().await? {
+            Playlist::MasterPlaylist(pl) => pl,
+            Playlist::MediaPlaylist(_) => anyhow::bail!("{} is a media playlist with a single format", self.url),
+        };
+        let variants: Vec<m3u8_rs::VariantStream> = master.variants.into_iter().filter(|v| !v.is_i_frame).collect();
+        let results: Vec<Result<VariantInfo>> = stream::iter(variants)
+            .map(|variant| async move {
+                let url = self.resolve_url(&variant.uri)?;
+                // 单个变体获取失败时仍然列出，只是没有时长
+                let media = self.fetch_media_playlist(&url).await.ok();
+                Ok(VariantInfo {
+                    duration: media.as_ref().map(|pl| pl.segments.iter().map(|s| s.duration as f64).sum()),
+                    segments: media.as_ref().map(|pl| pl.segments.len()),
+                    variant,
+                })
+            })
+            .buffer_unordered(self.concurrent_limit)
+            .collect()
+            .await;
+        let mut infos = results.into_iter().collect::<Result<Vec<_>>>()?;
+        infos.sort_by_key(|info| std::cmp::Reverse(info.variant.bandwidth));
+        Ok(infos)
+    }
+
+    async fn fetch_m3u8(&self) -> Result<StreamInfo> {
+        status!("📡 正在解析M3U8文件...");
+
+        let parsed = self.fetch_playlist().await?;
+        let mut audio = None;
+        let mut subtitles = None;
+        let mut start_offset = None;
+        let (playlist_url, media_pl, codecs, resolution) = match parsed {
+            Playlist::MasterPlaylist(pl) => {
+                start_offset = pl.start.as_ref().map(|start| start.time_offset);
+                let mut best_variant = select_variant(&pl.variants, self.quality)?;
+                let mut variant_pl = None;
+                if self.low_latency {
+                    let (variant, media_pl) = self.prefer_low_latency_variant(&pl.variants, best_variant).await?;
+                    best_variant = variant;
+                    variant_pl = Some(media_pl);
+                }
+
+                let variant_url = self.resolve_url(&best_variant.uri)?;
+                status!("  ✓ 选择 {}", describe_variant(best_variant));
+
+                let rendition = best_variant.audio.as_deref().and_then(|group| {
+                    select_rendition(&pl.alternatives, AlternativeMediaType::Audio, group, self.audio_lang.as_deref())
+                });
+                if let Some(rendition) = rendition {
+                    let rendition = self.fetch_rendition(rendition).await?;
+                    status!("  ✓ 独立音轨 {}", rendition.label);
+                    audio = Some(rendition);
+                }
+
+                if let Some(language) = &self.subs {
+                    let rendition = best_variant.subtitles.as_deref().and_then(|group| {
+                        select_rendition(&pl.alternatives, AlternativeMediaType::Subtitles, group, Some(language))
+                    });
+                    match rendition {
+                        Some(rendition) => {
+                            let rendition = self.fetch_rendition(rendition).await?;
+                            status!("  ✓ 字幕 {}", rendition.label);
+                            subtitles = Some(rendition);
+                        }
+                        None => status!("  ⚠ 所选变体没有可下载的字幕，已忽略 --subs"),
+                    }
+                }
+
+                let media_pl = match variant_pl {
+                    Some(media_pl) => media_pl,
+                    None => self.fetch_media_playlist(&variant_url).await?,
+                };
+                let resolution = best_variant.resolution.as_ref().map(|res| format!("{}x{}", res.width, res.height));
+                (variant_url, media_pl, best_variant.codecs.clone(), resolution)
+            }
+            Playlist::MediaPlaylist(pl) => (self.url.clone(), pl, None, None),
+        };
+        let segments = self.collect_segments(&media_pl, &playlist_url)?;
+
+        let live = if media_pl.end_list {
+            None
+        } else {
+            Some(LivePlaylist {
+                playlist_url: playlist_url.clone(),
+                target_duration: media_pl.target_duration as f64,
+                part_target: part_target(&media_pl),
+                next_sequence: media_pl.media_sequence + media_pl.segments.len() as u64,
+                next_part: 0,
+            })
+        };
+
+        let total_duration: f64 = segments.iter().map(|s| s.duration).sum();
+        status!(
+            "  ✓ 找到 {} 个视频片段 (时长 {})",
+            segments.len(),
+            format_duration(Duration::from_secs_f64(total_duration))
+        );
+
+        // 广告插入等拼接内容以 discontinuity 分隔，每段相当于一个独立的时段（period）
+        let period_starts: Vec<usize> = std::iter::once(0)
+            .chain(
+                segments.iter()
+                    .enumerate()
+                    .skip(1)
+                    .filter(|(_, seg)| seg.discontinuity)
+                    .map(|(i, _)| i),
+            )
+            .collect();
+        if period_starts.len() > 1 {
+            let boundaries: Vec<String> = period_starts[1..].iter().map(|i| format!("#{}", i)).collect();
+            status!(
+                "  ⚠ 播放列表包含 {} 个时段（在片段 {} 处切换），合并后时间戳可能不连续",
+                period_starts.len(),
+                boundaries.join(", ")
+            );
+        }
+        status!();
+
+        // 媒体播放列表中的 EXT-X-START 优先于主播放列表
+        let start_offset = media_pl.start.as_ref().map(|start| start.time_offset).or(start_offset);
+        Ok(StreamInfo { segments, playlist_url, codecs, resolution, live, audio, subtitles, start_offset })
+    }
+
+    /// 同一清晰度常有普通和低延迟两套播放列表，主播放列表里看不出区别，
+    /// 只能取媒体播放列表看有没有 #EXT-X-PART-INF。都不是低延迟时仍用 selected
+    async fn prefer_low_latency_variant<'a>(
+        &self,
+        variants: &'a [m3u8_rs::VariantStream],
+        selected: &'a m3u8_rs::VariantStream,
+    ) -> Result<(&'a m3u8_rs::VariantStream, m3u8_rs::MediaPlaylist)> {
+        let selected_pl = self.fetch_media_playlist(&self.resolve_url(&selected.uri)?).await?;
+        if part_target(&selected_pl).is_some() {
+            return Ok((selected, selected_pl));
+        }
+        let mut alternatives: Vec<&m3u8_rs::VariantStream> = variants.iter()
+            .filter(|v| !v.is_i_frame && v.uri != selected.uri && v.resolution == selected.resolution)
+            .collect();
+        alternatives.sort_by_key(|v| std::cmp::Reverse(v.bandwidth));
+        for variant in alternatives {
+            let Ok(media_pl) = self.fetch_media_playlist(&self.resolve_url(&variant.uri)?).await else { continue };
+            if part_target(&media_pl).is_some() {
+                status!("  ⚡ 改用同一清晰度的低延迟变体");
+                return Ok((variant, media_pl));
+            }
+        }
+        Ok((selected, selected_pl))
+    }
+
+    async fn fetch_media_playlist(&self, url: &str) -> Result<m3u8_rs::MediaPlaylist> {
+        let content = self.fetch_playlist_text(url).await?;
+        parse_media_playlist(&content)
+    }
+
+    /// 片段 URI 相对于媒体播放列表自身的地址解析，而不是主播放列表。
+    /// m3u8-rs 只把 EXT-X-MAP 挂在紧随其后的片段上，这里向后延续到下一个 EXT-X-MAP；
+    /// 省略了 offset 的 EXT-X-BYTERANGE 紧接在同一资源的上一段之后
+    fn collect_segments(&self, playlist: &m3u8_rs::MediaPlaylist, playlist_url: &str) -> Result<Vec<Segment>> {
+        check_encryption(playlist)?;
+        let mut init = None;
+        let mut previous_end: Option<(String, u64)> = None;
+        playlist.segments.iter()
+            .map(|seg| {
+                if let Some(map) = &seg.map {
+                    init = Some(InitSegment {
+                        url: resolve_against(playlist_url, &map.uri, self.inherit_query)?,
+                        byte_range: map.byte_range.as_ref().map(|range| ByteRange {
+                            offset: range.offset.unwrap_or(0),
+                            length: range.length,
+                        }),
+                    });
+                }
+                let url = resolve_against(playlist_url, &seg.uri, self.inherit_query)?;
+                let byte_range = seg.byte_range.as_ref().map(|range| {
+                    let offset = range.offset.unwrap_or(match &previous_end {
+                        Some((previous_url, end)) if *previous_url == url => *end,
+                        _ => 0,
+                    });
+                    ByteRange { offset, length: range.length }
+                });
+                previous_end = byte_range.map(|range| (url.clone(), range.offset + range.length));
+                Ok(Segment {
+                    url,
+                    duration: seg.duration as f64,
+                    discontinuity: seg.discontinuity,
+                    byte_range,
+                    init: init.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn resolve_url(&self, uri: &str) -> Result<String> {
+        resolve_against(&self.url, uri, self.inherit_query)
+    }
+
+    /// 每个片段任务各持有一份 Arc<Self>，不借用调用方的 downloader
+    async fn download_segments(
+        self: &Arc<Self>,
+        segments: Vec<Segment>,
+        first_index: usize,
+        stats: Arc<Mutex<DownloadStats>>,
+        cancel: CancellationToken,
+        merger: Option<&Mutex<StreamMerger>>,
+    ) -> Result<()> {
+        if let Some(files) = &self.temp_files {
+            fs::create_dir_all(&self.temp_dir).await?;
+            files.register_segments(first_index, &segments);
+        } else if merger.is_some() {
+            // 边下载边合并从临时文件读取片段
+            self.temp_files()?;
+        }
+
+        let probing = self.detect_connection_limit || self.adaptive_concurrency;
+        // 只在开始下载时逐步加大并发，直播每轮刷新出的少量片段直接按上限下载
+        let ramp_up = !probing && first_index == 0 && !self.ramp_up.is_zero();
+        // 直播后续几轮沿用用户在界面中选定的并发数
+        let target = stats.lock().await.concurrency_target;
+        let initial_permits = if let Some(target) = target {
+            target
+        } else if probing {
+            CONN_PROBE_START.min(self.concurrent_limit)
+        } else if ramp_up {
+            1
+        } else {
+            self.concurrent_limit
+        };
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(initial_permits));
+        {
+            let mut stats = stats.lock().await;
+            stats.concurrency = initial_permits;
+            stats.max_concurrency = self.max_concurrency;
+        }
+
+        let resumed = stats.lock().await.resumed_indices.clone();
+        let tasks = stream::iter(segments.into_iter().enumerate().map(|(i, segment)| (first_index + i, segment)))
+            .map(|(i, segment)| {
+                let downloader = Arc::clone(self);
+                let stats = Arc::clone(&stats);
+                let semaphore = Arc::clone(&semaphore);
+                let cancel = cancel.clone();
+                let resumed = &resumed;
+
+                async move {
+                    // --resume 时已存在的片段不再下载，--stream-merge 仍要按顺序送进管道
+                    if resumed.contains(&i) {
+                        if let Some(merger) = merger {
+                            merger.lock().await.complete(i, downloader.segment_path(i)).await;
+                        }
+                        return;
+                    }
+                    // 暂停时在拿 permit 之前等待，暂停期间不占用 permit，--detect-conn-limit 照常增减 permit；
+                    // 排队时恰好暂停的任务归还 permit 重新等待。已在下载的片段不受影响
+                    let _permit = loop {
+                        while stats.lock().await.paused {
+                            tokio::select! {
+                                _ = cancel.cancelled() => return,
+                                _ = tokio::time::sleep(Duration::from_millis(200)) => {}
+                            }
+                        }
+                        let permit = tokio::select! {
+                            _ = cancel.cancelled() => return,
+                            permit = semaphore.acquire() => permit.unwrap(),
+                        };
+                        if !stats.lock().await.paused {
+                            break permit;
+                        }
+                    };
+                    let segment_name = downloader.segment_file_name(i);
+                    stats.lock().await.start(i);
+                    downloader.emit(ProgressEvent::SegmentStarted { index: i });
+                    let started = Instant::now();
+
+                    // 取消时直接丢弃下载 future，立即中断 HTTP 请求并释放 permit
+                    let result = tokio::select! {
+                        _ = cancel.cancelled() => {
+                            stats.lock().await.cancel(i);
+                            return;
+                        }
+                        result = downloader.download_segment_with_retries(i, &segment, &stats) => result,
+                    };
+
+                    let completed = result.is_ok();
+                    match result {
+                        Ok(bytes) => {
+                            let mut stats = stats.lock().await;
+                            stats.update(i, bytes, segment.duration, started.elapsed(), segment_name);
+                            downloader.emit(ProgressEvent::SegmentCompleted { index: i, bytes });
+                        }
+                        Err(e) => {
+                            let mut stats = stats.lock().await;
+                            stats.fail(i, segment.duration, with_failure_reason(segment_name, &e));
+                            if stats.failure_limit_exceeded {
+                                cancel.cancel();
+                            }
+                            downloader.emit(ProgressEvent::SegmentFailed { index: i });
+                        }
+                    }
+                    if let Some(merger) = merger {
+                        merger.lock().await.complete(i, downloader.segment_path(i).filter(|_| completed)).await;
+                    }
+                }
+            });
+
+        let run = async {
+            match self.sequential_window {
+                // buffered 按顺序产出结果，队首完成前不会启动窗口之外的片段
+                Some(window) => tasks.buffered(window.max(1)).for_each(|_| async {}).await,
+                // 按能调到的并发上限逐个创建任务，上千个片段时不会一次性生成全部 future；实际并发由 permit 控制
+                None => tasks.for_each_concurrent(self.max_concurrency.max(1), |task| task).await,
+            }
+        };
+        let automatic = async {
+            if self.adaptive_concurrency {
+                self.tune_concurrency(&semaphore, &stats).await;
+            } else if self.detect_connection_limit {
+                self.probe_connection_limit(&semaphore, &stats).await;
+            } else if ramp_up {
+                self.ramp_up_concurrency(&semaphore, &stats).await;
+            }
+            std::future::pending::<()>().await
+        };
+        // 用户第一次按 +/- 后自动调整停止，之后按选定的值增减 permit
+        let probe = async {
+            tokio::select! {
+                _ = automatic => {}
+                _ = self.wait_for_concurrency_target(&stats) => {}
+            }
+            self.follow_concurrency_target(&semaphore, &stats).await
+        };
+        let sample = async {
+            let mut interval = tokio::time::interval(SPEED_SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                stats.lock().await.sample_speed();
+            }
+        };
+        tokio::select! {
+            _ = run => {}
+            _ = probe => {}
+            _ = sample => {}
+        }
+
+        Ok(())
+    }
+
+    /// 下载当前片段，同时按固定间隔（目标时长，--low-latency 时为 PART-TARGET）刷新直播播放列表，
+    /// 直到出现 EXT-X-ENDLIST 或用户按 q。刷新不等下载完成：上一批还在下载时新片段先排队，
+    /// 下一批一次下载排队的全部片段。返回包含新片段在内的全部片段
+    async fn record_live(
+        self: &Arc<Self>,
+        mut segments: Vec<Segment>,
+        mut live: LivePlaylist,
+        stats: Arc<Mutex<DownloadStats>>,
+        cancel: CancellationToken,
+        merger: Option<&Mutex<StreamMerger>>,
+    ) -> Result<Vec<Segment>> {
+        let period = match live.part_target.filter(|_| self.low_latency) {
+            Some(target) => Duration::from_secs_f64(target.max(0.1)),
+            None => Duration::from_secs_f64(live.target_duration.max(1.0)),
+        };
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        let download = futures::future::Fuse::terminated();
+        tokio::pin!(download);
+        download.set(
+            self.download_segments(segments.clone(), 0, Arc::clone(&stats), cancel.clone(), merger).fuse(),
+        );
+        // segments 末尾排队等下一批下载的片段数
+        let mut queued = 0;
+        let mut refreshing = true;
+        loop {
+            tokio::select! {
+                result = &mut download, if !download.is_terminated() => result?,
+                _ = cancel.cancelled(), if refreshing => refreshing = false,
+                _ = ticker.tick(), if refreshing => {
+                    if stats.lock().await.stop_requested {
+                        refreshing = false;
+                    } else {
+                        match self.refresh_live(&mut live, &stats).await {
+                            Ok((new_segments, ended)) => {
+                                queued += new_segments.len();
+                                segments.extend(new_segments);
+                                refreshing = !ended;
+                            }
+                            // 刷新失败多半是暂时的，下一轮再试
+                            Err(e) => stats.lock().await.log_activity(
+                                format!("刷新播放列表失败: {:#}", e),
+                                ActivityStatus::Failed,
+                            ),
+                        }
+                    }
+                }
+            }
+            if download.is_terminated() && queued > 0 {
+                let first_index = segments.len() - queued;
+                let batch = segments[first_index..].to_vec();
+                download.set(
+                    self.download_segments(batch, first_index, Arc::clone(&stats), cancel.clone(), merger).fuse(),
+                );
+                queued = 0;
+            }
+            if !refreshing && download.is_terminated() {
+                break;
+            }
+        }
+
+        stats.lock().await.live = false;
+        Ok(segments)
+    }
+
+    /// 刷新一次直播播放列表，返回新出现的片段（--low-latency 时含部分片段）以及是否已有 EXT-X-ENDLIST
+    async fn refresh_live(&self, live: &mut LivePlaylist, stats: &Mutex<DownloadStats>) -> Result<(Vec<Segment>, bool)> {
+        let content = self.fetch_playlist_text(&live.playlist_url).await?;
+        let playlist = parse_media_playlist(&content)?;
+        let segments = self.collect_segments(&playlist, &live.playlist_url)?;
+        let parts = if self.low_latency {
+            let init = segments.last().and_then(|segment| segment.init.as_ref());
+            self.collect_parts(partial_segments(&content, playlist.media_sequence), init, &live.playlist_url)?
+        } else {
+            Vec::new()
+        };
+
+        // 窗口开头越过了还没下载的片段，下载跟不上 DVR 窗口，这些片段多半已经 404
+        if playlist.media_sequence > live.next_sequence {
+            stats.lock().await.lose_segments((playlist.media_sequence - live.next_sequence) as usize);
+        }
+        let new_segments = live.take_new(playlist.media_sequence, segments, &parts);
+        if !new_segments.is_empty() {
+            let duration = new_segments.iter().map(|s| s.duration).sum();
+            stats.lock().await.add_segments(new_segments.len(), duration);
+        }
+        Ok((new_segments, playlist.end_list))
+    }
+
+    /// 部分片段按播放列表地址解析，与父片段共用初始化片段。GAP 部分片段为 None
+    fn collect_parts(
+        &self,
+        parts: Vec<PartialSegment>,
+        init: Option<&InitSegment>,
+        playlist_url: &str,
+    ) -> Result<Vec<(u64, Option<Segment>)>> {
+        parts.into_iter()
+            .map(|part| {
+                let segment = if part.gap {
+                    None
+                } else {
+                    Some(Segment {
+                        url: resolve_against(playlist_url, &part.uri, self.inherit_query)?,
+                        duration: part.duration,
+                        discontinuity: part.discontinuity,
+                        byte_range: part.byte_range,
+                        init: init.cloned(),
+                    })
+                };
+                Ok((part.sequence, segment))
+            })
+            .collect()
+    }
+
+    /// 在 ramp_up 时间内把 permit 从 1 个均匀加到 concurrent_limit
+    async fn ramp_up_concurrency(&self, semaphore: &tokio::sync::Semaphore, stats: &Mutex<DownloadStats>) {
+        let steps = self.concurrent_limit.saturating_sub(1);
+        if steps == 0 {
+            return;
+        }
+        let interval = self.ramp_up / steps as u32;
+        for _ in 0..steps {
+            tokio::time::sleep(interval).await;
+            semaphore.add_permits(1);
+            stats.lock().await.concurrency += 1;
+        }
+    }
+
+    async fn wait_for_concurrency_target(&self, stats: &Mutex<DownloadStats>) {
+        while stats.lock().await.concurrency_target.is_none() {
+            tokio::time::sleep(CONCURRENCY_KEY_POLL).await;
+        }
+    }
+
+    /// 把 permit 数调整到用户在界面中选定的并发数。减少时等正在下载的片段归还 permit 后丢弃
+    async fn follow_concurrency_target(&self, semaphore: &tokio::sync::Semaphore, stats: &Mutex<DownloadStats>) -> ! {
+        loop {
+            let change = {
+                let mut stats = stats.lock().await;
+                match stats.concurrency_target {
+                    Some(target) if target != stats.concurrency => {
+                        let current = std::mem::replace(&mut stats.concurrency, target);
+                        Some((current, target))
+                    }
+                    _ => None,
+                }
+            };
+            match change {
+                Some((current, target)) if target > current => semaphore.add_permits(target - current),
+                Some((current, target)) => {
+                    if let Ok(permits) = semaphore.acquire_many((current - target) as u32).await {
+                        permits.forget();
+                    }
+                }
+                None => tokio::time::sleep(CONCURRENCY_KEY_POLL).await,
+            }
+        }
+    }
+
+    /// 每隔一段时间把并发数翻倍，直到总吞吐量不再明显提升。
+    /// 服务器限制连接数时多出的连接只会排队，单连接速度按比例下降而总量不变。
+    async fn probe_connection_limit(&self, semaphore: &tokio::sync::Semaphore, stats: &Mutex<DownloadStats>) {
+        let mut level = CONN_PROBE_START.min(self.concurrent_limit);
+        let mut previous_level = level;
+        let mut previous_throughput = 0.0;
+        let mut last_bytes = stats.lock().await.downloaded_bytes;
+
+        while level < self.concurrent_limit {
+            tokio::time::sleep(CONN_PROBE_INTERVAL).await;
+            let (bytes, outstanding) = {
+                let stats = stats.lock().await;
+                (stats.downloaded_bytes, stats.outstanding_segments())
+            };
+            // 剩余片段不足以占满更多连接时，吞吐量下降不能说明问题
+            if outstanding < level * 2 {
+                return;
+            }
+            let throughput = (bytes - last_bytes) as f64;
+            last_bytes = bytes;
+            if throughput == 0.0 {
+                continue;
+            }
+
+            if level > previous_level && throughput < previous_throughput * 1.1 {
+                // 收回上一轮加出的 permit，等它们被释放后直接丢弃
+                let extra = (level - previous_level) as u32;
+                if let Ok(permits) = semaphore.acquire_many(extra).await {
+                    permits.forget();
+                }
+                let mut stats = stats.lock().await;
+                stats.connection_limit = Some(previous_level);
+                stats.concurrency = previous_level;
+                return;
+            }
+
+            let next = (level * 2).min(self.concurrent_limit);
+            semaphore.add_permits(next - level);
+            stats.lock().await.concurrency = next;
+            previous_level = level;
+            previous_throughput = throughput;
+            level = next;
+        }
+    }
+
+    /// `--concurrent auto`：定期比较最近的下载速度，速度还在上升就加大并发，
+    /// 持平时退回上一档并观察几轮，重试明显增多（多半被 CDN 限流）时减半
+    async fn tune_concurrency(&self, semaphore: &tokio::sync::Semaphore, stats: &Mutex<DownloadStats>) {
+        let mut level = CONN_PROBE_START.min(self.concurrent_limit);
+        let mut previous_level = level;
+        let mut previous_speed = 0.0;
+        let mut last_errors = 0;
+        let mut hold = 0;
+        stats.lock().await.concurrency_level = Some(level);
+
+        loop {
+            tokio::time::sleep(CONN_PROBE_INTERVAL).await;
+            let (speed, errors, outstanding) = {
+                let stats = stats.lock().await;
+                let recent: Vec<f64> = stats.speed_history.iter().rev().take(8).cloned().collect();
+                let speed = recent.iter().sum::<f64>() / recent.len().max(1) as f64;
+                (speed, stats.retried_requests + stats.failed_segments, stats.outstanding_segments())
+            };
+            let new_errors = errors - last_errors;
+            last_errors = errors;
+
+            let next = if new_errors * 2 > level {
+                hold = AUTO_CONCURRENCY_HOLD;
+                (level / 2).max(1)
+            } else if speed == 0.0 || outstanding <= level {
+                // 还没有速度数据，或剩余片段已占不满当前的连接数
+                level
+            } else if level > previous_level && speed < previous_speed * 1.1 {
+                hold = AUTO_CONCURRENCY_HOLD;
+                previous_level
+            } else if hold > 0 {
+                hold -= 1;
+                level
+            } else {
+                (level + (level / 2).max(1)).min(self.concurrent_limit)
+            };
+
+            if next > level {
+                semaphore.add_permits(next - level);
+            } else if next < level {
+                // 多出的 permit 等正在下载的片段归还后直接丢弃
+                if let Ok(permits) = semaphore.acquire_many((level - next) as u32).await {
+                    permits.forget();
+                }
+            }
+            previous_level = level;
+            previous_speed = speed;
+            level = next;
+            let mut stats = stats.lock().await;
+            stats.concurrency_level = Some(level);
+            stats.concurrency = level;
+        }
+    }
+
+    /// 并发检查每个片段的可访问性，返回不可访问的片段序号及原因
+    async fn preflight_check(&self, segments: &[Segment]) -> Vec<(usize, String)> {
+        let mut broken: Vec<(usize, String)> = stream::iter(segments.iter().enumerate())
+            .map(|(i, segment)| async move {
+                if segment.url.starts_with("data:") {
+                    return None;
+                }
+                self.probe_segment(&segment.url).await.err().map(|e| (i, e.to_string()))
+            })
+            .buffer_unordered(self.concurrent_limit.max(1))
+            .filter_map(|result| async move { result })
+            .collect()
+            .await;
+        broken.sort_by_key(|(i, _)| *i);
+        broken
+    }
+
+    async fn probe_segment(&self, url: &str) -> Result<()> {
+        let response = self.with_default_headers(self.client.head(url), url).send().await?;
+        let mut status = response.status();
+        // 部分 CDN 不支持 HEAD，退回只取一个字节的 GET
+        if status == http::StatusCode::METHOD_NOT_ALLOWED || status == http::StatusCode::NOT_IMPLEMENTED {
+            status = self.build_request(url)
+                .header("Range", "bytes=0-0")
+                .send()
+                .await?
+                .status();
+        }
+        if !status.is_success() {
+            anyhow::bail!("HTTP {}", status);
+        }
+        Ok(())
+    }
+
+    /// 重新获取已选中的媒体播放列表，用新的片段地址重试失败的片段（适用于签名 URL 过期），返回成功数量。
+    /// 不重新选择清晰度
+    async fn retry_failed_with_fresh_urls(&self, playlist_url: &str, stats: &Mutex<DownloadStats>) -> usize {
+        let (failed, expected_count) = {
+            let stats = stats.lock().await;
+            (stats.failed_indices.clone(), stats.total_segments)
+        };
+        if failed.is_empty() {
+            return 0;
+        }
+
+        status!("🔄 正在重新获取播放列表以刷新 {} 个失败片段的地址...", failed.len());
+        // 刷新只是额外的补救，失败时照常进入后面的失败处理，不丢弃已下载的片段
+        let fresh = self
+            .fetch_media_playlist(playlist_url)
+            .await
+            .and_then(|playlist| self.collect_segments(&playlist, playlist_url));
+        let fresh = match fresh {
+            Ok(fresh) if fresh.len() == expected_count => fresh,
+            Ok(fresh) => {
+                status!(
+                    "  ⚠ 刷新后的播放列表有 {} 个片段，原来为 {} 个，无法按序号对应失败片段，跳过刷新",
+                    fresh.len(),
+                    expected_count
+                );
+                return 0;
+            }
+            Err(e) => {
+                status!("  ⚠ 无法重新获取播放列表，跳过刷新: {:#}", e);
+                return 0;
+            }
+        };
+
+        self.retry_segments(&failed, &fresh, stats).await
+    }
+
+    /// 用原有地址重新下载当前所有失败的片段，返回成功数量
+    async fn retry_failed(&self, segments: &[Segment], stats: &Mutex<DownloadStats>) -> usize {
+        let failed = stats.lock().await.failed_indices.clone();
+        status!("🔄 正在重试 {} 个失败的片段...", failed.len());
+        self.retry_segments(&failed, segments, stats).await
+    }
+
+    async fn retry_segments(&self, failed: &[usize], segments: &[Segment], stats: &Mutex<DownloadStats>) -> usize {
+        let mut recovered = 0;
+        for &i in failed {
+            let segment = &segments[i];
+            let started = Instant::now();
+            self.emit(ProgressEvent::SegmentStarted { index: i });
+            match self.download_segment(i, segment).await {
+                Ok(bytes) => {
+                    stats.lock().await.recover(i, bytes, segment.duration, started.elapsed());
+                    self.emit(ProgressEvent::SegmentCompleted { index: i, bytes });
+                    recovered += 1;
+                }
+                Err(e) => {
+                    status!("  ✗ {} 仍然失败: {}", self.segment_file_name(i), e);
+                    self.emit(ProgressEvent::SegmentFailed { index: i });
+                }
+            }
+        }
+        status!("  ✓ 已恢复 {} 个片段", recovered);
+        recovered
+    }
+
+    /// 扫描临时目录中已存在的非空片段文件，记为已完成（--resume），返回片段数和字节数
+    async fn resume_existing(&self, segments: &[Segment], stats: &Mutex<DownloadStats>) -> Result<(usize, u64)> {
+        let files = self.temp_files()?;
+        let mut stats = stats.lock().await;
+        let mut count = 0;
+        let mut bytes = 0;
+        for (i, segment) in segments.iter().enumerate() {
+            let Ok(meta) = fs::metadata(files.path(i)).await else { continue };
+            if meta.len() > 0 {
+                stats.resume(i, meta.len(), segment.duration);
+                count += 1;
+                bytes += meta.len();
+            }
+        }
+        Ok((count, bytes))
+    }
+
+    /// 不下载内容，按抽样片段的平均大小推算总大小；抽样片段都无法获取大小时返回 None
+    async fn estimate_size_by_probe(&self, segments: &[Segment]) -> Option<u64> {
+        let sizes: Vec<u64> = stream::iter(sample_indices(segments.len(), DRY_RUN_SAMPLES))
+            .map(|i| self.segment_size(&segments[i]))
+            .buffer_unordered(DRY_RUN_SAMPLES)
+            .filter_map(|size| async move { size.ok().flatten() })
+            .collect()
+            .await;
+        if sizes.is_empty() {
+            return None;
+        }
+        let average = sizes.iter().sum::<u64>() / sizes.len() as u64;
+        Some(average * segments.len() as u64)
+    }
+
+    /// 片段的大小：BYTERANGE 直接取长度，否则读 HEAD 的 Content-Length，
+    /// HEAD 不可用或不带长度时退回只取一个字节的 GET，从 Content-Range 读总大小
+    async fn segment_size(&self, segment: &Segment) -> Result<Option<u64>> {
+        if let Some(range) = segment.byte_range {
+            return Ok(Some(range.length));
+        }
+        if segment.url.starts_with("data:") {
+            return Ok(None);
+        }
+        let header_u64 = |headers: &http::HeaderMap, name| -> Option<u64> {
+            headers.get(name)?.to_str().ok()?.trim().parse().ok()
+        };
+
+        let response = self.with_default_headers(self.client.head(&segment.url), &segment.url).send().await?;
+        if response.status().is_success() {
+            if let Some(size) = header_u64(response.headers(), http::header::CONTENT_LENGTH) {
+                return Ok(Some(size));
+            }
+        }
+        let response = self.build_request(&segment.url).header("Range", "bytes=0-0").send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("HTTP {}", status);
+        }
+        if status == http::StatusCode::PARTIAL_CONTENT {
+            // Content-Range: bytes 0-0/12345，总大小未知时为 *
+            let total = response.headers()
+                .get(http::header::CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit_once('/'))
+                .and_then(|(_, total)| total.trim().parse().ok());
+            return Ok(total);
+        }
+        // 服务器忽略了 Range，返回的是整个片段
+        Ok(header_u64(response.headers(), http::header::CONTENT_LENGTH))
+    }
+
+    /// 下载第一个片段，按片段数推算总大小；第一个片段保留在临时目录中，之后按已存在跳过
+    async fn estimate_total_size(&self, segments: &[Segment], resume: bool) -> Result<(u64, u64)> {
+        let first = segments.first().context("Playlist has no segments")?;
+        let path = self.temp_files()?.path(0);
+        let existing = match fs::metadata(&path).await {
+            Ok(meta) if resume && meta.len() > 0 => Some(meta.len()),
+            _ => None,
+        };
+        let first_size = match existing {
+            Some(size) => size,
+            None => {
+                fs::create_dir_all(&self.temp_dir).await?;
+                self.download_segment(0, first).await?
+            }
+        };
+        Ok((first_size, first_size * segments.len() as u64))
+    }
+
+    fn register_segments(&self, first_index: usize, segments: &[Segment]) {
+        if let Some(files) = &self.temp_files {
+            files.register_segments(first_index, segments);
+        }
+    }
+
+    /// 默认 sink 写下的片段文件，合并、--resume 和片段校验都依赖这些文件
+    fn temp_files(&self) -> Result<&TempFileSink> {
+        self.temp_files
+            .as_deref()
+            .context("Segments go to a custom sink; merging, resuming and validating need the default temp-file sink")
+    }
+
+    /// 片段在进度、报告中显示的名字，与临时文件同名
+    fn segment_file_name(&self, index: usize) -> String {
+        match &self.temp_files {
+            Some(files) => files.file_name(index),
+            None => format!("segment_{:05}", index),
+        }
+    }
+
+    fn segment_path(&self, index: usize) -> Option<PathBuf> {
+        self.temp_files.as_ref().map(|files| files.path(index))
+    }
+
+    /// 找出明显小于中位数的片段（可能被截断）并重新下载，返回重新下载的数量。
+    /// 重新下载失败时删除截断的文件并记为失败片段，不会被当作完整的片段合并
+    async fn redownload_small_segments(
+        &self,
+        segments: &[Segment],
+        ratio: f64,
+        stats: &Mutex<DownloadStats>,
+    ) -> Result<usize> {
+        let files = self.temp_files()?;
+        let mut sizes = Vec::new();
+        for i in 0..segments.len() {
+            if let Ok(meta) = fs::metadata(files.path(i)).await {
+                sizes.push((i, meta.len()));
+            }
+        }
+        if sizes.is_empty() {
+            return Ok(0);
+        }
+
+        let mut sorted: Vec<u64> = sizes.iter().map(|&(_, len)| len).collect();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2];
+        let threshold = (median as f64 * ratio) as u64;
+
+        let suspicious: Vec<usize> = sizes.iter()
+            .filter(|&&(_, len)| len < threshold)
+            .map(|&(i, _)| i)
+            .collect();
+        if suspicious.is_empty() {
+            return Ok(0);
+        }
+
+        status!(
+            "⚠ {} 个片段小于中位数 {:.2} MB 的 {:.0}%，正在重新下载...",
+            suspicious.len(),
+            median as f64 / (1024.0 * 1024.0),
+            ratio * 100.0
+        );
+
+        for &i in &suspicious {
+            match self.download_segment(i, &segments[i]).await {
+                Ok(len) if len < threshold => {
+                    status!("  ⚠ {} 重新下载后仍只有 {} 字节", self.segment_file_name(i), len);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let name = self.segment_file_name(i);
+                    status!("  ✗ {} 重新下载失败，记为失败片段: {}", name, e);
+                    let _ = fs::remove_file(files.path(i)).await;
+                    stats.lock().await.invalidate(i, segments[i].duration, with_failure_reason(name, &e));
+                }
+            }
+        }
+
+        Ok(suspicious.len())
+    }
+
+    /// 失败后按指数退避加随机抖动重试，所有尝试用尽才返回错误
+    async fn download_segment_with_retries(
+        &self,
+        index: usize,
+        segment: &Segment,
+        stats: &Mutex<DownloadStats>,
+    ) -> Result<u64> {
+        let mut attempt = 0;
+        loop {
+            match self.download_segment(index, segment).await {
+                Ok(bytes) => return Ok(bytes),
+                Err(e) if attempt >= self.retries => return Err(e),
+                Err(e) => {
+                    attempt += 1;
+                    let name = format!("{} #{}", self.segment_file_name(index), attempt);
+                    stats.lock().await.retry(with_failure_reason(name, &e));
+                    tokio::time::sleep(self.retry_backoff(attempt)).await;
+                }
+            }
+        }
+    }
+
+    /// 下载 EXT-X-MAP 初始化片段，同一地址和范围只请求一次，并发的片段等待同一个请求。
+    /// 默认 sink 时在临时目录中写一份，合并时列在使用它的片段前面
+    async fn init_segment(&self, init: &InitSegment) -> Result<Arc<Vec<u8>>> {
+        let mut cache = self.init_segments.lock().await;
+        if let Some(bytes) = cache.get(init) {
+            return Ok(Arc::clone(bytes));
+        }
+        let bytes = if init.url.starts_with("data:") {
+            let bytes = decode_data_url(&init.url)?;
+            match init.byte_range {
+                Some(range) => range.slice(&bytes)?.to_vec(),
+                None => bytes,
+            }
+        } else {
+            let mut request = self.build_request(&init.url);
+            if let Some(range) = init.byte_range {
+                request = request.header(http::header::RANGE, range.header());
+            }
+            let response = request.send().await?;
+            let status = response.status();
+            if !status.is_success() {
+                anyhow::bail!("Init segment {} returned HTTP {}", init.url, status);
+            }
+            check_content_encoding(response.headers())?;
+            let bytes = response.bytes().await?;
+            self.count_transfer(bytes.len());
+            match init.byte_range {
+                Some(range) if status != http::StatusCode::PARTIAL_CONTENT => range.slice(&bytes)?.to_vec(),
+                _ => bytes.to_vec(),
+            }
+        };
+        if let Some(files) = &self.temp_files {
+            files.write_init(init, &bytes).await?;
+        }
+        let bytes = Arc::new(bytes);
+        cache.insert(init.clone(), Arc::clone(&bytes));
+        Ok(bytes)
+    }
+
+    /// 在下载片段前取回所有初始化片段，返回数量；地址有误时在这里就报错，而不是每个片段都失败
+    async fn prefetch_init_segments(&self, segments: &[Segment]) -> Result<usize> {
+        let inits: HashSet<&InitSegment> = segments.iter().filter_map(|s| s.init.as_ref()).collect();
+        for init in &inits {
+            self.init_segment(init).await?;
+        }
+        Ok(inits.len())
+    }
+
+    /// 取回第 index 个片段交给 sink，返回片段本身的字节数。初始化片段在临时目录中单独成一个文件；
+    /// 自定义 sink 收不到单独的初始化片段，仍然写在每个片段开头
+    async fn download_segment(&self, index: usize, segment: &Segment) -> Result<u64> {
+        let init = match &segment.init {
+            Some(init) => Some(self.init_segment(init).await?),
+            None => None,
+        };
+        let body = if segment.url.starts_with("data:") {
+            read_data_url(segment)?
+        } else {
+            self.fetch_segment(index, segment).await?
+        };
+        let len = body.len() as u64;
+        let bytes = match init.filter(|_| self.temp_files.is_none()) {
+            Some(init) => {
+                let mut bytes = Vec::with_capacity(init.len() + body.len());
+                bytes.extend_from_slice(&init);
+                bytes.extend_from_slice(&body);
+                Bytes::from(bytes)
+            }
+            None => body,
+        };
+        self.sink.write(index, bytes).await?;
+        Ok(len)
+    }
+
+    /// 边接收边计入传输量并按 --limit-rate 限速，收完整个响应体才交给 sink
+    async fn fetch_segment(&self, index: usize, segment: &Segment) -> Result<Bytes> {
+        let url = segment.url.as_str();
+        let mut request = self.with_default_headers(self.client.request(self.segment_method.clone(), url), url);
+        if let Some(template) = &self.segment_body {
+            let body = template
+                .replace("{index}", &index.to_string())
+                .replace("{url}", url);
+            request = request.body(body);
+        }
+        if let Some(range) = segment.byte_range {
+            request = request.header(http::header::RANGE, range.header());
+        }
+        let mut response = request
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(HttpStatusError { url: url.to_string(), status }.into());
+        }
+        let partial = status == http::StatusCode::PARTIAL_CONTENT;
+        check_content_encoding(response.headers())?;
+        let content_type = response
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        // 服务器忽略 Range 返回了整个文件，只能读完再截取
+        if let Some(range) = segment.byte_range.filter(|_| !partial) {
+            let body = response.bytes().await?;
+            self.count_transfer(body.len());
+            let bytes = range.slice(&body)?;
+            check_media_content(content_type.as_deref(), bytes)?;
+            if let Some(limiter) = &self.rate_limiter {
+                limiter.acquire(bytes.len() as u64).await;
+            }
+            return Ok(Bytes::copy_from_slice(bytes));
+        }
+
+        let mut body = Vec::new();
+        let mut checked = false;
+        while let Some(chunk) = response.chunk().await? {
+            self.count_transfer(chunk.len());
+            // 每块都等待令牌，速度图在大片段中间也停在限速值附近
+            if let Some(limiter) = &self.rate_limiter {
+                limiter.acquire(chunk.len() as u64).await;
+            }
+            body.extend_from_slice(&chunk);
+            // 攒够 MEDIA_SNIFF_LEN 字节就检查一次，返回错误页时不必读完整个响应，直接交给重试
+            if !checked && body.len() >= MEDIA_SNIFF_LEN {
+                check_media_content(content_type.as_deref(), &body)?;
+                checked = true;
+            }
+        }
+        if !checked {
+            check_media_content(content_type.as_deref(), &body)?;
+        }
+        Ok(Bytes::from(body))
+    }
+
+    /// 运行 ffmpeg 并收集标准错误，退出码非零时把最后几行放进错误；--verbose 时同时转发到终端
+    async fn run_ffmpeg(&self, command: &mut tokio::process::Command) -> Result<()> {
+        let mut child = command
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| self.ffmpeg_spawn_error(e))?;
+        let stderr = match child.stderr.take() {
+            Some(stderr) => collect_stderr(stderr, self.verbose).await,
+            None => Vec::new(),
+        };
+        let status = child.wait().await?;
+        if !status.success() {
+            return Err(ffmpeg_failure(&stderr));
+        }
+        Ok(())
+    }
+
+    /// 启动 ffmpeg 失败时的错误；找不到可执行文件时先打印安装提示
+    fn ffmpeg_spawn_error(&self, e: std::io::Error) -> anyhow::Error {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            print_ffmpeg_install_hint(&self.ffmpeg_path);
+        }
+        anyhow::Error::new(e).context(format!("Failed to run {}", self.ffmpeg_path.display()))
+    }
+
+    /// 每 MERGE_GROUP_SIZE 个片段一组，最多 merge_jobs 个 ffmpeg 同时把各组拼接成 TS 中间文件，
+    /// 返回按顺序排列的中间文件，最后一遍合并只需拼接这些文件
+    async fn merge_groups(&self, segment_paths: &[PathBuf], retime: bool) -> Result<Vec<PathBuf>> {
+        let groups: Vec<&[PathBuf]> = segment_paths.chunks(MERGE_GROUP_SIZE).collect();
+        status!("  ℹ 分 {} 组并行合并（{} 个 ffmpeg 进程）", groups.len(), self.merge_jobs.min(groups.len()));
+
+        let results: Vec<Result<PathBuf>> = stream::iter(groups.into_iter().enumerate())
+            .map(|(group, files)| async move {
+                let filelist_path = self.merge_temp_dir.join(format!("filelist.{}.txt", group));
+                let part_path = self.merge_temp_dir.join(format!("merge_part_{:04}.ts", group));
+                write_concat_list(&filelist_path, files).await?;
+
+                let mut command = tokio::process::Command::new(&self.ffmpeg_path);
+                command.args(["-f", "concat", "-safe", "0"]);
+                if retime {
+                    command.args(["-fflags", "+genpts"]);
+                }
+                command.args(["-i", &filelist_path.to_string_lossy()]);
+                command
+                    .args(["-c", "copy", "-f", "mpegts", "-y"])
+                    .arg(&part_path)
+                    .stdout(std::process::Stdio::null());
+                self.run_ffmpeg(&mut command)
+                    .await
+                    .with_context(|| format!("Merge group {} failed", group))?;
+                fs::remove_file(&filelist_path).await?;
+                Ok(part_path.canonicalize()?)
+            })
+            .buffered(self.merge_jobs)
+            .collect()
+            .await;
+        results.into_iter().collect()
+    }
+
+    /// 只生成文件列表并打印对应的 ffmpeg 命令，不执行合并也不清理临时文件
+    async fn emit_concat(
+        &self,
+        output_name: &str,
+        segment_count: usize,
+        container: Container,
+        transcode: &TranscodeOptions,
+    ) -> Result<()> {
+        fs::create_dir_all(&self.merge_temp_dir).await?;
+        let filelist_path = self.merge_temp_dir.join("filelist.txt");
+        let output_path = self.output_dir.join(format!("{}.{}", output_name, container.extension()));
+        write_concat_list(&filelist_path, &self.temp_files()?.merge_inputs(0..segment_count).await?).await?;
+
+        let mut command = vec![
+            shell_quote(&self.ffmpeg_path.to_string_lossy()),
+            "-f".to_string(),
+            "concat".to_string(),
+            "-safe".to_string(),
+            "0".to_string(),
+            "-i".to_string(),
+            shell_quote(&filelist_path.to_string_lossy()),
+        ];
+        command.extend(transcode.ffmpeg_args().iter().map(|arg| shell_quote(arg)));
+        command.push(shell_quote(&output_path.to_string_lossy()));
+
+        status!("\n✓ 文件列表: {}", filelist_path.display());
+        status!("  合并命令:\n  {}\n", command.join(" "));
+        Ok(())
+    }
+
+    /// 把片段移到 `<output_dir>/<name>/` 并生成引用本地文件的 index.m3u8，返回播放列表路径
+    async fn export_hls(&self, segments: &[Segment], output_name: &str) -> Result<PathBuf> {
+        let hls_dir = self.output_dir.join(output_name);
+        fs::create_dir_all(&hls_dir).await?;
+
+        let target_duration = segments.iter().map(|s| s.duration).fold(0.0f64, f64::max).ceil() as u64;
+        // 点播播放列表使用 EXT-X-MAP 需要版本 6
+        let version = if segments.iter().any(|s| s.init.is_some()) { 6 } else { 3 };
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n",
+            version, target_duration
+        );
+
+        let files = self.temp_files()?;
+        let mut missing = 0;
+        let mut after_gap = false;
+        let mut current_init = None;
+        for (i, segment) in segments.iter().enumerate() {
+            let source = files.path(i);
+            if !source.exists() {
+                missing += 1;
+                after_gap = true;
+                continue;
+            }
+            let file_name = files.file_name(i);
+            fs::rename(&source, hls_dir.join(&file_name)).await?;
+
+            // 跳过缺失片段后时间戳会跳变，与原有的 discontinuity 一样需要标记
+            if segment.discontinuity || (after_gap && i > missing) {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            after_gap = false;
+            if let Some(id) = files.segment_init(i).filter(|&id| current_init != Some(id)) {
+                let init_name = files.init_file_name(id);
+                let target = hls_dir.join(&init_name);
+                if !target.exists() {
+                    fs::rename(files.dir.join(&init_name), &target).await?;
+                }
+                playlist.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", init_name));
+                current_init = Some(id);
+            }
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", segment.duration, file_name));
+        }
+        playlist.push_str("#EXT-X-ENDLIST\n");
+
+        let playlist_path = hls_dir.join("index.m3u8");
+        fs::write(&playlist_path, playlist).await?;
+        if missing > 0 {
+            status!("⚠ {} 个缺失的片段未写入播放列表", missing);
+        }
+        status!("✓ 本地 HLS: {}\n", playlist_path.display());
+
+        Ok(playlist_path)
+    }
+
+    /// TS 片段本身就可以首尾相接播放，按顺序拼接即可，不需要 ffmpeg
+    async fn concat_ts(&self, output_path: &Path, segment_count: usize, append: bool) -> Result<PathBuf> {
+        if append && output_path.exists() {
+            status!("\n🎬 正在追加视频片段到 {}...", output_path.display());
+        } else {
+            status!("\n🎬 正在拼接视频片段...");
+        }
+
+        // 新建的输出先写到 .part，成功后才改名；追加时记下原来的长度，失败时截回去
+        let to_stdout = is_stdout(&output_path.to_string_lossy());
+        let appending = append && output_path.exists();
+        let target_path = if to_stdout || appending { output_path.to_path_buf() } else { part_path(output_path) };
+        let original_len = if appending { fs::metadata(output_path).await?.len() } else { 0 };
+        let file: Box<dyn AsyncWrite + Unpin + Send> = if to_stdout {
+            Box::new(tokio::io::stdout())
+        } else {
+            Box::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(appending)
+                    .truncate(!appending)
+                    .open(&target_path)
+                    .await?,
+            )
+        };
+        if let Err(e) = self.copy_segments(file, segment_count).await {
+            if appending {
+                if let Ok(file) = fs::OpenOptions::new().write(true).open(output_path).await {
+                    let _ = file.set_len(original_len).await;
+                }
+            } else if !to_stdout {
+                let _ = fs::remove_file(&target_path).await;
+            }
+            return Err(e);
+        }
+        if target_path != output_path {
+            fs::rename(&target_path, output_path).await?;
+        }
+
+        status!("✓ 成功: {}\n", output_path.display());
+        Ok(output_path.to_path_buf())
+    }
+
+    /// 按顺序把已下载的片段写入 file，缺失的片段跳过
+    async fn copy_segments(&self, file: Box<dyn AsyncWrite + Unpin + Send>, segment_count: usize) -> Result<()> {
+        let mut writer = BufWriter::with_capacity(self.write_buffer_size, file);
+        let files = self.temp_files()?;
+        let missing = files.missing_segments(segment_count);
+        for i in (0..segment_count).filter(|i| !missing.contains(i)) {
+            let mut segment = File::open(files.path(i)).await?;
+            tokio::io::copy(&mut segment, &mut writer).await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// `--single-file-temp` 下载完成后的合并：TS 输出直接改名，其它格式交给 ffmpeg 重新封装
+    async fn merge_single_file(
+        &self,
+        input: &Path,
+        output_path: &Path,
+        container: Container,
+        transcode: &TranscodeOptions,
+    ) -> Result<()> {
+        if container == Container::Ts {
+            status!("\n🎬 正在移动视频文件...");
+            if fs::rename(input, output_path).await.is_err() {
+                // 临时目录在其它卷上时退回复制，同样先复制到 .part
+                let target_path = part_path(output_path);
+                if let Err(e) = fs::copy(input, &target_path).await {
+                    let _ = fs::remove_file(&target_path).await;
+                    return Err(e.into());
+                }
+                fs::rename(&target_path, output_path).await?;
+                fs::remove_file(input).await?;
+            }
+        } else {
+            status!("\n🎬 正在合并视频片段...");
+            let target_path = part_path(output_path);
+            let build_command = |transcode: &TranscodeOptions| {
+                let mut command = tokio::process::Command::new(&self.ffmpeg_path);
+                command
+                    .args(["-f", "mpegts", "-i", &input.to_string_lossy()])
+                    .args(transcode.ffmpeg_args())
+                    .args(["-f", container.muxer(), "-y"])
+                    .arg(&target_path)
+                    .stdout(std::process::Stdio::null());
+                command
+            };
+            let mut result = self.run_ffmpeg(&mut build_command(transcode)).await;
+            if result.is_err() && transcode.is_copy() {
+                if transcode.allow_reencode {
+                    status!("  ⚠ -c copy 合并失败，改为重新编码后重试（耗时较长）");
+                    result = self.run_ffmpeg(&mut build_command(&transcode.reencoded())).await;
+                } else {
+                    status!("  ℹ 片段之间编码参数不一致时，可加 --allow-reencode 在 -c copy 失败后自动重新编码");
+                }
+            }
+            if let Err(e) = result {
+                let _ = fs::remove_file(&target_path).await;
+                return Err(e);
+            }
+            fs::rename(&target_path, output_path).await?;
+        }
+        status!("✓ 成功: {}\n", output_path.display());
+        Ok(())
+    }
+
+    /// 独立音轨的片段与视频分别拼接，再与字幕一起用 -map 合在一起
+    async fn merge_to_mp4(
+        &self,
+        output_name: &str,
+        segments: &[Segment],
+        tracks: &ExtraTracks,
+        container: Container,
+        transcode: &TranscodeOptions,
+        append: bool,
+    ) -> Result<PathBuf> {
+        let segment_count = segments.len();
+        fs::create_dir_all(&self.merge_temp_dir).await?;
+        let filelist_path = self.merge_temp_dir.join("filelist.txt");
+        let to_stdout = is_stdout(output_name);
+        let output_path = self.output_dir.join(format!("{}.{}", output_name, container.extension()));
+        // 追加模式本来就要写进已有文件；其余情况在启动 ffmpeg 前处理重名，ffmpeg 的 -y 不会再覆盖已有文件
+        let output_path = if to_stdout {
+            PathBuf::from("-")
+        } else if append {
+            output_path
+        } else {
+            self.on_collision.resolve(&output_path)?
+        };
+
+        if container == Container::Ts {
+            return self.concat_ts(&output_path, segment_count, append).await;
+        }
+
+        // 追加模式下，已有的输出文件作为 concat 列表的第一项，
+        // concat demuxer 会按前一个文件的时长平移后续片段的时间戳
+        let existing_output = if append && output_path.exists() {
+            Some(output_path.canonicalize()?)
+        } else {
+            None
+        };
+
+        // ffmpeg 先写到输出文件旁边的 .part，成功后再改名：中断或失败时不会留下看似完整的输出，
+        // 追加时也不必原地改写输入文件。与输出文件在同一个卷上，--merge-temp-dir 在其它卷时改名也不会失败
+        let target_path = part_path(&output_path);
+
+        if existing_output.is_some() {
+            status!("\n🎬 正在追加视频片段到 {}...", output_path.display());
+        } else if transcode.is_copy() {
+            status!("\n🎬 正在合并视频片段...");
+        } else {
+            status!("\n🎬 正在合并并重新编码视频片段...");
+        }
+
+        // 时间戳在 discontinuity 处跳变或回绕，-c copy 直接拼接会得到倒退的时间戳，
+        // 与追加模式一样让 ffmpeg 重新生成并从零开始
+        let video_end = tracks.audio_start.unwrap_or(segment_count);
+        let discontinuities = count_discontinuities(&segments[..video_end]);
+        if discontinuities > 0 {
+            status!("  ℹ 播放列表有 {} 处 discontinuity，合并时重新生成时间戳", discontinuities);
+        }
+        let retime = existing_output.is_some() || discontinuities > 0;
+
+        let files = self.temp_files()?;
+        let segment_paths = files.merge_inputs(0..video_end).await?;
+        let audio_paths = files.merge_inputs(video_end..segment_count).await?;
+        let parts = if self.merge_jobs > 1 && segment_paths.len() > MERGE_GROUP_SIZE {
+            self.merge_groups(&segment_paths, retime).await?
+        } else {
+            Vec::new()
+        };
+        let mut files: Vec<PathBuf> = existing_output.iter().cloned().collect();
+        files.extend(if parts.is_empty() { segment_paths } else { parts.clone() });
+        write_concat_list(&filelist_path, &files).await?;
+
+        let audio_filelist_path = self.merge_temp_dir.join("filelist_audio.txt");
+        if !audio_paths.is_empty() {
+            write_concat_list(&audio_filelist_path, &audio_paths).await?;
+        }
+
+        let build_command = |transcode: &TranscodeOptions| {
+            let mut command = tokio::process::Command::new(&self.ffmpeg_path);
+            command.args(["-f", "concat", "-safe", "0"]);
+            if retime {
+                command.args(["-fflags", "+genpts"]);
+            }
+            command.args(["-i", &filelist_path.to_string_lossy()]);
+            if !audio_paths.is_empty() {
+                command.args(["-f", "concat", "-safe", "0"]);
+                if retime {
+                    command.args(["-fflags", "+genpts"]);
+                }
+                if tracks.audio_skip > 0.0 {
+                    command.args(["-ss", &format!("{:.3}", tracks.audio_skip)]);
+                }
+                command.args(["-i", &audio_filelist_path.to_string_lossy()]);
+            }
+            if let Some(subtitles) = &tracks.subtitles {
+                command.arg("-i").arg(subtitles);
+            }
+            if !audio_paths.is_empty() || tracks.subtitles.is_some() {
+                let audio_input = if audio_paths.is_empty() { "0:a?" } else { "1:a" };
+                command.args(["-map", "0:v", "-map", audio_input]);
+                if tracks.subtitles.is_some() {
+                    let subtitle_input = if audio_paths.is_empty() { "1:s" } else { "2:s" };
+                    command.args(["-map", subtitle_input]);
+                }
+            }
+            command.args(transcode.ffmpeg_args());
+            if tracks.subtitles.is_some() {
+                command.args(["-c:s", "srt"]);
+            }
+            if retime {
+                command.args(["-avoid_negative_ts", "make_zero"]);
+            }
+            if to_stdout {
+                command.args(container.pipe_format_args()).arg("pipe:1");
+            } else {
+                command.args(["-f", container.muxer(), "-y"]).arg(&target_path);
+            }
+            command.stdout(if to_stdout { std::process::Stdio::inherit() } else { std::process::Stdio::null() });
+            command
+        };
+
+        let mut result = self.run_ffmpeg(&mut build_command(transcode)).await;
+        // 写到标准输出时已经送出的数据收不回来，不能重来
+        if result.is_err() && transcode.is_copy() && !to_stdout {
+            if transcode.allow_reencode {
+                status!("  ⚠ -c copy 合并失败，改为重新编码后重试（耗时较长）");
+                result = self.run_ffmpeg(&mut build_command(&transcode.reencoded())).await;
+            } else {
+                status!("  ℹ 片段之间编码参数不一致时，可加 --allow-reencode 在 -c copy 失败后自动重新编码");
+            }
+        }
+
+        if let Err(e) = result {
+            if !to_stdout {
+                let _ = fs::remove_file(&target_path).await;
+            }
+            return Err(e);
+        }
+
+        if !to_stdout {
+            fs::rename(&target_path, &output_path).await?;
+        }
+        for part in &parts {
+            fs::remove_file(part).await?;
+        }
+
+        status!("✓ 成功: {}\n", output_path.display());
+
+        Ok(output_path)
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        if self.temp_dir.exists() {
+            tokio::fs::remove_dir_all(&self.temp_dir).await?;
+        }
+        // 单独指定的合并目录可能是共享位置，只删除自己写入的文件
+        for name in ["filelist.txt", "filelist_audio.txt"] {
+            let filelist_path = self.merge_temp_dir.join(name);
+            if filelist_path.exists() {
+                tokio::fs::remove_file(&filelist_path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 从片段地址推断临时文件的扩展名，保留 .aac、.m4s 等原始类型，ffmpeg 探测格式时不会被 .ts 误导。
+/// 没有扩展名或无法识别时，fMP4 片段（有 EXT-X-MAP）用 m4s，其余用 ts
+fn segment_extension(segment: &Segment) -> &'static str {
+    const KNOWN: &[&str] = &["ts", "aac", "ac3", "ec3", "mp3", "mp4", "m4s", "m4a", "m4v", "cmfv", "cmfa", "vtt", "webvtt"];
+    let from_url = Url::parse(&segment.url).ok().and_then(|url| {
+        let name = url.path_segments()?.next_back()?.to_ascii_lowercase();
+        let (_, extension) = name.rsplit_once('.')?;
+        KNOWN.iter().copied().find(|&known| known == extension)
+    });
+    from_url.unwrap_or(if segment.init.is_some() { "m4s" } else { "ts" })
+}
+
+/// 能下载出可播放文件的 EXT-X-KEY METHOD
+const SUPPORTED_KEY_METHODS: &[&str] = &["NONE"];
+
+/// 加密的片段原样保存下来也无法播放，开始下载前就报错。
+/// FairPlay 等 DRM 以 SAMPLE-AES 加 KEYFORMAT 的形式出现，一并说明
+fn check_encryption(playlist: &m3u8_rs::MediaPlaylist) -> Result<()> {
+    for key in playlist.segments.iter().filter_map(|segment| segment.key.as_ref()) {
+        let method = match &key.method {
+            m3u8_rs::KeyMethod::None => continue,
+            m3u8_rs::KeyMethod::AES128 => "AES-128",
+            m3u8_rs::KeyMethod::SampleAES => "SAMPLE-AES",
+            m3u8_rs::KeyMethod::Other(method) => method.as_str(),
+        };
+        let drm = match key.keyformat.as_deref() {
+            Some("com.apple.streamingkeydelivery") => " (FairPlay DRM)",
+            Some("com.microsoft.playready") => " (PlayReady DRM)",
+            Some(format) if format.eq_ignore_ascii_case("urn:uuid:edef8ba9-79d6-4ace-a3c8-27dcd51d21ed") => " (Widevine DRM)",
+            _ => "",
+        };
+        anyhow::bail!(
+            "Unsupported encryption method: {}{}; supported methods: {}",
+            method,
+            drm,
+            SUPPORTED_KEY_METHODS.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// 读完子进程的标准错误，只保留末尾 FFMPEG_STDERR_KEEP 字节；echo 时原样写到终端
+async fn collect_stderr(mut stderr: impl AsyncRead + Unpin, echo: bool) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    while let Ok(n @ 1..) = stderr.read(&mut buf).await {
+        if echo {
+            let _ = std::io::Write::write_all(&mut std::io::stderr(), &buf[..n]);
+        }
+        output.extend_from_slice(&buf[..n]);
+        if output.len() > FFMPEG_STDERR_KEEP * 2 {
+            output.drain(..output.len() - FFMPEG_STDERR_KEEP);
+        }
+    }
+    output
+}
+
+/// ffmpeg 退出码非零时的错误，附上标准错误的最后几行
+fn ffmpeg_failure(stderr: &[u8]) -> anyhow::Error {
+    let stderr = String::from_utf8_lossy(stderr);
+    // 进度行以 \r 结尾，同样按行拆开
+    let lines: Vec<&str> = stderr.split(['\r', '\n']).map(str::trim_end).filter(|line| !line.is_empty()).collect();
+    let tail = &lines[lines.len().saturating_sub(FFMPEG_STDERR_LINES)..];
+    if tail.is_empty() {
+        anyhow::anyhow!("FFmpeg failed")
+    } else {
+        anyhow::anyhow!("FFmpeg failed:\n{}", tail.join("\n"))
+    }
+}
+
+/// 写 ffmpeg concat demuxer 使用的文件列表
+async fn write_concat_list(filelist_path: &Path, files: &[PathBuf]) -> Result<()> {
+    let mut filelist_content = String::new();
+    for file in files {
+        filelist_content.push_str(&concat_list_entry(file));
+    }
+    tokio::fs::write(filelist_path, filelist_content).await?;
+    Ok(())
+}
+
+/// Windows 上 canonicalize 返回 \\?\ 开头的扩展路径，ffmpeg 打不开，去掉前缀还原成普通路径
+fn plain_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    match path.strip_prefix(r"\\?\") {
+        Some(rest) => match rest.strip_prefix(r"UNC\") {
+            Some(share) => format!(r"\\{}", share),
+            None => rest.to_string(),
+        },
+        None => path.into_owned(),
+    }
+}
+
+/// concat 列表中的一行。单引号内反斜杠按原样解析，只需转义单引号本身
+fn concat_list_entry(path: &Path) -> String {
+    format!("file {}\n", quote_single(&plain_path(path)))
+}
+
+/// 按 base 解析 uri。inherit_query 时相对地址继承 base 的查询参数：
+/// uri 自带的参数保留并优先，base 中同名的参数不再追加；base 的 #fragment 不会继承
+fn resolve_against(base: &str, uri: &str, inherit_query: bool) -> Result<String> {
+    // data: URL 自带内容，原样保留
+    if uri.starts_with("data:") {
+        return Ok(uri.to_string());
+    }
+    let base_url = Url::parse(base)?;
+    let mut resolved = base_url.join(uri)?;
+    // //host/path 只省略了协议，指向的已是另一台主机，与绝对地址同样处理
+    let is_relative = matches!(Url::parse(uri), Err(url::ParseError::RelativeUrlWithoutBase)) && !uri.starts_with("//");
+    if inherit_query && is_relative && base_url.query().is_some() {
+        let own: Vec<(String, String)> = resolved.query_pairs().into_owned().collect();
+        let inherited: Vec<(String, String)> = base_url
+            .query_pairs()
+            .into_owned()
+            .filter(|(key, _)| !own.iter().any(|(own_key, _)| own_key == key))
+            .collect();
+        if own.is_empty() {
+            // 原样沿用，不改变签名参数的编码
+            resolved.set_query(base_url.query());
+        } else {
+            resolved.query_pairs_mut().extend_pairs(inherited);
+        }
+    }
+    Ok(resolved.to_string())
+}
+
+/// 片段请求返回了非 2xx 状态码，与其它错误一样进入重试
+#[derive(Debug)]
+struct HttpStatusError {
+    url: String,
+    status: http::StatusCode,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Segment {} returned HTTP {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// 下载被按 q 或 Ctrl-C 中途停止；main 据此以非零状态退出，Ctrl-C 时为 130
+#[derive(Debug)]
+struct Cancelled {
+    interrupted: bool,
+}
+
+impl Cancelled {
+    fn new() -> Self {
+        Self { interrupted: INTERRUPTED.load(Ordering::Relaxed) }
+    }
+}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.interrupted {
+            write!(f, "Download interrupted by Ctrl-C")
+        } else {
+            write!(f, "Download cancelled")
+        }
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// 活动日志中的片段名后附上失败原因，目前只区分 HTTP 状态码
+fn with_failure_reason(name: String, error: &anyhow::Error) -> String {
+    match error.downcast_ref::<HttpStatusError>() {
+        Some(e) => format!("{} (HTTP {})", name, e.status.as_u16()),
+        None => name,
+    }
+}
+
+const TS_PACKET_SIZE: usize = 188;
+
+/// check_media_content 需要看到的开头字节数：足够检查前 8 个 TS 包
+const MEDIA_SNIFF_LEN: usize = TS_PACKET_SIZE * 8;
+
+/// 客户端解码后会去掉 Content-Encoding，还留着说明是解不开的编码，原样写入的压缩数据无法播放
+fn check_content_encoding(headers: &http::HeaderMap) -> Result<()> {
+    match headers.get(http::header::CONTENT_ENCODING).and_then(|value| value.to_str().ok()) {
+        Some(encoding) if !encoding.trim().eq_ignore_ascii_case("identity") => {
+            anyhow::bail!("Response is {}-encoded and could not be decoded", encoding)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// 粗略判断响应是不是媒体数据。认得出的格式（TS、fMP4、ADTS/ID3 音频、WebVTT）直接通过；
+/// 认不出时只有 Content-Type 是文本，或没有 Content-Type 而内容像文本（HTML 错误页、JSON）才算失败；
+/// 声明为 video/*、application/octet-stream 等的加密片段等无法识别的数据照常保存
+fn check_media_content(content_type: Option<&str>, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        anyhow::bail!("Segment response is empty");
+    }
+    if data[0] == 0x47 {
+        // 只检查前几个包，截断在包中间的最后一个包不算错
+        let broken = data.chunks(TS_PACKET_SIZE).take(8).skip(1).any(|packet| packet[0] != 0x47);
+        if broken {
+            anyhow::bail!("Segment starts like MPEG-TS but packets are not {}-byte aligned", TS_PACKET_SIZE);
+        }
+        return Ok(());
+    }
+    const MP4_BOXES: &[&[u8]] = &[b"ftyp", b"styp", b"moof", b"sidx", b"mdat", b"emsg", b"prft", b"free"];
+    let is_mp4 = data.len() >= 8 && MP4_BOXES.contains(&&data[4..8]);
+    let is_audio = data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xF0 == 0xF0);
+    let is_webvtt = data.strip_prefix(b"\xEF\xBB\xBF".as_slice()).unwrap_or(data).starts_with(b"WEBVTT");
+    if is_mp4 || is_audio || is_webvtt {
+        return Ok(());
+    }
+
+    let textual_type = content_type.is_some_and(|value| {
+        let mime = value.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        mime.starts_with("text/") || mime.ends_with("/json") || mime.ends_with("/xml") || mime.ends_with("+xml")
+    });
+    // 加密数据的第一个字节可能恰好是 < 或 {，只在服务器没有声明类型时按内容猜
+    let untyped = content_type.is_none_or(|value| value.trim().is_empty());
+    let text = data.iter().position(|b| !b.is_ascii_whitespace()).map(|start| data[start]);
+    let textual_body = untyped && matches!(text, Some(b'<' | b'{' | b'['));
+    if textual_type || textual_body {
+        let preview: String = String::from_utf8_lossy(&data[..data.len().min(80)])
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        anyhow::bail!(
+            "Segment response is not media ({}): {}",
+            content_type.unwrap_or("no Content-Type"),
+            preview
+        );
+    }
+    Ok(())
+}
+
+/// data: URL 片段的内容，有 BYTERANGE 时只取其中一段
+fn read_data_url(segment: &Segment) -> Result<Bytes> {
+    let bytes = decode_data_url(&segment.url)?;
+    Ok(match segment.byte_range {
+        Some(range) => Bytes::copy_from_slice(range.slice(&bytes)?),
+        None => Bytes::from(bytes),
+    })
+}
+
+/// 解码 `data:[<mediatype>][;base64],<data>` 形式的内联片段
+fn decode_data_url(url: &str) -> Result<Vec<u8>> {
+    let rest = url.strip_prefix("data:").context("Not a data: URL")?;
+    let (meta, data) = rest.split_once(',').context("Malformed data: URL, missing ','")?;
+    if meta.ends_with(";base64") {
+        // 部分生成器会在 base64 中夹带换行或 URL 编码
+        let cleaned: String = percent_encoding::percent_decode_str(data)
+            .decode_utf8()?
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        base64::engine::general_purpose::STANDARD
+            .decode(cleaned)
+            .context("Invalid base64 payload in data: URL")
+    } else {
+        Ok(percent_encoding::percent_decode_str(data).collect())
+    }
+}
+
+/// 打开 --progress-socket：已存在的 unix socket 直接连接，命名管道以非阻塞方式打开，
+/// 否则按普通文件追加写入。命名管道还没有读取方时不等待，返回 None，本次不输出进度
+fn open_progress_socket(path: &Path) -> Result<Option<Box<dyn std::io::Write + Send>>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        /// 命名管道没有读取方时非阻塞打开返回的 errno，Linux 与 macOS 相同
+        const ENXIO: i32 = 6;
+        let file_type = std::fs::metadata(path).map(|meta| meta.file_type());
+        if file_type.as_ref().is_ok_and(|file_type| file_type.is_socket()) {
+            let stream = std::os::unix::net::UnixStream::connect(path)
+                .with_context(|| format!("Failed to connect to progress socket {}", path.display()))?;
+            return Ok(Some(Box::new(stream)));
+        }
+        if file_type.is_ok_and(|file_type| file_type.is_fifo()) {
+            let sender = match tokio::net::unix::pipe::OpenOptions::new().open_sender(path) {
+                Ok(sender) => sender,
+                Err(e) if e.raw_os_error() == Some(ENXIO) => {
+                    status!("⚠ 命名管道 {} 还没有读取方，本次不输出进度\n", path.display());
+                    return Ok(None);
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to open progress socket {}", path.display()));
+                }
+            };
+            // 打开后改回阻塞模式，写入在阻塞线程上进行，接收方读得慢时等待而不是丢弃事件
+            let fd = sender.into_blocking_fd()?;
+            return Ok(Some(Box::new(std::fs::File::from(fd))));
+        }
+    }
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("Failed to open progress socket {}", path.display()))?;
+    Ok(Some(Box::new(file)))
+}
+
+/// 每秒向 --progress-socket 写一行 JSON 进度事件 (NDJSON)，接收方断开后不再输出
+async fn run_progress_socket(
+    stats: Arc<Mutex<DownloadStats>>,
+    mut writer: Box<dyn std::io::Write + Send>,
+    cancel: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let (line, finished) = {
+            let stats = stats.lock().await;
+            let event = serde_json::json!({
+                "event": "progress",
+                "percent": stats.progress_percent(),
+                "time_percent": stats.time_progress_percent(),
+                "downloaded": stats.downloaded_segments,
+                "failed": stats.failed_segments,
+                "total": stats.total_segments,
+                "bytes": stats.downloaded_bytes,
+                "speed": stats.current_speed * 1024.0 * 1024.0,
+                "eta": stats.eta().map(|eta| eta.as_secs_f64()),
+            });
+            let finished = !stats.live && stats.downloaded_segments + stats.failed_segments >= stats.total_segments;
+            // 整行一次写出，接收方读到的总是完整的事件
+            (format!("{}\n", event), finished)
+        };
+        // 接收方读得慢时写入会阻塞：放到阻塞线程上，且不持有 stats 锁，下载和界面不受影响
+        let written = tokio::task::spawn_blocking(move || {
+            let result = writer.write_all(line.as_bytes()).and_then(|_| writer.flush());
+            result.map(|_| writer)
+        })
+        .await;
+        match written {
+            Ok(Ok(returned)) => writer = returned,
+            _ => {
+                stats.lock().await.log_activity("进度接收方已断开，不再输出进度事件".to_string(), ActivityStatus::Failed);
+                break;
+            }
+        }
+        if finished || cancel.is_cancelled() {
+            break;
+        }
+    }
+}
+
+/// `--json`：每个片段开始和结束时输出一行事件，直到收到 Finished
+async fn print_segment_events(mut events: broadcast::Receiver<ProgressEvent>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(ProgressEvent::SegmentStarted { index }) => {
+                serde_json::json!({ "event": "segment", "index": index, "status": "started" })
+            }
+            Ok(ProgressEvent::SegmentCompleted { index, bytes }) => {
+                serde_json::json!({ "event": "segment", "index": index, "status": "completed", "bytes": bytes })
+            }
+            Ok(ProgressEvent::SegmentFailed { index }) => {
+                serde_json::json!({ "event": "segment", "index": index, "status": "failed" })
+            }
+            Ok(ProgressEvent::Finished) | Err(broadcast::error::RecvError::Closed) => break,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+        };
+        emit_json(event);
+    }
+}
+
+/// 发出 Finished，并等 `--json` 的片段事件全部写出
+async fn finish_events(downloader: &M3U8Downloader, printer: Option<tokio::task::JoinHandle<()>>) {
+    downloader.emit(ProgressEvent::Finished);
+    if let Some(handle) = printer {
+        let _ = handle.await;
+    }
+}
+
+/// 一行文字的进度摘要，供 --no-tui 和终端过小时的 TUI 使用
+fn progress_summary(stats: &DownloadStats, ui: &UiOptions) -> String {
+    let eta = match stats.eta() {
+        Some(eta) => format!("{}m{:02}s", eta.as_secs() / 60, eta.as_secs() % 60),
+        None => "--".to_string(),
+    };
+    let failed = if stats.failed_segments > 0 {
+        format!(" ({} 失败)", stats.failed_segments)
+    } else {
+        String::new()
+    };
+    // 批量下载时在前面加上整个批量任务的进度
+    let batch = match stats.batch {
+        Some(batch) => format!(
+            "[{}/{} 总 {:.1}%]  ",
+            batch.current,
+            batch.total,
+            batch.overall_percent(ui.progress_percent(stats))
+        ),
+        None => String::new(),
+    };
+    format!(
+        "{}{:5.1}%  {}/{} 片段{}  {:.2} {}  ETA {}",
+        batch,
+        ui.progress_percent(stats),
+        stats.downloaded_segments,
+        stats.total_segments,
+        failed,
+        ui.speed_value(stats.current_speed),
+        ui.speed_unit(),
+        eta
+    )
+}
+
+/// `--no-tui`：在标准错误输出一行进度。终端上每秒原地刷新，重定向到日志时每 5 秒输出一行
+async fn run_plain_progress(stats: Arc<Mutex<DownloadStats>>, ui: UiOptions, cancel: CancellationToken) {
+    use std::io::{IsTerminal, Write};
+
+    let tty = std::io::stderr().is_terminal();
+    let mut interval = tokio::time::interval(Duration::from_secs(if tty { 1 } else { 5 }));
+    loop {
+        interval.tick().await;
+        let stats = stats.lock().await;
+        let line = progress_summary(&stats, &ui);
+        let done = (!stats.live && stats.downloaded_segments + stats.failed_segments >= stats.total_segments)
+            || cancel.is_cancelled();
+
+        let mut stderr = std::io::stderr();
+        if tty {
+            // 补齐空格，覆盖上一次更长的输出
+            let _ = write!(stderr, "\r{:<72}", line);
+            if done {
+                let _ = writeln!(stderr);
+            }
+        } else {
+            let _ = writeln!(stderr, "{}", line);
+        }
+        let _ = stderr.flush();
+        if done {
+            break;
+        }
+    }
+}
+
+/// 退出原始模式并离开备用屏幕；失败时也不报错，终端可能已经恢复过
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(std::io::stdout(), LeaveAlternateScreen, DisableMouseCapture, Show);
+}
+
+/// run_tui 持有期间终端处于原始模式；任务被 abort 或 panic 时也会在 drop 中恢复终端
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+        TUI_ACTIVE.store(false, Ordering::Relaxed);
+    }
+}
+
+/// 第一次 Ctrl-C 取消下载，让各任务正常收尾；再按一次时恢复终端后立即退出
+fn spawn_ctrl_c_handler(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        cancel.cancel();
+        // TUI 看到取消后自行退出，下载结束时的提示在离开备用屏幕之后才打印
+        if !TUI_ACTIVE.load(Ordering::Relaxed) {
+            status!("\n⚠ 收到 Ctrl-C，正在停止下载（再按一次立即退出）");
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            restore_terminal();
+            std::process::exit(130);
+        }
+    });
+}
+
+async fn run_tui(
+    stats: Arc<Mutex<DownloadStats>>,
+    mut events: broadcast::Receiver<ProgressEvent>,
+    url: String,
+    output: String,
+    mut ui: UiOptions,
+    cancel: CancellationToken,
+) -> Result<()> {
+    enable_raw_mode()?;
+    TUI_ACTIVE.store(true, Ordering::Relaxed);
+    let _guard = TerminalGuard;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let tick_rate = Duration::from_millis(250);
+    // 没有新事件时也按这个间隔重绘，刷新耗时、速度和重试日志
+    let idle_redraw = Duration::from_secs(1);
+    let mut last_tick = Instant::now();
+    let mut last_draw = Instant::now();
+    let mut dirty = true;
+    let mut finished = false;
+
+    loop {
+        // 只在有片段事件、按键或到了空闲重绘间隔时才锁住 stats
+        while !finished {
+            match events.try_recv() {
+                Ok(ProgressEvent::Finished) | Err(broadcast::error::TryRecvError::Closed) => {
+                    finished = true;
+                    dirty = true;
+                }
+                Ok(_) | Err(broadcast::error::TryRecvError::Lagged(_)) => dirty = true,
+                Err(broadcast::error::TryRecvError::Empty) => break,
+            }
+        }
+        if dirty || last_draw.elapsed() >= idle_redraw {
+            let stats_guard = stats.lock().await;
+            terminal.draw(|f| draw_ui(f, &stats_guard, &url, &output, &ui))?;
+            last_draw = Instant::now();
+            dirty = false;
+
+            // 检查是否完成
+            if !stats_guard.live
+                && stats_guard.downloaded_segments + stats_guard.failed_segments >= stats_guard.total_segments
+            {
+                break;
+            }
+        }
+        if finished || cancel.is_cancelled() {
+            break;
+        }
+
+        let timeout = tick_rate
+            .checked_sub(last_tick.elapsed())
+            .unwrap_or_else(|| Duration::from_secs(0));
+
+        if crossterm::event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                dirty = true;
+                match key.code {
+                    // 原始模式下 Ctrl-C 不会产生 SIGINT，只是一次按键
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        INTERRUPTED.store(true, Ordering::Relaxed);
+                        cancel.cancel();
+                        break;
+                    }
+                    KeyCode::Char('q') => {
+                        // 直播录制时 q 只是停止刷新，已下载的部分照常合并
+                        let mut stats = stats.lock().await;
+                        if stats.live {
+                            stats.stop_requested = true;
+                        } else {
+                            cancel.cancel();
+                        }
+                        break;
+                    }
+                    KeyCode::Char('b') => ui.bits = !ui.bits,
+                    KeyCode::Char('t') => ui.by_time = !ui.by_time,
+                    KeyCode::Char('+' | '=') => stats.lock().await.request_concurrency(1),
+                    KeyCode::Char('-') => stats.lock().await.request_concurrency(-1),
+                    KeyCode::Char(c @ '1'..='5') => ui.toggle_panel(Panel::ALL[c as usize - '1' as usize]),
+                    KeyCode::Up => stats.lock().await.scroll_activity(1),
+                    KeyCode::Down => stats.lock().await.scroll_activity(-1),
+                    KeyCode::PageUp => stats.lock().await.scroll_activity(10),
+                    KeyCode::PageDown => stats.lock().await.scroll_activity(-10),
+                    KeyCode::End => stats.lock().await.activity_scroll = 0,
+                    KeyCode::Char(' ') => {
+                        let mut stats = stats.lock().await;
+                        stats.paused = !stats.paused;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if last_tick.elapsed() >= tick_rate {
+            last_tick = Instant::now();
+        }
+    }
+
+    // 恢复终端由 _guard 在返回时完成
+    Ok(())
+}
+
+/// 8 色终端上与 color 最接近的基本色：各通道按亮度二值化
+fn nearest_basic_color(color: Color) -> Color {
+    let [r, g, b] = color_to_rgb(color);
+    match (r > 127, g > 127, b > 127) {
+        (false, false, false) => Color::Black,
+        (true, false, false) => Color::Red,
+        (false, true, false) => Color::Green,
+        (true, true, false) => Color::Yellow,
+        (false, false, true) => Color::Blue,
+        (true, false, true) => Color::Magenta,
+        (false, true, true) => Color::Cyan,
+        (true, true, true) => Color::White,
+    }
+}
+
+/// 终端 16 色在常见配色下对应的 RGB 值，256 色按 xterm 调色板换算
+fn color_to_rgb(color: Color) -> [u8; 3] {
+    const ANSI: [Color; 16] = [
+        Color::Black, Color::Red, Color::Green, Color::Yellow, Color::Blue, Color::Magenta, Color::Cyan, Color::Gray,
+        Color::DarkGray, Color::LightRed, Color::LightGreen, Color::LightYellow, Color::LightBlue,
+        Color::LightMagenta, Color::LightCyan, Color::White,
+    ];
+    match color {
+        Color::Rgb(r, g, b) => [r, g, b],
+        Color::Indexed(i) if i < 16 => color_to_rgb(ANSI[i as usize]),
+        Color::Indexed(i) if i >= 232 => [8 + (i - 232) * 10; 3],
+        Color::Indexed(i) => {
+            // 16-231 是 6×6×6 的颜色立方
+            let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+            let i = i - 16;
+            [level(i / 36), level(i / 6 % 6), level(i % 6)]
+        }
+        Color::Black => [0, 0, 0],
+        Color::Red => [205, 49, 49],
+        Color::Green => [13, 188, 121],
+        Color::Yellow => [229, 229, 16],
+        Color::Blue => [36, 114, 200],
+        Color::Magenta => [188, 63, 188],
+        Color::Cyan => [17, 168, 205],
+        Color::Gray => [229, 229, 229],
+        Color::DarkGray => [102, 102, 102],
+        Color::LightRed => [241, 76, 76],
+        Color::LightGreen => [35, 209, 139],
+        Color::LightYellow => [245, 245, 67],
+        Color::LightBlue => [59, 142, 234],
+        Color::LightMagenta => [214, 112, 214],
+        Color::LightCyan => [41, 184, 219],
+        _ => [255, 255, 255],
+    }
+}
+
+/// 把最终的分块状态渲染成 PNG，配色与 Chunks 面板一致
+fn save_chunkmap_png(states: &[ChunkState], theme: &Theme, path: &std::path::Path) -> Result<()> {
+    const CELL: usize = 16;
+    const GAP: usize = 2;
+    const BACKGROUND: [u8; 3] = [24, 24, 32];
+
+    let columns = (states.len() as f64).sqrt().ceil().max(1.0) as usize;
+    let rows = states.len().div_ceil(columns).max(1);
+    let width = columns * (CELL + GAP) + GAP;
+    let height = rows * (CELL + GAP) + GAP;
+
+    let mut pixels = BACKGROUND.repeat(width * height);
+    for (i, state) in states.iter().enumerate() {
+        let rgb = color_to_rgb(state.color(theme));
+        let left = GAP + (i % columns) * (CELL + GAP);
+        let top = GAP + (i / columns) * (CELL + GAP);
+        for y in top..top + CELL {
+            for x in left..left + CELL {
+                let offset = (y * width + x) * 3;
+                pixels[offset..offset + 3].copy_from_slice(&rgb);
+            }
+        }
+    }
+
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    Ok(())
+}
+
+/// 写出 --report：按片段序号排列的大小与耗时，.json 扩展名写 JSON 数组，否则写 CSV
+fn write_report(records: &[SegmentRecord], segments: &[Segment], path: &Path) -> Result<()> {
+    let mut records = records.to_vec();
+    records.sort_by_key(|record| record.index);
+
+    let content = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+        let rows: Vec<serde_json::Value> = records
+            .iter()
+            .map(|record| {
+                serde_json::json!({
+                    "index": record.index,
+                    "url": segments.get(record.index).map(|s| s.url.as_str()),
+                    "bytes": record.bytes,
+                    "seconds": record.elapsed.as_secs_f64(),
+                })
+            })
+            .collect();
+        serde_json::to_string_pretty(&rows)?
+    } else {
+        let mut csv = String::from("index,url,bytes,seconds\n");
+        for record in &records {
+            let url = segments.get(record.index).map(|s| s.url.as_str()).unwrap_or("");
+            csv.push_str(&format!(
+                "{},\"{}\",{},{:.3}\n",
+                record.index,
+                url.replace('"', "\"\""),
+                record.bytes,
+                record.elapsed.as_secs_f64()
+            ));
+        }
+        csv
+    };
+
+    std::fs::write(path, content).with_context(|| format!("Failed to write report {}", path.display()))
+}
+
+/// 下载结束后仍然失败的片段，写到输出目录的 `<输出文件名>.failed.json`，供 --retry-file 补下载
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct FailedList {
+    url: String,
+    output: String,
+    /// 保留着已完成片段的临时目录
+    temp_dir: PathBuf,
+    /// 播放列表的片段总数，补下载时用来确认播放列表没有变化
+    total_segments: usize,
+    failed: Vec<FailedSegment>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct FailedSegment {
+    index: usize,
+    url: String,
+}
+
+impl FailedList {
+    fn path(output_dir: &Path, output: &str) -> PathBuf {
+        output_dir.join(format!("{}.failed.json", output))
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read retry file {}", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid retry file {}", path.display()))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// 临时目录中的片段按序号对应，片段数或失败片段的地址变了（换了 --quality、播放列表已更新）就对不上
+    fn check_matches(&self, segments: &[Segment], path: &Path) -> Result<()> {
+        if self.total_segments != segments.len() {
+            anyhow::bail!(
+                "The playlist now has {} segments but {} was written for {}; pass the same options as the original download",
+                segments.len(),
+                path.display(),
+                self.total_segments
+            );
+        }
+        for failed in &self.failed {
+            if segments.get(failed.index).is_none_or(|segment| segment.url != failed.url) {
+                anyhow::bail!(
+                    "Segment {} in the playlist no longer matches {} ({}); pass the same options as the original download",
+                    failed.index + 1,
+                    path.display(),
+                    failed.url
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 配置文件中的值：整数、小数或字符串，如 concurrent = 8 或 "auto"、limit-rate = 2.5 或 "2M"。
+/// 都按对应命令行参数的规则解析
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum ConfigValue {
+    Integer(u64),
+    Float(f64),
+    Text(String),
+}
+
+impl std::fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigValue::Integer(n) => write!(f, "{}", n),
+            ConfigValue::Float(n) => write!(f, "{}", n),
+            ConfigValue::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// config.toml 中的默认值，键名与同名的命令行参数一致
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    dir: Option<String>,
+    concurrent: Option<ConfigValue>,
+    headers: Vec<String>,
+    cookie: Option<String>,
+    proxy: Option<String>,
+    proxy_auth: Option<String>,
+    user_agent: Option<String>,
+    ffmpeg_path: Option<String>,
+    ffprobe_path: Option<String>,
+    limit_rate: Option<ConfigValue>,
+    connect_timeout: Option<ConfigValue>,
+    read_timeout: Option<ConfigValue>,
+    retries: Option<ConfigValue>,
+    retry_delay_ms: Option<ConfigValue>,
+    ramp_up: Option<ConfigValue>,
+}
+
+impl Config {
+    /// 把配置中的值填入命令行没有给出的参数（given 按参数 id 判断），按与命令行相同的规则解析。
+    /// 请求头与命令行的合并，同名时命令行的为准
+    fn apply(self, args: &mut Args, given: impl Fn(&str) -> bool) -> Result<()> {
+        fn parse<T, E: std::fmt::Display>(
+            key: &str,
+            value: &ConfigValue,
+            parser: impl Fn(&str) -> std::result::Result<T, E>,
+        ) -> Result<T> {
+            parser(&value.to_string()).map_err(|e| anyhow::anyhow!("{}: {}", key, e))
+        }
+        fn number<T: std::str::FromStr + PartialOrd + std::fmt::Display>(
+            min: T,
+        ) -> impl Fn(&str) -> std::result::Result<T, String> {
+            move |value| match value.parse::<T>() {
+                Ok(n) if n >= min => Ok(n),
+                _ => Err(format!("invalid value '{}', expected a whole number of at least {}", value, min)),
+            }
+        }
+
+        let text = |value: &Option<String>| value.clone().map(ConfigValue::Text);
+        if let Some(dir) = self.dir.filter(|_| !given("dir")) {
+            args.dir = dir;
+        }
+        if let Some(value) = self.concurrent.filter(|_| !given("concurrent")) {
+            args.concurrent = parse("concurrent", &value, parse_concurrency)?;
+        }
+        let mut headers = self.headers.iter()
+            .map(|header| parse_header(header).map_err(|e| anyhow::anyhow!("headers: {}", e)))
+            .collect::<Result<Vec<_>>>()?;
+        headers.append(&mut args.headers);
+        args.headers = headers;
+        if let Some(cookie) = self.cookie.filter(|_| !given("cookie")) {
+            args.cookie = Some(cookie);
+        }
+        if let Some(value) = text(&self.proxy).filter(|_| !given("proxy")) {
+            args.proxy = Some(parse("proxy", &value, parse_proxy_url)?);
+        }
+        if let Some(auth) = self.proxy_auth.filter(|_| !given("proxy_auth")) {
+            args.proxy_auth = Some(auth);
+        }
+        if let Some(value) = text(&self.user_agent).filter(|_| !given("user_agent")) {
+            args.user_agent = Some(parse("user-agent", &value, parse_user_agent)?);
+        }
+        if let Some(path) = self.ffmpeg_path.filter(|_| !given("ffmpeg_path")) {
+            args.ffmpeg_path = path;
+        }
+        if let Some(path) = self.ffprobe_path.filter(|_| !given("ffprobe_path")) {
+            args.ffprobe_path = Some(path);
+        }
+        if let Some(value) = self.limit_rate.filter(|_| !given("limit_rate")) {
+            args.limit_rate = Some(parse("limit-rate", &value, parse_rate)?);
+        }
+        if let Some(value) = self.connect_timeout.filter(|_| !given("connect_timeout")) {
+            args.connect_timeout = parse("connect-timeout", &value, number(1))?;
+        }
+        if let Some(value) = self.read_timeout.filter(|_| !given("read_timeout")) {
+            args.read_timeout = parse("read-timeout", &value, number(1))?;
+        }
+        if let Some(value) = self.retries.filter(|_| !given("retries")) {
+            args.retries = parse("retries", &value, number(0))?;
+        }
+        if let Some(value) = self.retry_delay_ms.filter(|_| !given("retry_delay_ms")) {
+            args.retry_delay_ms = parse("retry-delay-ms", &value, number(0))?;
+        }
+        if let Some(value) = self.ramp_up.filter(|_| !given("ramp_up")) {
+            args.ramp_up = parse("ramp-up", &value, number(0))?;
+        }
+        Ok(())
+    }
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => expand_path("~/.config"),
+    };
+    Some(config_home.join("surge-wave").join("config.toml")).filter(|path| path.is_absolute())
+}
+
+/// 用配置文件中的值补上命令行没有给出的参数；--config 指定的文件必须存在，默认位置没有文件时忽略
+fn apply_config(args: &mut Args, matches: &clap::ArgMatches) -> Result<()> {
+    let path = match &args.config {
+        Some(path) => expand_path(path),
+        None => match default_config_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        },
+    };
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let config: Config = toml::from_str(&content)
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    config
+        .apply(args, |id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine))
+        .with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+/// 读取 --batch 文件，每行 "URL 输出文件名"，文件名中可以有空格
+fn read_batch_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read batch file {}", path.display()))?;
+    let items: Vec<(String, String)> = content
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            let (url, output) = line
+                .split_once(char::is_whitespace)
+                .with_context(|| format!("{}:{}: expected \"URL OUTPUT\"", path.display(), n + 1))?;
+            // 文件名中的路径分隔符和 .. 不能让输出跳出输出目录
+            Ok((url.to_string(), safe_file_name(output.trim())))
+        })
+        .collect::<Result<_>>()?;
+    if items.is_empty() {
+        anyhow::bail!("Batch file {} has no entries", path.display());
+    }
+    Ok(items)
+}
+
+/// 把名称变成单个路径组成部分：路径分隔符和控制字符换成 _，只有点的名称（如 ..）整个换掉，
+/// 批量文件中的名称不会跳出所在目录
+fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect();
+    if name.trim_matches('.').is_empty() {
+        "_".repeat(name.len().max(1))
+    } else {
+        name
+    }
+}
+
+/// --batch 时每个条目各写一份 --report/--save-chunkmap：在扩展名前加上条目的输出名，
+/// 如 report.json → report-movie.json
+fn batch_item_path(path: &Path, item: Option<&str>) -> PathBuf {
+    let Some(item) = item else {
+        return path.to_path_buf();
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, safe_file_name(item), ext.to_string_lossy()),
+        None => format!("{}-{}", stem, safe_file_name(item)),
+    };
+    path.with_file_name(name)
+}
+
+/// `--cookie` 的两种形式：原始 Cookie 头，或 Netscape 格式的 cookies.txt
+#[derive(Clone)]
+enum CookieSource {
+    Header(http::HeaderValue),
+    Jar(Arc<wreq::cookie::Jar>),
+}
+
+fn load_cookies(value: &str) -> Result<CookieSource> {
+    let path = expand_path(value);
+    if path.is_file() {
+        return read_cookie_file(&path).map(|jar| CookieSource::Jar(Arc::new(jar)));
+    }
+
+    let raw = value.trim();
+    let raw = match raw.split_once(':') {
+        Some((name, rest)) if name.trim().eq_ignore_ascii_case("cookie") => rest.trim(),
+        _ => raw,
+    };
+    if !raw.contains('=') {
+        anyhow::bail!("--cookie expects \"name=value; ...\" or a path to a cookies.txt file, got '{}'", value);
+    }
+    let header = http::HeaderValue::from_str(raw).context("Invalid characters in --cookie value")?;
+    Ok(CookieSource::Header(header))
+}
+
+/// 读取 curl/浏览器扩展导出的 Netscape cookies.txt，按各自的域名放入 cookie jar
+fn read_cookie_file(path: &Path) -> Result<wreq::cookie::Jar> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read cookies from {}", path.display()))?;
+    let jar = wreq::cookie::Jar::default();
+    let mut count = 0;
+
+    for (line_no, line) in content.lines().enumerate() {
+        // HttpOnly 的 cookie 以 "#HttpOnly_" 前缀导出，其余以 # 开头的是注释
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, _, cookie_path, secure, _, name, value] = fields[..] else {
+            anyhow::bail!("{}:{}: expected 7 tab-separated fields", path.display(), line_no + 1);
+        };
+
+        let host = domain.trim_start_matches('.');
+        let scheme = if secure.eq_ignore_ascii_case("TRUE") { "https" } else { "http" };
+        let url = Url::parse(&format!("{}://{}{}", scheme, host, cookie_path))
+            .with_context(|| format!("{}:{}: invalid cookie domain '{}'", path.display(), line_no + 1, domain))?;
+        let mut cookie = format!("{}={}; Domain={}; Path={}", name, value, host, cookie_path);
+        if scheme == "https" {
+            cookie.push_str("; Secure");
+        }
+        jar.add_cookie_str(&cookie, &url);
+        count += 1;
+    }
+
+    if count == 0 {
+        anyhow::bail!("No cookies found in {}", path.display());
+    }
+    Ok(jar)
+}
+
+fn read_bearer_file(path: &Path) -> Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read bearer token from {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("Bearer token file {} is empty", path.display());
+    }
+    Ok(token.to_string())
+}
+
+/// 通过系统 shell 执行命令，取 stdout 作为 token
+fn run_bearer_command(command: &str) -> Result<String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    }
+    .with_context(|| format!("Failed to run bearer command: {}", command))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Bearer command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let token = String::from_utf8(output.stdout).context("Bearer command printed non-UTF-8 output")?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("Bearer command printed an empty token");
+    }
+    Ok(token.to_string())
+}
+
+/// 启动时确认 ffmpeg 可以执行，避免下载完才发现无法合并
+fn check_ffmpeg(ffmpeg: &Path) -> Result<()> {
+    let result = Command::new(ffmpeg)
+        .arg("-version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            print_ffmpeg_install_hint(ffmpeg);
+            anyhow::bail!("ffmpeg not found: {}", ffmpeg.display())
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to run {}", ffmpeg.display())),
+    }
+}
+
+fn print_ffmpeg_install_hint(ffmpeg: &Path) {
+    status!("✗ 找不到 ffmpeg ({})，合并片段需要 ffmpeg", ffmpeg.display());
+    status!("  安装方法:");
+    status!("    macOS:          brew install ffmpeg");
+    status!("    Debian/Ubuntu:  sudo apt install ffmpeg");
+    status!("    Fedora:         sudo dnf install ffmpeg");
+    status!("    Windows:        winget install ffmpeg");
+    status!("  已安装在其它位置时用 --ffmpeg-path 指定可执行文件；--format ts 或 --hls-output 不需要 ffmpeg");
+}
+
+/// 没有指定 --ffprobe-path 时，在 ffmpeg 旁边找 ffprobe（保留 .exe 等后缀）
+fn default_ffprobe_path(ffmpeg: &Path) -> PathBuf {
+    let name = ffmpeg.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let probe = match name.find("ffmpeg") {
+        Some(at) => format!("{}ffprobe{}", &name[..at], &name[at + "ffmpeg".len()..]),
+        None => "ffprobe".to_string(),
+    };
+    ffmpeg.with_file_name(probe)
+}
+
+/// 合并前确认 ffprobe 可以执行，避免合并和清理片段之后才发现无法校验
+fn check_ffprobe(ffprobe: &Path) -> Result<()> {
+    let result = Command::new(ffprobe)
+        .arg("-version")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            status!("✗ 找不到 ffprobe ({})，--verify 需要 ffprobe", ffprobe.display());
+            status!("  ffprobe 通常和 ffmpeg 一起安装；在其它位置时用 --ffprobe-path 指定");
+            anyhow::bail!("ffprobe not found: {}", ffprobe.display())
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to run {}", ffprobe.display())),
+    }
+}
+
+/// 用 ffprobe 读取媒体文件的时长（秒）
+fn probe_duration(ffprobe: &Path, path: &Path) -> Result<f64> {
+    let output = Command::new(ffprobe)
+        .args(["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .output()
+        .with_context(|| format!("Failed to run {}", ffprobe.display()))?;
+    if !output.status.success() {
+        anyhow::bail!("ffprobe failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<f64>()
+        .context("ffprobe returned an unreadable duration")
+}
+
+/// 0 到 max 之间的随机时长，避免大量片段在同一时刻集中重试
+fn jitter(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let max_nanos = max.as_nanos() as u64;
+    if max_nanos == 0 {
+        return Duration::ZERO;
+    }
+    // RandomState 每次创建都带有不同的随机种子，足够用作抖动
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % max_nanos)
+}
+
+/// 在交互式终端上询问 y/N，非交互环境一律视为否
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::{IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    // 提示写到标准错误，`-o -` 时不会混进视频数据
+    eprint!("{} [y/N] ", prompt);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// 询问如何处理失败的片段；非交互终端返回 None，由调用方使用默认动作
+fn ask_failure_action(failed: usize) -> Result<Option<FailureAction>> {
+    use std::io::{IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    loop {
+        eprint!("{} 个片段下载失败。[R]重试失败片段 / [M]仍然合并 / [A]放弃? ", failed);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        if let Ok(action) = parse_failure_action(answer.trim()) {
+            return Ok(Some(action));
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let result = run().await;
+    if let Err(e) = &result {
+        if e.downcast_ref::<Cancelled>().is_some_and(|cancelled| cancelled.interrupted) {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(130);
+        }
+    }
+    result
+}
+
+async fn run() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    apply_config(&mut args, &matches)?;
+    let to_stdout = args.output.as_deref().is_some_and(is_stdout);
+    STDOUT_RESERVED.store(args.json || to_stdout, Ordering::Relaxed);
+    if to_stdout && (args.json || args.append || args.hls_output || args.stream_merge || args.emit_concat_only || args.subs.is_some()) {
+        anyhow::bail!("-o - cannot be combined with --json, --append, --hls-output, --stream-merge, --emit-concat-only or --subs");
+    }
+    // auto 的并发由自适应探测决定，逐步加大并发不会生效；两者都可能来自配置文件，只能在合并后检查
+    if args.concurrent == Concurrency::Auto && args.ramp_up > 0 {
+        anyhow::bail!("--ramp-up cannot be used with --concurrent auto, which already starts with a few connections and adapts");
+    }
+    if args.segment_body.is_some() && args.segment_method == http::Method::GET {
+        anyhow::bail!("--segment-body needs --segment-method with a method that carries a body, such as POST");
+    }
+
+    let output_dir = expand_path(&args.dir);
+
+    let bearer_token = match (&args.bearer_file, &args.bearer_command) {
+        (Some(path), _) => Some(read_bearer_file(&expand_path(path))?),
+        (None, Some(command)) => Some(run_bearer_command(command)?),
+        (None, None) => None,
+    };
+
+    let cookies = args.cookie.as_deref().map(load_cookies).transpose()?;
+    let proxy = args.proxy
+        .as_ref()
+        .map(|url| build_proxy(url, args.proxy_auth.as_deref()))
+        .transpose()?;
+
+    let mut options = DownloadOptions {
+        concurrent_limit: match args.concurrent {
+            Concurrency::Fixed(n) => n,
+            Concurrency::Auto => AUTO_CONCURRENCY_MAX,
+        },
+        sequential_window: args.sequential,
+        headers: args.headers.clone(),
+        user_agent: args.user_agent.clone(),
+        cookies,
+        proxy,
+        playlist_accept: args.accept.clone(),
+        write_buffer_size: args.write_buffer_size,
+        bearer_token,
+        merge_temp_dir: args.merge_temp_dir.as_deref().map(expand_path),
+        merge_jobs: args.merge_jobs,
+        verbose: args.verbose,
+        segment_method: args.segment_method.clone(),
+        segment_body: args.segment_body.clone(),
+        detect_connection_limit: args.detect_conn_limit,
+        ramp_up: Duration::from_secs(args.ramp_up),
+        inherit_query: args.inherit_query,
+        adaptive_concurrency: args.concurrent == Concurrency::Auto,
+        max_concurrency: args.max_concurrent as usize,
+        retries: args.retries,
+        retry_delay: Duration::from_millis(args.retry_delay_ms),
+        quality: args.quality,
+        low_latency: args.low_latency,
+        audio_lang: args.audio_lang.clone(),
+        subs: args.subs.clone(),
+        limit_rate: args.limit_rate,
+        connect_timeout: Duration::from_secs(args.connect_timeout),
+        read_timeout: Duration::from_secs(args.read_timeout),
+        ffmpeg_path: expand_path(&args.ffmpeg_path),
+        on_collision: if args.no_overwrite {
+            OnCollision::Fail
+        } else if args.auto_rename {
+            OnCollision::Rename
+        } else {
+            OnCollision::Overwrite
+        },
+        temp_dir: None,
+        client: None,
+        sink: None,
+    };
+    let cancel = CancellationToken::new();
+    spawn_ctrl_c_handler(cancel.clone());
+
+    if args.list_formats {
+        let Some(url) = &args.url else {
+            anyhow::bail!("A playlist URL is required for --list-formats");
+        };
+        let downloader = M3U8Downloader::new(url.clone(), output_dir, options);
+        print_formats(&downloader.list_variants().await?);
+        return Ok(());
+    }
+
+    if args.dry_run {
+        let Some(url) = &args.url else {
+            anyhow::bail!("A playlist URL is required for --dry-run");
+        };
+        return dry_run(&M3U8Downloader::new(url.clone(), output_dir, options)).await;
+    }
+
+    fs::create_dir_all(&output_dir).await?;
+
+    if let Some(path) = &args.retry_file {
+        let path = expand_path(path);
+        let list = FailedList::read(&path)?;
+        if !list.temp_dir.is_dir() {
+            anyhow::bail!("Temp directory {} listed in {} no longer exists", list.temp_dir.display(), path.display());
+        }
+        status!("♻ 重新下载 {} 个失败的片段到 {}\n", list.failed.len(), list.temp_dir.display());
+        options.temp_dir = Some(list.temp_dir);
+        return download_playlist(&args, &list.url, &list.output, output_dir, options, None, cancel).await;
+    }
+
+    let Some(batch_file) = &args.batch else {
+        // 没有 --batch 时 clap 保证 url 已给出，output 只在有 --output-template 时可以省略
+        let Some(url) = &args.url else {
+            anyhow::bail!("A playlist URL is required unless --batch is given");
+        };
+        let output = match (&args.output, &args.output_template) {
+            (Some(output), _) => output.clone(),
+            (None, Some(_)) => playlist_title(url),
+            (None, None) => anyhow::bail!("--output is required unless --batch or --output-template is given"),
+        };
+        return download_playlist(&args, url, &output, output_dir, options, None, cancel).await;
+    };
+
+    let items = read_batch_file(&expand_path(batch_file))?;
+    options.client = Some(M3U8Downloader::build_client(&options));
+    download_batch(&args, &items, output_dir, options, cancel).await
+}
+
+/// `--batch`：按顺序逐个下载，某个条目失败时继续下一个，最后汇总失败的条目
+async fn download_batch(
+    args: &Args,
+    items: &[(String, String)],
+    output_dir: PathBuf,
+    options: DownloadOptions,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let mut failed = Vec::new();
+    let mut stopped = false;
+    for (i, (url, output)) in items.iter().enumerate() {
+        let batch = BatchProgress { current: i + 1, total: items.len(), failed: failed.len() };
+        status!("📋 [{}/{}] {} → {}\n", batch.current, batch.total, url, output);
+        // 各条目的片段放在各自的临时目录，失败时留下的片段不会混进下一个条目
+        let mut item_options = options.clone();
+        item_options.temp_dir = Some(output_dir.join("temp").join(safe_file_name(output)));
+        // 每个条目用自己的子令牌：超出失败上限只停止当前条目，Ctrl-C 取消的父令牌仍会停止全部
+        let result = download_playlist(args, url, output, output_dir.clone(), item_options, Some(batch), cancel.child_token()).await;
+        // 按 q 只停止了当前条目的令牌，同样不再下载剩余的条目
+        stopped = cancel.is_cancelled() || result.as_ref().is_err_and(|e| e.is::<Cancelled>());
+        if let Err(e) = result {
+            status!("✗ {} 下载失败: {:#}\n", output, e);
+            failed.push(output.as_str());
+        }
+        if stopped {
+            status!("⚠ 已取消，跳过剩余的 {} 个条目", items.len() - i - 1);
+            break;
+        }
+    }
+
+    status!("📋 批量下载完成: {} 个成功，{} 个失败", items.len() - failed.len(), failed.len());
+    if stopped {
+        return Err(Cancelled::new().into());
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("{} of {} batch items failed: {}", failed.len(), items.len(), failed.join(", "));
+    }
+    Ok(())
+}
+
+/// `--dry-run`：解析播放列表并估算大小，不下载片段、不合并、不启动 TUI
+async fn dry_run(downloader: &M3U8Downloader) -> Result<()> {
+    let stream = downloader.fetch_m3u8().await?;
+    let renditions = [Some(&stream.segments), stream.audio.as_ref().map(|audio| &audio.segments)];
+    let mut estimated = Some(0);
+    for segments in renditions.into_iter().flatten() {
+        estimated = match (estimated, downloader.estimate_size_by_probe(segments).await) {
+            (Some(total), Some(size)) => Some(total + size),
+            _ => None,
+        };
+    }
+
+    if stream.live.is_some() {
+        status!("ℹ 播放列表没有 EXT-X-ENDLIST，是直播流；以下只是当前窗口的统计");
+    }
+    match estimated {
+        Some(size) => status!("💾 预计大小约 {}（抽样 {} 个片段估算）", format_bytes(size), DRY_RUN_SAMPLES),
+        None => status!("⚠ 无法获取片段大小，跳过大小估算"),
+    }
+    status!("ℹ --dry-run：未下载任何片段");
+    Ok(())
+}
+
+/// 从 len 个元素中均匀挑选最多 n 个下标，包含首尾
+fn sample_indices(len: usize, n: usize) -> Vec<usize> {
+    match (len, n) {
+        (0, _) | (_, 0) => Vec::new(),
+        (_, 1) => vec![0],
+        _ if len <= n => (0..len).collect(),
+        _ => (0..n).map(|i| i * (len - 1) / (n - 1)).collect(),
+    }
+}
+
+/// 没有指定 --single-file-temp 时，片段足够多且没有需要逐个片段文件的选项才自动开启
+fn auto_single_file_temp(args: &Args, segment_count: usize) -> bool {
+    segment_count >= SINGLE_FILE_TEMP_MIN_SEGMENTS
+        && !(args.stream_merge || args.append || args.resume || args.refresh_failed || args.hls_output || args.emit_concat_only)
+        && args.min_segment_ratio.is_none()
+        && args.retry_file.is_none()
+}
+
+/// 下载结束后还有失败的片段时，按交互选择或 --on-failure 重试、照常合并或放弃；
+/// 返回是否跳过缺失的片段合并，放弃时返回错误
+async fn resolve_failed_segments(
+    args: &Args,
+    downloader: &M3U8Downloader,
+    segments: &[Segment],
+    stats: &Mutex<DownloadStats>,
+) -> Result<bool> {
+    // 交互时选择"仍然合并"等同于 --allow-partial
+    let mut allow_partial = args.allow_partial;
+    loop {
+        let failed = stats.lock().await.failed_segments;
+        if failed == 0 {
+            break;
+        }
+        let prompted = if args.yes || args.json { None } else { ask_failure_action(failed)? };
+        match prompted.unwrap_or(args.on_failure) {
+            FailureAction::Retry => {
+                downloader.retry_failed(segments, stats).await;
+                // 非交互模式只按默认动作重试一轮，之后照常合并
+                if prompted.is_none() {
+                    break;
+                }
+            }
+            FailureAction::Merge => {
+                allow_partial |= prompted.is_some();
+                break;
+            }
+            FailureAction::Abort => anyhow::bail!("Merge aborted, {} segments failed", failed),
+        }
+    }
+    Ok(allow_partial)
+}
+
+/// 下载并合并一个播放列表；--batch 时每个条目调用一次
+async fn download_playlist(
+    args: &Args,
+    url: &str,
+    output: &str,
+    output_dir: PathBuf,
+    options: DownloadOptions,
+    batch: Option<BatchProgress>,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let progress_socket = args
+        .progress_socket
+        .as_deref()
+        .map(|path| open_progress_socket(&expand_path(path)))
+        .transpose()?
+        .flatten();
+
+    let downloader = Arc::new(M3U8Downloader::new(url.to_string(), output_dir, options));
+    let to_stdout = is_stdout(output);
+
+    let stream = downloader.fetch_m3u8().await?;
+    let mut segments = stream.segments;
+    let (container, reason) = match args.format {
+        Some(container) => (container, "由 --format 指定".to_string()),
+        None => Container::for_codecs(stream.codecs.as_deref()),
+    };
+    status!("🎞  输出格式: {} ({})\n", container.extension().to_uppercase(), reason);
+
+    let expanded;
+    let output = match &args.output_template {
+        Some(template) if !to_stdout => {
+            let date = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+            expanded = expand_output_template(template, &TemplateVars {
+                title: output,
+                resolution: stream.resolution.as_deref(),
+                date: utc_date(date),
+                index: batch.map_or(1, |batch| batch.current),
+                ext: container.extension(),
+            });
+            status!("📝 输出文件名: {}.{}\n", expanded, container.extension());
+            expanded.as_str()
+        }
+        _ => output,
+    };
+
+    // 没有 EXT-X-ENDLIST 时自动录制；与录制冲突的 --resume、--retry-file、--from/--to 只下载当前窗口
+    let window_only = args.no_live || args.resume || args.retry_file.is_some() || args.from.is_some() || args.to.is_some();
+    let live = match stream.live {
+        None => {
+            if args.live {
+                status!("ℹ 播放列表已有 EXT-X-ENDLIST，按点播下载\n");
+            }
+            None
+        }
+        Some(_) if window_only => {
+            status!("ℹ 播放列表没有 EXT-X-ENDLIST，是直播流；本次只下载当前窗口中的 {} 个片段\n", segments.len());
+            None
+        }
+        live => live,
+    };
+    let is_live = live.is_some();
+    if let Some(tail) = args.tail {
+        if live.is_none() {
+            status!("⚠ --tail 只适用于直播，已忽略\n");
+        } else if segments.len() > tail {
+            status!("⏭  跳过 DVR 窗口中较早的 {} 个片段，从最后 {} 个开始录制\n", segments.len() - tail, tail);
+            segments.drain(..segments.len() - tail);
+        }
+    }
+    // --from/--to 先按片段时长选出与时间段重叠的片段，片段内多出的部分再由 ffmpeg 裁掉；
+    // 直接拼接 TS 或导出 HLS 时不经过 ffmpeg，只能按片段边界截取
+    let ffmpeg_trims = !args.hls_output && (container != Container::Ts || args.stream_merge || args.emit_concat_only);
+    let mut clip_offset = 0.0;
+    let (mut trim_start, mut trim_end) = (0.0, None);
+    if args.from.is_some() || args.to.is_some() {
+        let total: f64 = segments.iter().map(|s| s.duration).sum();
+        let from = match args.from {
+            None => 0.0,
+            Some(ClipStart::Time(time)) => time,
+            Some(ClipStart::Playlist) => match stream.start_offset {
+                Some(offset) if offset < 0.0 => (total + offset).max(0.0),
+                Some(offset) => offset,
+                None => {
+                    status!("⚠ 播放列表没有 EXT-X-START，从头开始下载");
+                    0.0
+                }
+            },
+        };
+        if args.to.is_some_and(|to| to <= from) {
+            anyhow::bail!("--to must be later than --from");
+        }
+        let (range, first_start) = select_clip(&segments, from, args.to);
+        if range.is_empty() {
+            anyhow::bail!("No segments in the requested time range; the playlist is {:.1} seconds long", total);
+        }
+        status!(
+            "✂  截取 {} - {}：第 {}-{} 个片段，共 {} 个\n",
+            format_duration(Duration::from_secs_f64(from)),
+            args.to.map_or("结尾".to_string(), |to| format_duration(Duration::from_secs_f64(to))),
+            range.start + 1,
+            range.end,
+            range.len()
+        );
+        segments.truncate(range.end);
+        segments.drain(..range.start);
+        clip_offset = first_start;
+        if ffmpeg_trims {
+            // 第一个片段在 from 之前开始，裁掉片段开头多出的部分
+            trim_start = from - first_start;
+            trim_end = args.to.map(|to| to - first_start);
+        } else {
+            status!("ℹ 不经过 ffmpeg 的输出只能按片段边界截取，开头和结尾会多出不到一个片段的内容\n");
+        }
+    }
+
+    // 独立音轨的片段接在视频片段之后一起下载，合并时再分成两路输入。
+    // 时长记为 0，避免进度、预计时间和校验把同一段时间算两遍
+    let mut tracks = ExtraTracks::default();
+    if let Some(audio) = stream.audio {
+        if is_live {
+            status!("⚠ 直播暂不支持独立音轨，将只录制视频 ({} 未下载)\n", audio.label);
+        } else if args.hls_output || args.stream_merge || args.emit_concat_only || args.append || args.refresh_failed {
+            anyhow::bail!(
+                "Separate audio renditions cannot be used with --hls-output, --stream-merge, --emit-concat-only, --append or --refresh-failed"
+            );
+        } else if args.single_file_temp == Some(true) || container == Container::Ts || to_stdout {
+            anyhow::bail!("Separate audio renditions need an ffmpeg mux and cannot be used with --single-file-temp, --format ts or -o -");
+        } else {
+            let mut audio_segments = audio.segments;
+            if clip_offset > 0.0 || args.to.is_some() {
+                // 音频片段的边界与视频不同，按已选视频片段覆盖的时间段选择，多出的开头在合并时跳过
+                let video_end = clip_offset + segments.iter().map(|s| s.duration).sum::<f64>();
+                let (range, first_start) = select_clip(&audio_segments, clip_offset, Some(video_end));
+                audio_segments.truncate(range.end);
+                audio_segments.drain(..range.start);
+                tracks.audio_skip = (clip_offset - first_start).max(0.0);
+            }
+            status!("🔊 独立音轨 {}：{} 个片段，合并时与视频混流\n", audio.label, audio_segments.len());
+            tracks.audio_start = Some(segments.len());
+            segments.extend(audio_segments.into_iter().map(|segment| Segment { duration: 0.0, ..segment }));
+        }
+    }
+    let subtitles = match stream.subtitles {
+        Some(rendition) if is_live => {
+            status!("⚠ 直播暂不支持字幕，已忽略 --subs ({} 未下载)\n", rendition.label);
+            None
+        }
+        subtitles => subtitles,
+    };
+    if let Some(path) = &args.retry_file {
+        let path = expand_path(path);
+        FailedList::read(&path)?.check_matches(&segments, &path)?;
+    }
+    // 补下载失败片段时，临时目录中已有的片段都不再下载
+    let resume = args.resume || args.retry_file.is_some();
+    downloader.register_segments(0, &segments);
+    if let Some(live) = &live {
+        let stop_hint = if args.json || args.no_tui || to_stdout { "" } else { "，按 q 停止录制" };
+        status!("🔴 直播流：将持续刷新播放列表录制新片段{}", stop_hint);
+        if !args.live {
+            status!("   播放列表没有 EXT-X-ENDLIST，自动按直播录制；只下载当前窗口请加 --no-live");
+        }
+        match live.part_target {
+            Some(target) if args.low_latency => status!("⚡ 低延迟 HLS：每 {:.2} 秒刷新一次播放列表 (PART-TARGET)", target),
+            None if args.low_latency => status!("ℹ 播放列表不是低延迟 HLS，按目标时长刷新"),
+            _ => {}
+        }
+        status!();
+    }
+    if args.append && live.is_none() && downloader.output_dir.join(format!("{}.{}", output, container.extension())).exists() {
+        status!("⚠ --append 会把播放列表的全部 {} 个片段追加到已有文件末尾，点播内容重复追加会出现重复\n", segments.len());
+    }
+    if args.preflight_check {
+        status!("🔍 正在检查 {} 个片段的可访问性...", segments.len());
+        let broken = downloader.preflight_check(&segments).await;
+        status!(
+            "  ✓ 可访问: {}  ✗ 不可访问: {}",
+            segments.len() - broken.len(),
+            broken.len()
+        );
+        if !broken.is_empty() {
+            for (i, reason) in broken.iter().take(10) {
+                status!("    {}: {}", downloader.segment_file_name(*i), reason);
+            }
+            if broken.len() > 10 {
+                status!("    ... 以及另外 {} 个", broken.len() - 10);
+            }
+            // --json 供脚本调用，不等待确认
+            if args.json || !confirm("是否仍然继续下载?")? {
+                anyhow::bail!("Preflight check found {} unreachable segments", broken.len());
+            }
+        }
+        status!();
+    }
+
+    let transcode = TranscodeOptions {
+        scale: args.scale.clone(),
+        fps: args.fps.clone(),
+        trim_start,
+        trim_end,
+        reencode: false,
+        allow_reencode: args.allow_reencode,
+    };
+    if container == Container::Ts && !transcode.is_copy() {
+        anyhow::bail!("--scale/--fps require re-encoding and cannot be used with --format ts");
+    }
+    // 只拼接 TS、导出 HLS 或只生成文件列表时用不到 ffmpeg
+    let needs_ffmpeg = !args.hls_output
+        && !args.emit_concat_only
+        && (container != Container::Ts || args.stream_merge);
+    if needs_ffmpeg {
+        check_ffmpeg(&downloader.ffmpeg_path)?;
+    }
+    let ffprobe = match &args.ffprobe_path {
+        Some(path) => expand_path(path),
+        None => default_ffprobe_path(&downloader.ffmpeg_path),
+    };
+    if args.verify && !args.append {
+        check_ffprobe(&ffprobe)?;
+    }
+
+    let init_count = downloader.prefetch_init_segments(&segments).await?;
+    if init_count > 0 {
+        status!("📦 已下载 {} 个初始化片段 (EXT-X-MAP)，合并时列在片段之前\n", init_count);
+        if args.stream_merge || container == Container::Ts {
+            anyhow::bail!("fMP4 segments (EXT-X-MAP) cannot be used with --stream-merge or --format ts");
+        }
+    }
+
+    // 直播的片段总数未知，无法推算
+    let mut disk_estimate = None;
+    if live.is_none() && !segments.is_empty() {
+        match downloader.estimate_total_size(&segments, resume).await {
+            Ok((first_size, estimated)) => {
+                fs::create_dir_all(&downloader.output_dir).await?;
+                let available = fs2::available_space(&downloader.output_dir)?;
+                // 合并时片段与输出文件会同时存在，大约需要两倍空间
+                let merges = !args.hls_output && !args.emit_concat_only && !to_stdout;
+                let required = if merges { estimated * 2 } else { estimated };
+                status!(
+                    "💾 预计大小约 {:.2} GB，输出目录可用 {:.2} GB\n",
+                    gigabytes(estimated),
+                    gigabytes(available)
+                );
+                if required > available {
+                    status!("⚠ 磁盘空间可能不足：下载{}约需 {:.2} GB", if merges { "并合并" } else { "" }, gigabytes(required));
+                    if !args.force {
+                        anyhow::bail!(
+                            "Not enough disk space: about {} bytes needed, {} available (use --force to continue anyway)",
+                            required,
+                            available
+                        );
+                    }
+                    status!();
+                }
+                disk_estimate = Some((first_size, estimated, available));
+            }
+            Err(e) => status!("⚠ 无法下载第一个片段估算大小，跳过磁盘空间检查: {}\n", e),
+        }
+    }
+
+    // --no-overwrite 在下载前就报错；--auto-rename 选出的文件名显示在 Info 面板，合并时会再检查一次
+    let requested_path = downloader.output_dir.join(format!("{}.{}", output, container.extension()));
+    let output_path = if args.append || to_stdout {
+        requested_path.clone()
+    } else {
+        downloader.on_collision.resolve(&requested_path)?
+    };
+    if output_path != requested_path {
+        status!("ℹ 输出文件已存在，改为写入 {}\n", output_path.display());
+    }
+
+    let single_file_temp = match args.single_file_temp {
+        Some(true) if init_count > 0 => anyhow::bail!("fMP4 segments (EXT-X-MAP) cannot be used with --single-file-temp"),
+        Some(enabled) => enabled,
+        None => {
+            auto_single_file_temp(args, segments.len())
+                && init_count == 0
+                && !to_stdout
+                && tracks.audio_start.is_none()
+        }
+    };
+    let single_file_path = downloader.temp_dir.join("segments.ts");
+    let merger = if args.stream_merge {
+        fs::create_dir_all(&downloader.temp_dir).await?;
+        Some(Mutex::new(StreamMerger::spawn(&downloader.ffmpeg_path, &output_path, container, &transcode)?))
+    } else if single_file_temp {
+        if args.single_file_temp.is_none() {
+            status!("ℹ 片段数达到 {}，自动使用 --single-file-temp（--single-file-temp=false 关闭）", SINGLE_FILE_TEMP_MIN_SEGMENTS);
+        }
+        status!("📼 片段将按顺序追加到 {}\n", single_file_path.display());
+        fs::create_dir_all(&downloader.temp_dir).await?;
+        Some(Mutex::new(StreamMerger::to_file(&single_file_path, downloader.write_buffer_size).await?))
+    } else {
+        None
+    };
+
+    let total_duration = segments.iter().map(|s| s.duration).sum();
+    let stats = Arc::new(Mutex::new(DownloadStats::new(segments.len(), total_duration)));
+    {
+        let mut stats = stats.lock().await;
+        stats.live = live.is_some();
+        stats.activity_capacity = args.log_history;
+        stats.batch = batch;
+        stats.discontinuities = count_discontinuities(&segments);
+        stats.transferred = Arc::clone(&downloader.transferred);
+        stats.max_failures = args.max_failures;
+        stats.max_failure_pct = args.max_failure_pct;
+        if let Some((first_size, estimated, available)) = disk_estimate {
+            stats.estimated_size = Some(estimated);
+            stats.available_space = Some(available);
+            // 估算时已下载的第一个片段不再重复下载；--resume 会在下面自行扫描到它
+            if !resume {
+                stats.resume(0, first_size, segments[0].duration);
+            }
+        }
+    }
+    if resume {
+        let (count, bytes) = downloader.resume_existing(&segments, &stats).await?;
+        if count > 0 {
+            status!("♻ 断点续传：{} 个片段已存在 ({})，跳过下载\n", count, format_bytes(bytes));
+        }
+    }
+
+    // 启动 TUI
+    let tui_stats = Arc::clone(&stats);
+    let tui_url = url.to_string();
+    let tui_output = output_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let ui = UiOptions::detect(
+        args.ascii,
+        args.color_depth,
+        args.theme,
+        args.no_color,
+        args.bits,
+        args.progress_by_time,
+        args.hide_panels.clone(),
+    );
+    let theme = ui.theme;
+    let tui_cancel = cancel.clone();
+    // -o - 时标准输出是视频数据，进度改为写到标准错误的单行
+    let plain_handle = (args.no_tui || to_stdout)
+        .then(|| tokio::spawn(run_plain_progress(Arc::clone(&stats), ui.clone(), cancel.clone())));
+    let tui_events = downloader.subscribe();
+    let tui_handle = (!args.json && !args.no_tui && !to_stdout).then(|| {
+        tokio::spawn(async move { run_tui(tui_stats, tui_events, tui_url, tui_output, ui, tui_cancel).await })
+    });
+    let progress_handle = progress_socket
+        .map(|writer| tokio::spawn(run_progress_socket(Arc::clone(&stats), writer, cancel.clone())));
+    // --json 的进度事件与 --progress-socket 格式相同，直接写到标准输出
+    let json_handle = args.json.then(|| {
+        let stdout: Box<dyn std::io::Write + Send> = Box::new(std::io::stdout());
+        tokio::spawn(run_progress_socket(Arc::clone(&stats), stdout, cancel.clone()))
+    });
+    let mut segment_events_handle = args.json.then(|| tokio::spawn(print_segment_events(downloader.subscribe())));
+
+    // 下载
+    let segments = match live {
+        Some(live) => {
+            downloader.record_live(segments, live, Arc::clone(&stats), cancel.clone(), merger.as_ref()).await?
+        }
+        None => {
+            downloader.download_segments(segments.clone(), 0, Arc::clone(&stats), cancel.clone(), merger.as_ref()).await?;
+            segments
+        }
+    };
+
+    // 等待 TUI 完成
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    if let Some(handle) = tui_handle {
+        handle.abort();
+    }
+    // 等最后一个进度事件写出
+    for handle in progress_handle.into_iter().chain(json_handle).chain(plain_handle) {
+        let _ = handle.await;
+    }
+
+    if cancel.is_cancelled() {
+        finish_events(&downloader, segment_events_handle).await;
+        if let Some(merger) = merger {
+            merger.into_inner().abort().await;
+        }
+        let stats = stats.lock().await;
+        if stats.failure_limit_exceeded {
+            status!("✗ 失败片段过多，已停止下载，已完成的片段保留在 {}", downloader.temp_dir.display());
+            anyhow::bail!(
+                "Download aborted: {} of {} segments failed, exceeding the failure limit",
+                stats.failed_segments,
+                stats.total_segments
+            );
+        }
+        status!("⚠ 下载已取消，已完成的片段保留在 {}", downloader.temp_dir.display());
+        return Err(Cancelled::new().into());
+    }
+
+    if let Some(ratio) = args.min_segment_ratio {
+        downloader.redownload_small_segments(&segments, ratio, &stats).await?;
+    }
+
+    if args.refresh_failed {
+        if is_live {
+            status!("⚠ 直播播放列表的窗口会滚动，无法按序号对应失败片段，跳过 --refresh-failed");
+        } else {
+            downloader.retry_failed_with_fresh_urls(&stream.playlist_url, &stats).await;
+        }
+    }
+
+    // 边下载边合并时片段已经写入 ffmpeg，事后重试的片段无法再插回去
+    let allow_partial = if merger.is_none() {
+        match resolve_failed_segments(args, &downloader, &segments, &stats).await {
+            Ok(allow_partial) => allow_partial,
+            Err(e) => {
+                finish_events(&downloader, segment_events_handle.take()).await;
+                status!("⚠ 已放弃合并，已完成的片段保留在 {}", downloader.temp_dir.display());
+                return Err(e);
+            }
+        }
+    } else {
+        args.allow_partial
+    };
+
+    // 边下载边合并或追加到单个临时文件时失败的片段补不回去，与缺失片段一样要 --allow-partial 或确认后才跳过
+    if merger.is_some() && !allow_partial {
+        let failed = stats.lock().await.failed_segments;
+        let skip = failed > 0
+            && !(args.yes || args.json)
+            && confirm(&format!("{} 个片段下载失败且无法再补入，是否跳过它们继续合并?", failed))?;
+        if failed > 0 && !skip {
+            finish_events(&downloader, segment_events_handle.take()).await;
+            if let Some(merger) = merger {
+                merger.into_inner().abort().await;
+            }
+            status!("✗ {} 个片段下载失败，已放弃合并；使用 --allow-partial 可跳过失败的片段继续合并", failed);
+            anyhow::bail!("{} of {} segments failed", failed, segments.len());
+        }
+    }
+
+    finish_events(&downloader, segment_events_handle).await;
+
+    let final_stats = stats.lock().await;
+    if let Some(limit) = final_stats.connection_limit {
+        status!("ℹ 服务器似乎限制为约 {} 个连接，更高的 --concurrent 不会加快下载", limit);
+    }
+    if let Some(level) = final_stats.concurrency_level {
+        status!("ℹ 自动并发最终为 {} 个连接", level);
+    }
+    // 边下载边合并时临时目录里没有片段，--hls-output 会把片段移出临时目录，
+    // 直播的序号会随窗口滚动，都无法按序号补下载
+    let retryable = merger.is_none() && !is_live && !to_stdout && !args.hls_output;
+    let failed_list_path = FailedList::path(&downloader.output_dir, output);
+    if final_stats.failed_segments > 0 {
+        status!("⚠ 警告: {} 个片段下载失败", final_stats.failed_segments);
+        if retryable {
+            let mut indices = final_stats.failed_indices.clone();
+            indices.sort_unstable();
+            let list = FailedList {
+                url: url.to_string(),
+                output: output.to_string(),
+                // 相对路径在别的工作目录下补下载时会找错位置
+                temp_dir: downloader.temp_dir.canonicalize().unwrap_or_else(|_| downloader.temp_dir.clone()),
+                total_segments: segments.len(),
+                failed: indices.into_iter().map(|index| FailedSegment { index, url: segments[index].url.clone() }).collect(),
+            };
+            list.write(&failed_list_path)?;
+            status!("ℹ 失败的片段已记录到 {}，可用 --retry-file 补下载后重新合并", failed_list_path.display());
+        }
+    } else {
+        // 补下载成功后上次的记录已经过时
+        let _ = std::fs::remove_file(&failed_list_path);
+    }
+    // 补下载要用到已完成的片段
+    let keep_temp = args.keep_temp || (retryable && final_stats.failed_segments > 0);
+    if final_stats.lost_segments > 0 {
+        status!(
+            "⚠ 下载跟不上直播，{} 个片段在下载前就滑出了 DVR 窗口，可提高 --concurrent 或网络带宽",
+            final_stats.lost_segments
+        );
+    }
+
+    if let Some(path) = &args.save_chunkmap {
+        let path = batch_item_path(&expand_path(path), batch.is_some().then_some(output));
+        save_chunkmap_png(&final_stats.chunk_states(), &theme, &path)?;
+        status!("✓ 分块状态图: {}", path.display());
+    }
+
+    if let Some(path) = &args.report {
+        let path = batch_item_path(&expand_path(path), batch.is_some().then_some(output));
+        write_report(&final_stats.segment_records, &segments, &path)?;
+        status!("✓ 片段报告: {}", path.display());
+    }
+
+    drop(final_stats);
+
+    // 字幕只是附加内容，下载失败时照常合并视频
+    if let Some(rendition) = &subtitles {
+        status!("💬 正在下载字幕 {}...", rendition.label);
+        match downloader.download_subtitles(&rendition.segments).await {
+            Ok(cues) => {
+                // 只有 ffmpeg 合并 MKV 时才封装进视频，其它情况写到输出文件旁
+                let mux = container == Container::Mkv && merger.is_none() && !args.hls_output && !args.emit_concat_only;
+                // 字幕时间以整个播放列表开头为零点；封装时 ffmpeg 会一并裁剪，旁挂字幕自己对齐到裁剪后的视频
+                let cues = if mux {
+                    shift_cues(cues, clip_offset, None)
+                } else {
+                    shift_cues(cues, clip_offset + transcode.trim_start, transcode.trim_end.map(|end| end - transcode.trim_start))
+                };
+                let (path, content) = if mux {
+                    (downloader.temp_dir.join("subtitles.vtt"), write_vtt(&cues))
+                } else if container == Container::Mp4 {
+                    (output_path.with_extension("srt"), write_srt(&cues))
+                } else {
+                    (output_path.with_extension("vtt"), write_vtt(&cues))
+                };
+                fs::create_dir_all(path.parent().unwrap_or(Path::new("."))).await?;
+                fs::write(&path, content).await?;
+                if mux {
+                    status!("  ✓ {} 条字幕，合并时封装进 MKV\n", cues.len());
+                    tracks.subtitles = Some(path);
+                } else {
+                    status!("  ✓ {} 条字幕: {}\n", cues.len(), path.display());
+                }
+            }
+            Err(e) => status!("  ⚠ 字幕下载失败，跳过: {:#}\n", e),
+        }
+    }
+
+    if args.hls_output {
+        let playlist_path = downloader.export_hls(&segments, output).await?;
+        finish_temp_files(&downloader, keep_temp).await?;
+        if args.json {
+            let size = playlist_path.metadata()?.len();
+            return emit_json_result(&*stats.lock().await, &playlist_path, size, None);
+        }
+        return Ok(());
+    }
+
+    if merger.is_none() {
+        let missing = downloader.temp_files()?.missing_segments(segments.len());
+        if !missing.is_empty() {
+            let list: Vec<String> = missing.iter().take(20).map(|i| i.to_string()).collect();
+            let more = if missing.len() > 20 { format!(" 等 {} 个", missing.len()) } else { String::new() };
+            if !allow_partial {
+                status!("✗ 缺少片段: {}{}", list.join(", "), more);
+                status!("  已完成的片段保留在 {}，使用 --allow-partial 可跳过缺失片段继续合并", downloader.temp_dir.display());
+                anyhow::bail!("{} of {} segments are missing or empty", missing.len(), segments.len());
+            }
+            status!("⚠ 跳过缺失的片段: {}{}", list.join(", "), more);
+        }
+    }
+
+    if args.emit_concat_only {
+        return downloader.emit_concat(output, segments.len(), container, &transcode).await;
+    }
+
+    let output_file = match merger {
+        Some(merger) if single_file_temp => {
+            merger.into_inner().finish().await?;
+            match downloader.merge_single_file(&single_file_path, &output_path, container, &transcode).await {
+                Ok(()) => output_path,
+                Err(e) => {
+                    status!("✗ 合并失败，已下载的数据保留在 {}", single_file_path.display());
+                    return Err(e);
+                }
+            }
+        }
+        Some(merger) => {
+            status!("\n🎬 正在等待 ffmpeg 完成合并...");
+            merger.into_inner().finish().await?;
+            status!("✓ 成功: {}\n", output_path.display());
+            output_path
+        }
+        None => match downloader.merge_to_mp4(output, &segments, &tracks, container, &transcode, args.append).await {
+            Ok(output_path) => output_path,
+            Err(e) => {
+                // 不执行 cleanup，修复问题后可以用 --resume 直接合并
+                status!("✗ 合并失败，已下载的片段保留在 {}", downloader.temp_dir.display());
+                return Err(e);
+            }
+        },
+    };
+    finish_temp_files(&downloader, keep_temp).await?;
+    if to_stdout {
+        if args.verify || args.checksum {
+            status!("⚠ 输出到标准输出时没有输出文件，跳过 --verify 和 --checksum");
+        }
+        return Ok(());
+    }
+
+    if args.verify {
+        if args.append {
+            status!("⚠ 追加模式下无法根据播放列表推算完整时长，跳过时长校验");
+        } else {
+            // 最终失败的片段本来就不在输出里，不计入预期时长
+            let expected = {
+                let stats = stats.lock().await;
+                // --from/--to 裁掉的首尾也不在输出里
+                let trimmed = transcode.trim_start
+                    + transcode.trim_end.map_or(0.0, |end| (stats.total_duration - end).max(0.0));
+                stats.total_duration - stats.failed_duration - trimmed
+            };
+            let actual = probe_duration(&ffprobe, &output_file)?;
+            let tolerance = (expected * 0.02).max(2.0);
+            if (actual - expected).abs() > tolerance {
+                status!(
+                    "⚠ 时长校验失败: 预期 {}，实际 {}，合并时可能丢失了片段",
+                    format_duration(Duration::from_secs_f64(expected)),
+                    format_duration(Duration::from_secs_f64(actual))
+                );
+            } else {
+                status!(
+                    "✓ 时长校验: 预期 {}，实际 {}",
+                    format_duration(Duration::from_secs_f64(expected)),
+                    format_duration(Duration::from_secs_f64(actual))
+                );
+            }
+        }
+    }
+
+    let size = output_file.metadata()?.len();
+    status!("✓ 文件: {}", output_file.display());
+    status!("✓ 大小: {}", format_bytes(size));
+    status!("✓ 网络传输: {}", format_bytes(downloader.transferred.load(Ordering::Relaxed)));
+    let checksum = if args.checksum { Some(sha256_file(&output_file).await?) } else { None };
+    if let Some(checksum) = &checksum {
+        status!("✓ SHA-256: {}", checksum);
+    }
+
+    if args.json {
+        return emit_json_result(&*stats.lock().await, &output_file, size, checksum.as_deref());
+    }
+
+    Ok(())
+}
+
+/// 成功结束后删除临时片段，--keep-temp 时只打印其位置
+async fn finish_temp_files(downloader: &M3U8Downloader, keep_temp: bool) -> Result<()> {
+    if keep_temp {
+        status!("ℹ 临时文件保留在 {}", downloader.temp_dir.display());
+        return Ok(());
+    }
+    downloader.cleanup().await
+}
+
+/// 分块读取文件计算 SHA-256，返回小写十六进制；大文件也不会整个读入内存
+async fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::Digest;
+    use tokio::io::AsyncReadExt;
+
+    let mut file = File::open(path).await?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
-#[derive(Clone, PartialEq)]
-enum ChunkState {
-    Pending,
-    Downloading,
-    Completed,
-    Failed,
+/// `--json` 的最终结果事件；有片段失败时返回错误，进程以非零状态退出
+fn emit_json_result(stats: &DownloadStats, output: &Path, size: u64, sha256: Option<&str>) -> Result<()> {
+    emit_json(serde_json::json!({
+        "event": "result",
+        "output": output,
+        "size": size,
+        "sha256": sha256,
+        "transferred": stats.transferred.load(Ordering::Relaxed),
+        "downloaded": stats.downloaded_segments,
+        "failed": stats.failed_segments,
+        "total": stats.total_segments,
+    }));
+    if stats.failed_segments > 0 {
+        anyhow::bail!("{} of {} segments failed", stats.failed_segments, stats.total_segments);
+    }
+    Ok(())
 }
 
-impl DownloadStats {
-    fn new(total: usize) -> Self {
-        let chunk_count = total.min(100);
-        Self {
-            total_segments: total,
-            downloaded_segments: 0,
-            failed_segments: 0,
-            downloaded_bytes: 0,
-            start_time: Instant::now(),
-            current_speed: 0.0,
-            speed_history: VecDeque::with_capacity(50),
-            chunk_states: vec![ChunkState::Pending; chunk_count],
-            activity_log: VecDeque::with_capacity(6),
-            last_update: Instant::now(),
-            bytes_since_update: 0,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    /// 请求路径 -> (状态码, 响应体)
+    type Routes = HashMap<String, (u16, Vec<u8>)>;
+
+    /// 测试用的极简 HTTP 服务器，按请求路径返回预置的状态码和内容，并记录每个路径的请求次数
+    struct FixtureServer {
+        base_url: String,
+        hits: Arc<std::sync::Mutex<HashMap<String, usize>>>,
     }
 
-    fn update(&mut self, segment_id: usize, bytes: u64, segment_name: String) {
-        self.downloaded_segments += 1;
-        self.downloaded_bytes += bytes;
-        self.bytes_since_update += bytes;
+    impl FixtureServer {
+        async fn start(routes: Routes) -> Self {
+            Self::serve(routes, HashMap::new(), false).await
+        }
 
-        // 添加活动日志
-        self.activity_log.push_back(ActivityItem {
-            name: segment_name,
-            status: ActivityStatus::Success,
-        });
-        if self.activity_log.len() > 6 {
-            self.activity_log.pop_front();
+        /// flaky 中的路径在前 N 次请求时返回 503，之后才按 routes 响应，用于模拟临时故障
+        async fn start_flaky(routes: Routes, flaky: HashMap<String, usize>) -> Self {
+            Self::serve(routes, flaky, false).await
         }
 
-        // 更新速度
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.last_update).as_secs_f64();
-        if elapsed >= 0.25 {
-            self.current_speed = (self.bytes_since_update as f64) / elapsed / (1024.0 * 1024.0);
-            self.speed_history.push_back(self.current_speed);
-            if self.speed_history.len() > 50 {
-                self.speed_history.pop_front();
-            }
-            self.last_update = now;
-            self.bytes_since_update = 0;
+        /// 所有响应都以 Content-Encoding: gzip 压缩发送
+        async fn start_gzipped(routes: Routes) -> Self {
+            Self::serve(routes, HashMap::new(), true).await
+        }
+
+        async fn serve(routes: Routes, flaky: HashMap<String, usize>, gzip: bool) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let routes = Arc::new(routes);
+            let flaky = Arc::new(flaky);
+            let hits = Arc::new(std::sync::Mutex::new(HashMap::new()));
+            let server_hits = Arc::clone(&hits);
+
+            tokio::spawn(async move {
+                loop {
+                    let Ok((mut socket, _)) = listener.accept().await else { break };
+                    let routes = Arc::clone(&routes);
+                    let flaky = Arc::clone(&flaky);
+                    let hits = Arc::clone(&server_hits);
+                    tokio::spawn(async move {
+                        let mut request = Vec::new();
+                        let mut buf = [0u8; 1024];
+                        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            match socket.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        let request = String::from_utf8_lossy(&request);
+                        let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                        let hit = {
+                            let mut hits = hits.lock().unwrap();
+                            let hit = hits.entry(path.clone()).or_insert(0);
+                            *hit += 1;
+                            *hit
+                        };
+                        let (status, body) = if flaky.get(&path).is_some_and(|&failures| hit <= failures) {
+                            (503, b"unavailable".to_vec())
+                        } else {
+                            routes.get(&path).cloned().unwrap_or((404, b"not found".to_vec()))
+                        };
+                        let (body, encoding) = if gzip {
+                            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                            std::io::Write::write_all(&mut encoder, &body).unwrap();
+                            (encoder.finish().unwrap(), "Content-Encoding: gzip\r\n")
+                        } else {
+                            (body, "")
+                        };
+                        let header = format!(
+                            "HTTP/1.1 {} X\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                            status,
+                            encoding,
+                            body.len()
+                        );
+                        let _ = socket.write_all(header.as_bytes()).await;
+                        let _ = socket.write_all(&body).await;
+                        let _ = socket.shutdown().await;
+                    });
+                }
+            });
+
+            Self { base_url: format!("http://{}", addr), hits }
         }
 
-        // 更新分块状态
-        let chunk_id = (segment_id * self.chunk_states.len()) / self.total_segments;
-        if chunk_id < self.chunk_states.len() {
-            self.chunk_states[chunk_id] = ChunkState::Completed;
+        fn url(&self, path: &str) -> String {
+            format!("{}{}", self.base_url, path)
+        }
+
+        fn hits(&self, path: &str) -> usize {
+            self.hits.lock().unwrap().get(path).copied().unwrap_or(0)
         }
     }
 
-    fn fail(&mut self, segment_id: usize, segment_name: String) {
-        self.failed_segments += 1;
+    fn ffmpeg_available() -> bool {
+        Command::new("ffmpeg")
+            .arg("-version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
 
-        self.activity_log.push_back(ActivityItem {
-            name: segment_name,
-            status: ActivityStatus::Failed,
-        });
-        if self.activity_log.len() > 6 {
-            self.activity_log.pop_front();
+    /// 有 ffmpeg 时生成真实可合并的 TS 片段，否则用只含同步字节的占位数据
+    fn fixture_segment(index: usize) -> Vec<u8> {
+        if ffmpeg_available() {
+            let path = std::env::temp_dir().join(format!(
+                "surge-wave-fixture-{}-{}.ts",
+                std::process::id(),
+                index
+            ));
+            let status = Command::new("ffmpeg")
+                .args(["-f", "lavfi", "-i", "testsrc=duration=1:size=64x64:rate=10"])
+                .args(["-c:v", "mpeg2video", "-f", "mpegts", "-y"])
+                .arg(&path)
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            bytes
+        } else {
+            let mut packet = [0xFFu8; 188];
+            packet[0] = 0x47;
+            packet[3] = index as u8;
+            packet.repeat(4)
         }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("surge-wave-{}-{}", name, std::process::id()))
+    }
 
-        let chunk_id = (segment_id * self.chunk_states.len()) / self.total_segments;
-        if chunk_id < self.chunk_states.len() {
-            self.chunk_states[chunk_id] = ChunkState::Failed;
+    fn test_downloader(url: String, dir: PathBuf) -> M3U8Downloader {
+        M3U8Downloader::new(url, dir, DownloadOptions { concurrent_limit: 4, ..Default::default() })
+    }
+
+    /// 主播放列表 + 媒体播放列表 + 3 个片段
+    fn fixture_routes() -> (Routes, Vec<Vec<u8>>) {
+        let master = "#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=200000,RESOLUTION=64x64\n\
+            low/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=128x128\n\
+            high/index.m3u8\n";
+        let media = "#EXTM3U\n\
+            #EXT-X-VERSION:3\n\
+            #EXT-X-TARGETDURATION:2\n\
+            #EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXTINF:1.5,\nseg0.ts\n\
+            #EXTINF:1.5,\nseg1.ts\n\
+            #EXTINF:1.0,\nseg2.ts\n\
+            #EXT-X-ENDLIST\n";
+
+        let segments: Vec<Vec<u8>> = (0..3).map(fixture_segment).collect();
+        let mut routes = HashMap::new();
+        routes.insert("/master.m3u8".to_string(), (200, master.as_bytes().to_vec()));
+        routes.insert("/high/index.m3u8".to_string(), (200, media.as_bytes().to_vec()));
+        for (i, body) in segments.iter().enumerate() {
+            routes.insert(format!("/high/seg{}.ts", i), (200, body.clone()));
         }
+        (routes, segments)
     }
 
-    fn progress_percent(&self) -> f64 {
-        if self.total_segments > 0 {
-            (self.downloaded_segments as f64 / self.total_segments as f64) * 100.0
-        } else {
-            0.0
+    #[tokio::test]
+    async fn failure_limit_in_one_batch_item_does_not_cancel_the_next() {
+        let (mut routes, _) = fixture_routes();
+        let broken = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:2\n\
+            #EXTINF:1.5,\n../high/seg0.ts\n\
+            #EXTINF:1.5,\nmissing.ts\n\
+            #EXTINF:1.0,\n../high/seg2.ts\n\
+            #EXT-X-ENDLIST\n";
+        routes.insert("/broken/index.m3u8".to_string(), (200, broken.as_bytes().to_vec()));
+        let server = FixtureServer::start(routes).await;
+        let dir = test_dir("batch-max-failures");
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let args = Args::try_parse_from([
+            "surge-wave", "--batch", "unused", "--format", "ts", "--no-tui", "--retries", "0", "--max-failures", "0",
+        ])
+        .unwrap();
+        let items = [
+            (server.url("/broken/index.m3u8"), "first".to_string()),
+            (server.url("/high/index.m3u8"), "second".to_string()),
+        ];
+        let options = DownloadOptions { retries: 0, ..Default::default() };
+        let cancel = CancellationToken::new();
+        let error = download_batch(&args, &items, dir.clone(), options, cancel.clone()).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "1 of 2 batch items failed: first");
+        assert!(!cancel.is_cancelled());
+        assert!(!dir.join("first.ts").exists());
+        assert!(dir.join("second.ts").exists());
+        let _ = fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn downloads_fixture_playlist_end_to_end() {
+        let (routes, fixture_segments) = fixture_routes();
+        let server = FixtureServer::start(routes).await;
+        let dir = test_dir("e2e");
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let downloader = test_downloader(server.url("/master.m3u8"), dir.clone());
+        let stream = downloader.fetch_m3u8().await.unwrap();
+        let urls: Vec<&str> = stream.segments.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, [
+            server.url("/high/seg0.ts"),
+            server.url("/high/seg1.ts"),
+            server.url("/high/seg2.ts"),
+        ]);
+        let total_duration: f64 = stream.segments.iter().map(|s| s.duration).sum();
+        assert!((total_duration - 4.0).abs() < 1e-6);
+
+        let stats = Arc::new(Mutex::new(DownloadStats::new(stream.segments.len(), total_duration)));
+        let downloader = Arc::new(downloader);
+        downloader
+            .download_segments(stream.segments.clone(), 0, Arc::clone(&stats), CancellationToken::new(), None)
+            .await
+            .unwrap();
+
+        let expected_bytes: usize = fixture_segments.iter().map(Vec::len).sum();
+        {
+            let stats = stats.lock().await;
+            assert_eq!(stats.downloaded_segments, 3);
+            assert_eq!(stats.failed_segments, 0);
+            assert_eq!(stats.downloaded_bytes, expected_bytes as u64);
+        }
+        // 网络传输还包括主播放列表和媒体播放列表
+        assert!(downloader.transferred.load(Ordering::Relaxed) > expected_bytes as u64);
+        for (i, body) in fixture_segments.iter().enumerate() {
+            assert_eq!(&fs::read(downloader.segment_path(i).unwrap()).await.unwrap(), body);
+        }
+
+        if ffmpeg_available() {
+            let output = downloader
+                .merge_to_mp4("fixture", &stream.segments, &ExtraTracks::default(), Container::Mp4, &TranscodeOptions::default(), false)
+                .await
+                .unwrap();
+            assert!(output.metadata().unwrap().len() > 0);
         }
+
+        fs::remove_dir_all(&dir).await.unwrap();
     }
 
-    fn average_speed(&self) -> f64 {
-        let elapsed = self.start_time.elapsed().as_secs_f64();
-        if elapsed > 0.0 {
-            (self.downloaded_bytes as f64) / elapsed / (1024.0 * 1024.0)
-        } else {
-            0.0
+    #[tokio::test]
+    async fn single_file_temp_appends_segments_in_order() {
+        let dir = test_dir("single-file");
+        fs::create_dir_all(&dir).await.unwrap();
+        for (i, body) in ["aa", "bb", "cc", "dd"].iter().enumerate() {
+            fs::write(dir.join(format!("{}.ts", i)), body).await.unwrap();
+        }
+
+        let output = dir.join("segments.ts");
+        let mut merger = StreamMerger::to_file(&output, 4).await.unwrap();
+        merger.complete(2, Some(dir.join("2.ts"))).await;
+        merger.complete(0, Some(dir.join("0.ts"))).await;
+        // 片段 1 最终失败，跳过后继续写 2 和 3
+        merger.complete(1, None).await;
+        merger.complete(3, Some(dir.join("3.ts"))).await;
+        merger.finish().await.unwrap();
+
+        assert_eq!(fs::read(&output).await.unwrap(), b"aaccdd");
+        assert!(!dir.join("0.ts").exists());
+        fs::remove_dir_all(&dir).await.unwrap();
+
+        // 片段数达到阈值时自动开启；重新开始时会清空 segments.ts，不能与 --resume 同用
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(["surge-wave", "https://example.com/a.m3u8", "-o", "out"].iter().chain(extra))
+        };
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.single_file_temp, None);
+        assert!(auto_single_file_temp(&args, SINGLE_FILE_TEMP_MIN_SEGMENTS));
+        assert!(!auto_single_file_temp(&args, SINGLE_FILE_TEMP_MIN_SEGMENTS - 1));
+        for extra in ["--resume", "--append", "--stream-merge"] {
+            assert!(!auto_single_file_temp(&parse(&[extra]).unwrap(), 5000), "{}", extra);
         }
+        assert_eq!(parse(&["--single-file-temp"]).unwrap().single_file_temp, Some(true));
+        assert_eq!(parse(&["--single-file-temp=false"]).unwrap().single_file_temp, Some(false));
+        assert!(parse(&["--single-file-temp", "--resume"]).is_err());
     }
 
-    fn elapsed_time(&self) -> Duration {
-        self.start_time.elapsed()
+    #[tokio::test]
+    async fn playlist_fetch_retries_only_transient_failures() {
+        let mut routes = HashMap::new();
+        routes.insert("/busy.m3u8".to_string(), (503, b"busy".to_vec()));
+        let server = FixtureServer::start(routes).await;
+        let options = DownloadOptions { retries: 2, retry_delay: Duration::from_millis(1), ..Default::default() };
+        let downloader = M3U8Downloader::new(server.url("/busy.m3u8"), test_dir("playlist-retry"), options);
+
+        let error = downloader.fetch_playlist_text(&server.url("/busy.m3u8")).await.unwrap_err();
+        assert!(error.to_string().contains("after 3 attempt(s)"), "{:#}", error);
+        let error = downloader.fetch_playlist_text(&server.url("/missing.m3u8")).await.unwrap_err();
+        assert!(error.to_string().contains("after 1 attempt(s)"), "{:#}", error);
     }
 
-    fn eta(&self) -> Option<Duration> {
-        if self.average_speed() > 0.0 && self.downloaded_segments > 0 {
-            let remaining = self.total_segments - self.downloaded_segments;
-            let avg_size = self.downloaded_bytes as f64 / self.downloaded_segments as f64;
-            let eta_seconds = (remaining as f64 * avg_size) / (self.average_speed() * 1024.0 * 1024.0);
-            Some(Duration::from_secs_f64(eta_seconds))
-        } else {
-            None
+    #[tokio::test]
+    async fn retries_segments_on_injected_failures() {
+        let (routes, fixture_segments) = fixture_routes();
+        // seg1 在重试次数内恢复，seg2 一直失败
+        let flaky = HashMap::from([("/high/seg1.ts".to_string(), 2), ("/high/seg2.ts".to_string(), usize::MAX)]);
+        let server = FixtureServer::start_flaky(routes, flaky).await;
+        let dir = test_dir("flaky");
+        let options = DownloadOptions {
+            concurrent_limit: 4,
+            retries: 2,
+            retry_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let downloader = Arc::new(M3U8Downloader::new(server.url("/high/index.m3u8"), dir.clone(), options));
+        let stream = downloader.fetch_m3u8().await.unwrap();
+        let stats = Arc::new(Mutex::new(DownloadStats::new(stream.segments.len(), 0.0)));
+        downloader
+            .download_segments(stream.segments, 0, Arc::clone(&stats), CancellationToken::new(), None)
+            .await
+            .unwrap();
+
+        {
+            let stats = stats.lock().await;
+            assert_eq!(stats.downloaded_segments, 2);
+            assert_eq!(stats.failed_segments, 1);
+            assert_eq!(stats.downloaded_bytes, (fixture_segments[0].len() + fixture_segments[1].len()) as u64);
         }
+        assert_eq!(server.hits("/high/seg0.ts"), 1);
+        assert_eq!(server.hits("/high/seg1.ts"), 3);
+        // 首次请求加 2 次重试
+        assert_eq!(server.hits("/high/seg2.ts"), 3);
+
+        fs::remove_dir_all(&dir).await.unwrap();
     }
-}
 
-fn draw_ui(f: &mut Frame, stats: &DownloadStats, url: &str, output: &str) {
-    let size = f.size();
+    #[test]
+    fn segment_files_keep_their_original_extension() {
+        let segment = |url: &str, init: bool| Segment {
+            url: url.to_string(),
+            duration: 4.0,
+            discontinuity: false,
+            byte_range: None,
+            init: init.then(|| InitSegment { url: "https://example.com/init.mp4".to_string(), byte_range: None }),
+        };
+        let segments = [
+            segment("https://example.com/v/seg0.M4S?token=a.ts", true),
+            segment("https://example.com/a/chunk-1.aac", false),
+            segment("https://example.com/v/chunk", true),
+            segment("https://example.com/v/chunk.php", false),
+            segment("data:video/mp2t;base64,RwARECAAAAA=", false),
+        ];
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), test_dir("extensions"));
+        downloader.register_segments(0, &segments);
 
-    // 主布局：顶部Logo + 主体
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Length(3),  // Logo
-            Constraint::Min(0),     // Main
-        ])
-        .split(size);
+        let names: Vec<String> = (0..6).map(|i| downloader.segment_file_name(i)).collect();
+        assert_eq!(
+            names,
+            ["segment_00000.m4s", "segment_00001.aac", "segment_00002.m4s", "segment_00003.ts", "segment_00004.ts", "segment_00005.ts"]
+        );
+    }
 
-    // Logo
-    let logo = Paragraph::new(Line::from(vec![
-        Span::styled("S", Style::default().fg(COLOR_NEON_PURPLE).add_modifier(Modifier::BOLD)),
-        Span::styled("U", Style::default().fg(COLOR_NEON_PINK).add_modifier(Modifier::BOLD)),
-        Span::styled("R", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)),
-        Span::styled("G", Style::default().fg(COLOR_NEON_PURPLE).add_modifier(Modifier::BOLD)),
-        Span::styled("E", Style::default().fg(COLOR_NEON_PINK).add_modifier(Modifier::BOLD)),
-        Span::styled(" M3U8 ", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)),
-        Span::styled("Quad", Style::default().fg(COLOR_GRAY).add_modifier(Modifier::ITALIC)),
-    ]))
-    .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(COLOR_NEON_CYAN)))
-    .alignment(ratatui::layout::Alignment::Center);
-    f.render_widget(logo, chunks[0]);
+    #[test]
+    fn output_template_expands_variables_and_drops_the_extension() {
+        let vars = TemplateVars {
+            title: "movie",
+            resolution: Some("1280x720"),
+            date: utc_date(1_700_000_000),
+            index: 3,
+            ext: "mp4",
+        };
+        assert_eq!(vars.date, "2023-11-14");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+        assert_eq!(expand_output_template("{title}-{resolution}-{date}.{ext}", &vars), "movie-1280x720-2023-11-14");
+        assert_eq!(expand_output_template("{index}_{title}", &vars), "3_movie");
+        assert_eq!(expand_output_template("{title}.{ext}.bak", &vars), "movie.mp4.bak");
+        assert_eq!(
+            expand_output_template("{resolution}", &TemplateVars { resolution: None, ..vars }),
+            "unknown"
+        );
 
-    // 主体：上下分
-    let main_chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([
-            Constraint::Percentage(50),  // Top
-            Constraint::Percentage(50),  // Bottom
-        ])
-        .split(chunks[1]);
+        assert!(parse_output_template("{title}-{height}").unwrap_err().contains("{height}"));
+        assert!(parse_output_template("{title").is_err());
+        assert_eq!(playlist_title("https://example.com/show/ep1.m3u8?token=a"), "ep1");
+        assert_eq!(playlist_title("https://example.com/"), "video");
+    }
 
-    // 上排：Info(30%) + Graph(70%)
-    let top_chunks = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([
-            Constraint::Percentage(30),
-            Constraint::Percentage(70),
-        ])
-        .split(main_chunks[0]);
+    #[tokio::test]
+    async fn aborting_on_failed_segments_returns_an_error() {
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), test_dir("abort"));
+        let stats = Mutex::new(DownloadStats::new(2, 2.0));
+        stats.lock().await.fail(1, 1.0, "segment_00001.ts".to_string());
+        let parse = |action: &str| {
+            Args::try_parse_from(["surge-wave", "https://example.com/a.m3u8", "-o", "out", "--yes", "--on-failure", action])
+                .unwrap()
+        };
 
-    // 下排：Activity(30%) + Stats(20%) + ChunkMap(50%)
-    let bottom_chunks = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([
-            Constraint::Percentage(30),
-            Constraint::Percentage(20),
-            Constraint::Percentage(50),
-        ])
-        .split(main_chunks[1]);
+        // 放弃时以错误返回，进程不会以 0 退出
+        let error = resolve_failed_segments(&parse("abort"), &downloader, &[], &stats).await.unwrap_err();
+        assert_eq!(error.to_string(), "Merge aborted, 1 segments failed");
+        // merge 只有配合 --allow-partial 才跳过缺失的片段
+        assert!(!resolve_failed_segments(&parse("merge"), &downloader, &[], &stats).await.unwrap());
+    }
 
-    // Info Panel
-    draw_info_panel(f, top_chunks[0], stats, url, output);
+    #[test]
+    fn failed_list_round_trips_for_retry_file() {
+        let dir = test_dir("failed-list");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = FailedList::path(&dir, "movie");
+        assert_eq!(path.file_name().unwrap(), "movie.failed.json");
 
-    // Speed Graph Panel
-    draw_graph_panel(f, top_chunks[1], stats);
+        let list = FailedList {
+            url: "https://example.com/index.m3u8".to_string(),
+            output: "movie".to_string(),
+            temp_dir: dir.join("temp"),
+            total_segments: 10,
+            failed: vec![
+                FailedSegment { index: 3, url: "https://example.com/seg3.ts".to_string() },
+                FailedSegment { index: 7, url: "https://example.com/seg7.ts".to_string() },
+            ],
+        };
+        list.write(&path).unwrap();
+        let read = FailedList::read(&path).unwrap();
+        assert_eq!(read.url, list.url);
+        assert_eq!(read.temp_dir, list.temp_dir);
+        assert_eq!(read.total_segments, 10);
+        assert_eq!(read.failed.iter().map(|s| s.index).collect::<Vec<_>>(), [3, 7]);
+        assert_eq!(read.failed[1].url, "https://example.com/seg7.ts");
 
-    // Activity Panel
-    draw_activity_panel(f, bottom_chunks[0], stats);
+        // 片段数或失败片段的地址变了就不能按序号补下载
+        let mut segments: Vec<Segment> = (0..10)
+            .map(|i| Segment {
+                url: format!("https://example.com/seg{}.ts", i),
+                duration: 1.0,
+                discontinuity: false,
+                byte_range: None,
+                init: None,
+            })
+            .collect();
+        read.check_matches(&segments, &path).unwrap();
+        segments[7].url = "https://example.com/low/seg7.ts".to_string();
+        let error = read.check_matches(&segments, &path).unwrap_err().to_string();
+        assert!(error.starts_with("Segment 8 in the playlist no longer matches"), "{}", error);
+        assert!(read.check_matches(&segments[..9], &path).is_err());
 
-    // Stats Panel
-    draw_stats_panel(f, bottom_chunks[1], stats);
+        // --retry-file 代替 URL 和 -o
+        let args = Args::try_parse_from(["surge-wave", "--retry-file", path.to_str().unwrap()]).unwrap();
+        assert!(args.url.is_none() && args.output.is_none());
+        assert!(Args::try_parse_from(["surge-wave", "--retry-file", "x.json", "-o", "out"]).is_err());
 
-    // Chunk Map Panel
-    draw_chunkmap_panel(f, bottom_chunks[2], stats);
-}
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-fn draw_info_panel(f: &mut Frame, area: Rect, stats: &DownloadStats, url: &str, output: &str) {
-    let url_display = if url.len() > 25 {
-        format!("{}...", &url[..22])
-    } else {
-        url.to_string()
-    };
+    #[test]
+    fn rejects_encrypted_playlists_with_the_method_name() {
+        let keyed = |method, keyformat: Option<&str>| m3u8_rs::MediaPlaylist {
+            segments: vec![
+                m3u8_rs::MediaSegment { uri: "clear.ts".to_string(), ..Default::default() },
+                m3u8_rs::MediaSegment {
+                    uri: "locked.ts".to_string(),
+                    key: Some(m3u8_rs::Key { method, keyformat: keyformat.map(str::to_string), ..Default::default() }),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
 
-    let progress_bar_width = 20;
-    let filled = (stats.progress_percent() / 5.0) as usize;
-    let progress_bar = format!("{}{}",
-        "█".repeat(filled.min(progress_bar_width)),
-        "░".repeat(progress_bar_width.saturating_sub(filled))
-    );
+        assert!(check_encryption(&keyed(m3u8_rs::KeyMethod::None, None)).is_ok());
+        let error = check_encryption(&keyed(m3u8_rs::KeyMethod::AES128, None)).unwrap_err().to_string();
+        assert_eq!(error, "Unsupported encryption method: AES-128; supported methods: NONE");
+        let error = check_encryption(&keyed(m3u8_rs::KeyMethod::SampleAES, Some("com.apple.streamingkeydelivery")))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("SAMPLE-AES (FairPlay DRM)"), "{}", error);
+    }
 
-    let text = vec![
-        Line::from(vec![
-            Span::styled("URL: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::raw(url_display),
-        ]),
-        Line::from(vec![
-            Span::styled("Output: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::raw(format!("{}.mp4", output)),
-        ]),
-        Line::from(""),
-        Line::from(vec![
-            Span::styled("Progress: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::styled(progress_bar, Style::default().fg(COLOR_NEON_PINK)),
-            Span::raw(format!(" {:.1}%", stats.progress_percent())),
-        ]),
-        Line::from(vec![
-            Span::styled("Segments: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::styled(
-                format!("{}", stats.downloaded_segments),
-                Style::default().fg(COLOR_COMPLETED)
-            ),
-            Span::raw("/"),
-            Span::raw(format!("{}", stats.total_segments)),
-            if stats.failed_segments > 0 {
-                Span::styled(
-                    format!(" ({}✗)", stats.failed_segments),
-                    Style::default().fg(COLOR_FAILED)
-                )
-            } else {
-                Span::raw("")
-            },
-        ]),
-    ];
+    #[test]
+    fn finds_ffprobe_next_to_ffmpeg() {
+        assert_eq!(default_ffprobe_path(Path::new("ffmpeg")), PathBuf::from("ffprobe"));
+        assert_eq!(default_ffprobe_path(Path::new("/opt/ffmpeg/bin/ffmpeg")), PathBuf::from("/opt/ffmpeg/bin/ffprobe"));
+        assert_eq!(default_ffprobe_path(Path::new("tools/ffmpeg.exe")), PathBuf::from("tools/ffprobe.exe"));
+        assert_eq!(default_ffprobe_path(Path::new("/usr/bin/avconv")), PathBuf::from("/usr/bin/ffprobe"));
+    }
 
-    let paragraph = Paragraph::new(text)
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(COLOR_NEON_PINK))
-                .title(Span::styled("Info", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)))
+    #[test]
+    fn concat_list_entries_survive_spaces_quotes_and_extended_paths() {
+        assert_eq!(
+            concat_list_entry(Path::new("/tmp/my videos/it's here/segment_00000.ts")),
+            "file '/tmp/my videos/it'\\''s here/segment_00000.ts'\n"
         );
-    f.render_widget(paragraph, area);
-}
+        assert_eq!(
+            concat_list_entry(Path::new(r"\\?\C:\Users\a b\segment_00000.ts")),
+            "file 'C:\\Users\\a b\\segment_00000.ts'\n"
+        );
+        assert_eq!(
+            concat_list_entry(Path::new(r"\\?\UNC\nas\share\segment_00000.ts")),
+            "file '\\\\nas\\share\\segment_00000.ts'\n"
+        );
+    }
 
-fn draw_graph_panel(f: &mut Frame, area: Rect, stats: &DownloadStats) {
-    let max_speed = stats.speed_history.iter().cloned().fold(0.0f64, f64::max).max(1.0);
-    let avg_speed = stats.average_speed();
+    #[tokio::test]
+    async fn decodes_gzipped_playlists_and_segments() {
+        let (routes, fixture_segments) = fixture_routes();
+        let server = FixtureServer::start_gzipped(routes).await;
+        let dir = test_dir("gzip");
+        let downloader = Arc::new(test_downloader(server.url("/high/index.m3u8"), dir.clone()));
+        let stream = downloader.fetch_m3u8().await.unwrap();
+        assert_eq!(stream.segments.len(), fixture_segments.len());
 
-    let mut lines = vec![
-        Line::from(vec![
-            Span::styled("▼ Speed  ", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)),
-            Span::styled(format!("Peak: {:.2} MB/s  ", max_speed), Style::default().fg(COLOR_NEON_PINK)),
-            Span::styled(format!("Avg: {:.2} MB/s", avg_speed), Style::default().fg(COLOR_NEON_PURPLE)),
-        ]),
-    ];
+        let stats = Arc::new(Mutex::new(DownloadStats::new(stream.segments.len(), 0.0)));
+        downloader
+            .download_segments(stream.segments, 0, Arc::clone(&stats), CancellationToken::new(), None)
+            .await
+            .unwrap();
 
-    // 绘制速度图表
-    let graph_height = (area.height as usize).saturating_sub(4).max(6);
-    let graph_width = (area.width as usize).saturating_sub(4).max(20);
+        assert_eq!(stats.lock().await.downloaded_segments, fixture_segments.len());
+        for (i, expected) in fixture_segments.iter().enumerate() {
+            assert_eq!(&fs::read(downloader.segment_path(i).unwrap()).await.unwrap(), expected);
+        }
 
-    let block_chars = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 
-    for row in (0..graph_height).rev() {
-        let threshold = ((row + 1) as f64 / graph_height as f64) * max_speed;
-        let mut line_spans = Vec::new();
+    #[tokio::test]
+    async fn lists_variants_with_durations() {
+        let (routes, _) = fixture_routes();
+        let server = FixtureServer::start(routes).await;
+        let downloader = test_downloader(server.url("/master.m3u8"), test_dir("formats"));
 
-        let points: Vec<f64> = stats.speed_history.iter().cloned().collect();
-        let display_points = if points.len() > graph_width {
-            &points[points.len() - graph_width..]
-        } else {
-            &points[..]
+        let infos = downloader.list_variants().await.unwrap();
+        let bandwidths: Vec<u64> = infos.iter().map(|info| info.variant.bandwidth).collect();
+        assert_eq!(bandwidths, [800000, 200000]);
+        assert_eq!(infos[0].duration, Some(4.0));
+        assert_eq!(infos[0].segments, Some(3));
+        // fixture 中没有 low/index.m3u8，仍然列出但没有时长
+        assert_eq!(infos[1].duration, None);
+    }
+
+    #[test]
+    fn samples_spread_across_the_playlist() {
+        assert_eq!(sample_indices(100, 3), [0, 49, 99]);
+        assert_eq!(sample_indices(2, 3), [0, 1]);
+        assert_eq!(sample_indices(5, 1), [0]);
+        assert!(sample_indices(0, 3).is_empty());
+    }
+
+    #[test]
+    fn part_file_sits_next_to_the_output() {
+        assert_eq!(part_path(Path::new("out/video.mp4")), PathBuf::from("out/video.mp4.part"));
+        assert_eq!(part_path(Path::new("video")), PathBuf::from("video.part"));
+    }
+
+    #[tokio::test]
+    async fn failed_ts_concat_leaves_no_output_file() {
+        let dir = test_dir("concat-fail");
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), dir.clone());
+        fs::create_dir_all(&downloader.temp_dir).await.unwrap();
+        fs::write(downloader.segment_path(0).unwrap(), [0x47; 188]).await.unwrap();
+        // 目录能打开但读不出内容，拼接到一半失败
+        fs::create_dir_all(downloader.segment_path(1).unwrap()).await.unwrap();
+
+        let output = dir.join("video.ts");
+        assert!(downloader.concat_ts(&output, 2, false).await.is_err());
+        assert!(!output.exists());
+        assert!(!part_path(&output).exists());
+
+        // 追加失败时截回原来的内容
+        fs::write(&output, b"old").await.unwrap();
+        assert!(downloader.concat_ts(&output, 2, true).await.is_err());
+        assert_eq!(fs::read(&output).await.unwrap(), b"old");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn selects_audio_rendition_by_language_then_default() {
+        let audio = |group: &str, language: &str, default: bool, uri: Option<&str>| m3u8_rs::AlternativeMedia {
+            media_type: m3u8_rs::AlternativeMediaType::Audio,
+            group_id: group.to_string(),
+            name: language.to_string(),
+            language: Some(language.to_string()),
+            default,
+            uri: uri.map(str::to_string),
+            ..Default::default()
+        };
+        let alternatives = [
+            audio("aac", "en", false, Some("en.m3u8")),
+            audio("aac", "zh-Hans", true, Some("zh.m3u8")),
+            audio("other", "fr", true, Some("fr.m3u8")),
+            audio("muxed", "en", true, None),
+        ];
+        let uri = |lang| {
+            select_rendition(&alternatives, AlternativeMediaType::Audio, "aac", lang).and_then(|media| media.uri.as_deref())
         };
 
-        for &speed in display_points {
-            if speed >= threshold {
-                let ratio = (speed - (threshold - max_speed / graph_height as f64)) / (max_speed / graph_height as f64);
-                let block_idx = (ratio * 8.0) as usize;
-                let block_idx = block_idx.min(8);
-                let ch = block_chars[block_idx];
+        assert_eq!(uri(None), Some("zh.m3u8"));
+        assert_eq!(uri(Some("EN")), Some("en.m3u8"));
+        assert_eq!(uri(Some("zh")), Some("zh.m3u8"));
+        // 组内没有该语言时退回默认音轨，不跨组选择
+        assert_eq!(uri(Some("fr")), Some("zh.m3u8"));
+        // 没有 URI 的音轨已经封装在视频里
+        assert!(select_rendition(&alternatives, AlternativeMediaType::Audio, "muxed", None).is_none());
+    }
 
-                let color = if speed > max_speed * 0.7 {
-                    COLOR_NEON_PINK
-                } else if speed > max_speed * 0.4 {
-                    COLOR_NEON_PURPLE
-                } else {
-                    COLOR_NEON_CYAN
-                };
+    #[test]
+    fn merges_vtt_segments_onto_one_timeline() {
+        let segments = [
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n1\n00:00:01.000 --> 00:00:03.500 line:90%\n<c.yellow>Hello</c> &amp; <i>welcome</i>\n\n00:00:05.000 --> 00:00:07.000\nSpans the boundary\n".to_string(),
+            "WEBVTT\r\nX-TIMESTAMP-MAP=LOCAL:00:00:10.000,MPEGTS:1800000\r\n\r\n00:00:05.000 --> 00:00:07.000\r\nSpans the boundary\r\n\r\n00:11.250 --> 00:12.000\r\nSecond\r\n".to_string(),
+        ];
+        let cues = merge_vtt_segments(&segments);
+        // 第二个片段的映射与第一个等价，跨边界重复出现的字幕只保留一次
+        assert_eq!(cues.len(), 3);
+        assert_eq!((cues[2].start, cues[2].end), (11.25, 12.0));
 
-                line_spans.push(Span::styled(ch.to_string(), Style::default().fg(color)));
-            } else {
-                line_spans.push(Span::raw(" "));
-            }
+        assert_eq!(
+            write_srt(&cues[..1]),
+            "1\n00:00:01,000 --> 00:00:03,500\nHello & <i>welcome</i>\n\n"
+        );
+        assert!(write_vtt(&cues).starts_with("WEBVTT\n\n00:00:01.000 --> 00:00:03.500\n<c.yellow>Hello</c>"));
+    }
+
+    #[tokio::test]
+    async fn resume_skips_existing_segments() {
+        let (routes, fixture_segments) = fixture_routes();
+        let server = FixtureServer::start(routes).await;
+        let dir = test_dir("resume");
+        let downloader = test_downloader(server.url("/master.m3u8"), dir.clone());
+        let stream = downloader.fetch_m3u8().await.unwrap();
+
+        // 上次运行留下的片段 1，以及一个写到一半就中断的空文件
+        fs::create_dir_all(&downloader.temp_dir).await.unwrap();
+        fs::write(downloader.segment_path(1).unwrap(), b"previous run").await.unwrap();
+        fs::write(downloader.segment_path(2).unwrap(), b"").await.unwrap();
+
+        let stats = Arc::new(Mutex::new(DownloadStats::new(stream.segments.len(), 0.0)));
+        let (count, bytes) = downloader.resume_existing(&stream.segments, &stats).await.unwrap();
+        assert_eq!((count, bytes), (1, 12));
+        assert_eq!(stats.lock().await.downloaded_segments, 1);
+
+        let downloader = Arc::new(downloader);
+        downloader
+            .download_segments(stream.segments, 0, Arc::clone(&stats), CancellationToken::new(), None)
+            .await
+            .unwrap();
+
+        let stats = stats.lock().await;
+        assert_eq!(stats.downloaded_segments, 3);
+        assert_eq!(stats.failed_segments, 0);
+        assert_eq!(&fs::read(downloader.segment_path(1).unwrap()).await.unwrap(), b"previous run");
+        assert_eq!(&fs::read(downloader.segment_path(2).unwrap()).await.unwrap(), &fixture_segments[2]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_missing_segments_as_failed() {
+        let (mut routes, _) = fixture_routes();
+        routes.remove("/high/seg1.ts");
+        let server = FixtureServer::start(routes).await;
+        let dir = test_dir("missing");
+        fs::create_dir_all(&dir).await.unwrap();
+
+        let downloader = test_downloader(server.url("/high/index.m3u8"), dir.clone());
+        let stream = downloader.fetch_m3u8().await.unwrap();
+        let stats = Arc::new(Mutex::new(DownloadStats::new(stream.segments.len(), 0.0)));
+        let downloader = Arc::new(downloader);
+        downloader
+            .download_segments(stream.segments, 0, Arc::clone(&stats), CancellationToken::new(), None)
+            .await
+            .unwrap();
+
+        let stats = stats.lock().await;
+        assert_eq!(stats.failed_segments, 1);
+        assert_eq!(stats.downloaded_segments, 2);
+        assert_eq!(stats.failed_indices, [1]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn decodes_base64_data_url() {
+        let bytes = decode_data_url("data:video/mp2t;base64,RwARECAAAAA=").unwrap();
+        assert_eq!(bytes, vec![0x47, 0x00, 0x11, 0x10, 0x20, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn decodes_plain_data_url() {
+        let bytes = decode_data_url("data:text/plain,hello%20world").unwrap();
+        assert_eq!(bytes, b"hello world");
+    }
+
+    #[test]
+    fn rejects_malformed_data_url() {
+        assert!(decode_data_url("data:video/mp2t;base64").is_err());
+        assert!(decode_data_url("data:video/mp2t;base64,!!!").is_err());
+    }
+
+    #[test]
+    fn parses_concurrency() {
+        assert_eq!(parse_concurrency("16"), Ok(Concurrency::Fixed(16)));
+        assert_eq!(parse_concurrency("Auto"), Ok(Concurrency::Auto));
+        assert!(parse_concurrency("0").is_err());
+        assert!(parse_concurrency("fast").is_err());
+    }
+
+    #[test]
+    fn parses_scale() {
+        assert_eq!(parse_scale("1280x720"), Ok("1280:720".to_string()));
+        assert_eq!(parse_scale("1920X1080"), Ok("1920:1080".to_string()));
+        // 单边 -1/-2 由 ffmpeg 按宽高比推算
+        assert_eq!(parse_scale("-1x720"), Ok("-1:720".to_string()));
+        assert_eq!(parse_scale("1280x-2"), Ok("1280:-2".to_string()));
+        for invalid in ["-1x-1", "0x720", "1280x0", "-3x720", "1280x-720", "1280", "1280x", "x720", "widexhigh", "1280*720"] {
+            assert!(parse_scale(invalid).is_err(), "{}", invalid);
         }
+    }
 
-        lines.push(Line::from(line_spans));
+    #[test]
+    fn parses_fps() {
+        assert_eq!(parse_fps("30"), Ok("30".to_string()));
+        assert_eq!(parse_fps("29.97"), Ok("29.97".to_string()));
+        assert_eq!(parse_fps("30000/1001"), Ok("30000/1001".to_string()));
+        for invalid in ["0", "-30", "0/1", "30/0", "-30/1", "30/", "/1001", "inf", "NaN", "fast", ""] {
+            assert!(parse_fps(invalid).is_err(), "{}", invalid);
+        }
     }
 
-    let paragraph = Paragraph::new(lines)
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(COLOR_NEON_CYAN))
+    #[tokio::test]
+    async fn failed_redownload_of_a_truncated_segment_counts_as_failed() {
+        assert_eq!(parse_ratio("0.1"), Ok(0.1));
+        assert_eq!(parse_ratio("1"), Ok(1.0));
+        for invalid in ["0", "-0.5", "1.5", "abc"] {
+            assert!(parse_ratio(invalid).is_err(), "{}", invalid);
+        }
+
+        let dir = test_dir("small");
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), dir.clone());
+        fs::create_dir_all(&downloader.temp_dir).await.unwrap();
+        let segment = |url: &str| Segment { url: url.to_string(), duration: 2.0, discontinuity: false, byte_range: None, init: None };
+        let segments = [
+            segment("data:video/mp2t,full"),
+            segment("data:video/mp2t,full"),
+            segment("data:video/mp2t;base64,%%%"),
+        ];
+        for (i, content) in [&[0u8; 100][..], &[0; 100], &[0; 1]].into_iter().enumerate() {
+            fs::write(downloader.segment_path(i).unwrap(), content).await.unwrap();
+        }
+        let stats = Mutex::new(DownloadStats::new(3, 6.0));
+        stats.lock().await.downloaded_segments = 3;
+
+        assert_eq!(downloader.redownload_small_segments(&segments, 0.5, &stats).await.unwrap(), 1);
+        assert!(!downloader.segment_path(2).unwrap().exists());
+        let stats = stats.lock().await;
+        assert_eq!((stats.downloaded_segments, stats.failed_segments), (2, 1));
+        assert_eq!(stats.failed_indices, [2]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn sequential_window_does_not_take_the_url() {
+        let args = Args::try_parse_from(["surge-wave", "--sequential", "https://example.com/a.m3u8", "-o", "out"]).unwrap();
+        assert_eq!(args.sequential, Some(1));
+        assert_eq!(args.url.as_deref(), Some("https://example.com/a.m3u8"));
+        let args = Args::try_parse_from(["surge-wave", "https://example.com/a.m3u8", "-o", "out", "--sequential=3"]).unwrap();
+        assert_eq!(args.sequential, Some(3));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn opens_progress_fifo_without_blocking() {
+        let dir = test_dir("fifo");
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("progress");
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+
+        // 没有读取方时立即返回，不占住运行时
+        assert!(open_progress_socket(&path).unwrap().is_none());
+
+        let mut receiver = tokio::net::unix::pipe::OpenOptions::new().open_receiver(&path).unwrap();
+        let mut writer = open_progress_socket(&path).unwrap().unwrap();
+        writer.write_all(b"{}\n").unwrap();
+        let mut line = [0u8; 3];
+        receiver.read_exact(&mut line).await.unwrap();
+        assert_eq!(&line, b"{}\n");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn auto_rename_picks_the_first_free_name() {
+        let dir = test_dir("collision");
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("video.mp4");
+        assert_eq!(OnCollision::Fail.resolve(&path).unwrap(), path);
+
+        fs::write(&path, b"").await.unwrap();
+        fs::write(dir.join("video (1).mp4"), b"").await.unwrap();
+        assert!(OnCollision::Fail.resolve(&path).is_err());
+        assert_eq!(OnCollision::Overwrite.resolve(&path).unwrap(), path);
+        assert_eq!(OnCollision::Rename.resolve(&path).unwrap(), dir.join("video (2).mp4"));
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sha256_of_file_matches_known_digest() {
+        let dir = test_dir("checksum");
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("output.bin");
+        fs::write(&path, b"abc").await.unwrap();
+        assert_eq!(
+            sha256_file(&path).await.unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
         );
-    f.render_widget(paragraph, area);
-}
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 
-fn draw_activity_panel(f: &mut Frame, area: Rect, stats: &DownloadStats) {
-    let lines: Vec<Line> = if stats.activity_log.is_empty() {
-        vec![Line::from(Span::styled("Waiting...", Style::default().fg(COLOR_GRAY)))]
-    } else {
-        stats.activity_log.iter().map(|item| {
-            let (icon, color) = match item.status {
-                ActivityStatus::Success => ("✓ ", COLOR_COMPLETED),
-                ActivityStatus::Failed => ("✗ ", COLOR_FAILED),
-                ActivityStatus::Downloading => ("⟳ ", COLOR_NEON_CYAN),
-            };
+    #[test]
+    fn command_line_overrides_config_defaults() {
+        let config = |content: &str| toml::from_str::<Config>(content).unwrap();
+        let parse = |argv: &[&str], config: Config| -> Result<Args> {
+            let matches = Args::command().try_get_matches_from(
+                ["surge-wave", "https://example.com/a.m3u8", "-o", "out"].iter().chain(argv),
+            )?;
+            let mut args = Args::from_arg_matches(&matches)?;
+            config.apply(&mut args, |id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine))?;
+            Ok(args)
+        };
+
+        let defaults = config(
+            "dir = \"~/Videos\"\n\
+             concurrent = \"auto\"\n\
+             retries = 7\n\
+             limit-rate = 2.5\n\
+             ramp-up = \"3\"\n\
+             user-agent = \"config-ua\"\n\
+             headers = [\"Referer: https://example.com/\"]\n",
+        );
+        let args = parse(&["--retries", "1", "-H", "X-Test: 1"], defaults).unwrap();
+        assert_eq!(args.dir, "~/Videos");
+        assert_eq!(args.concurrent, Concurrency::Auto);
+        assert_eq!(args.retries, 1);
+        assert_eq!(args.limit_rate, Some(2));
+        assert_eq!(args.ramp_up, 3);
+        assert_eq!(args.user_agent.unwrap(), "config-ua");
+        assert_eq!(args.headers.len(), 2);
+
+        // 命令行显式给出与默认值相同的值时同样优先
+        assert_eq!(parse(&["--concurrent", "10"], config("concurrent = 4")).unwrap().concurrent, Concurrency::Fixed(10));
+        assert!(parse(&[], config("retries = 1.5")).is_err());
+        assert!(parse(&[], config("connect-timeout = 0")).is_err());
+        // 同一个参数在命令行中重复给出仍然报错
+        assert!(parse(&["--retries", "1", "--retries", "2"], Config::default()).is_err());
+        assert!(toml::from_str::<Config>("concurency = 4").is_err());
+    }
 
-            let name = if item.name.len() > 20 {
-                format!("{}...", &item.name[..17])
-            } else {
-                item.name.clone()
-            };
+    #[test]
+    fn reads_batch_file_entries() {
+        let dir = test_dir("batch");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("batch.txt");
+        std::fs::write(&path, "# 周一\nhttps://a.example/index.m3u8  第一集\n\nhttps://b.example/index.m3u8 Episode 2\n").unwrap();
+        assert_eq!(read_batch_file(&path).unwrap(), [
+            ("https://a.example/index.m3u8".to_string(), "第一集".to_string()),
+            ("https://b.example/index.m3u8".to_string(), "Episode 2".to_string()),
+        ]);
 
-            Line::from(vec![
-                Span::styled(icon, Style::default().fg(color)),
-                Span::raw(name),
-            ])
-        }).collect()
-    };
+        std::fs::write(&path, "https://a.example/index.m3u8 ../../etc/evil\nhttps://b.example/index.m3u8 ..\n").unwrap();
+        assert_eq!(read_batch_file(&path).unwrap(), [
+            ("https://a.example/index.m3u8".to_string(), ".._.._etc_evil".to_string()),
+            ("https://b.example/index.m3u8".to_string(), "__".to_string()),
+        ]);
 
-    let paragraph = Paragraph::new(lines)
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(COLOR_NEON_PURPLE))
-                .title(Span::styled("Activity", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)))
-        );
-    f.render_widget(paragraph, area);
-}
+        std::fs::write(&path, "https://a.example/index.m3u8\n").unwrap();
+        assert!(read_batch_file(&path).is_err());
 
-fn draw_stats_panel(f: &mut Frame, area: Rect, stats: &DownloadStats) {
-    let elapsed = stats.elapsed_time();
-    let eta = stats.eta();
+        // 条目名称用作临时目录和报告文件名时不能跳出所在目录
+        assert_eq!(safe_file_name("../x"), ".._x");
+        assert_eq!(safe_file_name(".."), "__");
+        assert_eq!(safe_file_name("第一集"), "第一集");
+        assert_eq!(batch_item_path(Path::new("out/report.json"), Some("a/b")), Path::new("out/report-a_b.json"));
+        assert_eq!(batch_item_path(Path::new("chunks"), Some("ep1")), Path::new("chunks-ep1"));
+        assert_eq!(batch_item_path(Path::new("report.csv"), None), Path::new("report.csv"));
 
-    let lines = vec![
-        Line::from(vec![
-            Span::styled("Speed: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::styled(format!("{:.1}", stats.current_speed), Style::default().fg(COLOR_NEON_PINK).add_modifier(Modifier::BOLD)),
-        ]),
-        Line::from(vec![
-            Span::styled("Down: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::styled(
-                format!("{} MB", stats.downloaded_bytes / (1024 * 1024)),
-                Style::default().fg(COLOR_NEON_PINK).add_modifier(Modifier::BOLD)
-            ),
-        ]),
-        Line::from(vec![
-            Span::styled("Time: ", Style::default().fg(COLOR_NEON_CYAN)),
-            Span::styled(
-                format!("{}m{}s", elapsed.as_secs() / 60, elapsed.as_secs() % 60),
-                Style::default().fg(COLOR_NEON_PINK).add_modifier(Modifier::BOLD)
-            ),
-        ]),
-        if let Some(eta_duration) = eta {
-            Line::from(vec![
-                Span::styled("ETA: ", Style::default().fg(COLOR_NEON_CYAN)),
-                Span::styled(
-                    format!("{}m{}s", eta_duration.as_secs() / 60, eta_duration.as_secs() % 60),
-                    Style::default().fg(COLOR_NEON_PINK).add_modifier(Modifier::BOLD)
-                ),
-            ])
-        } else {
-            Line::from("")
-        },
-    ];
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    let paragraph = Paragraph::new(lines)
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(COLOR_NEON_PURPLE))
-                .title(Span::styled("Stats", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)))
-        );
-    f.render_widget(paragraph, area);
-}
+    #[test]
+    fn batch_progress_counts_finished_items_and_the_current_one() {
+        let batch = BatchProgress { current: 1, total: 4, failed: 0 };
+        assert_eq!(batch.overall_percent(0.0), 0.0);
+        assert_eq!(batch.overall_percent(50.0), 12.5);
+        let batch = BatchProgress { current: 4, total: 4, failed: 1 };
+        assert_eq!(batch.overall_percent(100.0), 100.0);
+        assert_eq!(progress_bar(50.0, 10, true), "#####.....");
+        assert_eq!(progress_bar(120.0, 4, true), "####");
+    }
 
-fn draw_chunkmap_panel(f: &mut Frame, area: Rect, stats: &DownloadStats) {
-    let chunks_per_row = ((area.width as usize).saturating_sub(2)) / 2;
-    let mut lines = Vec::new();
-    let mut current_line = Vec::new();
+    #[test]
+    fn rejects_html_error_page_as_segment() {
+        let mut ts = [0xFFu8; 188].repeat(3);
+        ts[0] = 0x47;
+        ts[188] = 0x47;
+        ts[376] = 0x47;
+        assert!(check_media_content(Some("video/mp2t"), &ts).is_ok());
+        assert!(check_media_content(None, b"\0\0\0\x18ftypiso6").is_ok());
+        // 加密片段认不出格式，但也不像文本
+        assert!(check_media_content(Some("application/octet-stream"), &[0x8A, 0x13, 0x07, 0xC4]).is_ok());
 
-    for (i, state) in stats.chunk_states.iter().enumerate() {
-        if i > 0 && i % chunks_per_row == 0 {
-            lines.push(Line::from(current_line.clone()));
-            current_line.clear();
-        }
+        assert!(check_media_content(Some("text/html; charset=utf-8"), b"Access denied").is_err());
+        assert!(check_media_content(None, b"\n<!DOCTYPE html><html>").is_err());
+        // 声明了媒体类型时不按首字节猜，加密数据可能恰好以 < 开头
+        assert!(check_media_content(Some("video/mp2t"), b"<\x8A\x13\x07\xC4").is_ok());
+        assert!(check_media_content(Some("application/octet-stream"), b"{\x8A\x13").is_ok());
+        assert!(check_media_content(None, b"").is_err());
+        ts[188] = 0x00;
+        assert!(check_media_content(None, &ts).is_err());
+    }
 
-        let (color, _) = match state {
-            ChunkState::Completed => (COLOR_COMPLETED, "■ "),
-            ChunkState::Downloading => (COLOR_NEON_PINK, "■ "),
-            ChunkState::Failed => (COLOR_FAILED, "■ "),
-            ChunkState::Pending => (COLOR_GRAY, "■ "),
-        };
+    #[test]
+    fn labels_http_failures_with_status_code() {
+        let error: anyhow::Error = HttpStatusError {
+            url: "https://example.com/0.ts".to_string(),
+            status: http::StatusCode::FORBIDDEN,
+        }
+        .into();
+        assert_eq!(error.to_string(), "Segment https://example.com/0.ts returned HTTP 403 Forbidden");
+        assert_eq!(with_failure_reason("segment_00000.ts".to_string(), &error), "segment_00000.ts (HTTP 403)");
+        let other = anyhow::anyhow!("connection reset");
+        assert_eq!(with_failure_reason("segment_00000.ts".to_string(), &other), "segment_00000.ts");
+    }
 
-        current_line.push(Span::styled("■ ", Style::default().fg(color)));
+    fn render(width: u16, height: u16, stats: &DownloadStats, ui: &UiOptions) -> String {
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(width, height)).unwrap();
+        terminal.draw(|f| draw_ui(f, stats, "https://example.com/index.m3u8", "video.mp4", ui)).unwrap();
+        terminal.backend().buffer().content.iter().map(|cell| cell.symbol()).collect()
     }
 
-    if !current_line.is_empty() {
-        lines.push(Line::from(current_line));
+    fn test_ui(hidden_panels: Vec<Panel>) -> UiOptions {
+        UiOptions { ascii: true, color_depth: ColorDepth::Full, theme: Theme::SURGE, bits: false, by_time: false, hidden_panels }
     }
 
-    let paragraph = Paragraph::new(lines)
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .border_style(Style::default().fg(COLOR_NEON_PURPLE))
-                .title(Span::styled("Chunks", Style::default().fg(COLOR_NEON_CYAN).add_modifier(Modifier::BOLD)))
-        );
-    f.render_widget(paragraph, area);
-}
+    #[test]
+    fn themes_stay_distinguishable_without_full_color() {
+        let ui = |color_depth| UiOptions { color_depth, theme: Theme::SOLARIZED, ..test_ui(Vec::new()) };
+        let theme = Theme::SOLARIZED;
 
-struct M3U8Downloader {
-    url: String,
-    output_dir: PathBuf,
-    temp_dir: PathBuf,
-    client: wreq::Client,
-    concurrent_limit: usize,
-    custom_headers: http::HeaderMap,
-}
+        let mono = ui(ColorDepth::Mono);
+        assert_eq!(mono.fg(theme.completed), Style::default().add_modifier(Modifier::REVERSED));
+        assert_eq!(mono.fg(theme.failed), Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED));
+        assert_eq!(mono.fg(theme.label).fg, None);
 
-impl M3U8Downloader {
-    fn new(url: String, output_dir: PathBuf, concurrent_limit: usize, headers: Vec<String>) -> Self {
-        let temp_dir = output_dir.join("temp");
-        // Emulate a real Chrome browser's TLS (JA3/JA4) and HTTP/2 fingerprints so the
-        // requests get past Cloudflare bot protection. A plain reqwest client is
-        // fingerprinted as a script and rejected with 403 regardless of headers.
-        let client = wreq::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .emulation(wreq_util::Emulation::Chrome137)
-            .build()
-            .expect("Failed to create HTTP client");
+        // 256 色的 Solarized 在 8 色终端上退回最接近的基本色
+        let basic = ui(ColorDepth::Basic);
+        assert_eq!(basic.fg(theme.failed).fg, Some(Color::Red));
+        assert_eq!(basic.fg(theme.muted), Style::default().add_modifier(Modifier::DIM));
+        assert_eq!(color_to_rgb(Color::Indexed(196)), [255, 0, 0]);
 
-        let mut custom_headers = http::HeaderMap::new();
-        for h in &headers {
-            if let Some((key, value)) = h.split_once(':') {
-                if let (Ok(k), Ok(v)) = (
-                    http::header::HeaderName::from_bytes(key.trim().as_bytes()),
-                    http::header::HeaderValue::from_str(value.trim()),
-                ) {
-                    custom_headers.insert(k, v);
+        // Chunks 面板的每种状态在任何配色和颜色数下都要看得出区别
+        let states = [
+            ChunkState::Pending,
+            ChunkState::Downloading,
+            ChunkState::Partial,
+            ChunkState::Completed,
+            ChunkState::Failed,
+        ];
+        for theme in [Theme::SURGE, Theme::SOLARIZED] {
+            for color_depth in [ColorDepth::Basic, ColorDepth::Mono] {
+                let ui = UiOptions { color_depth, theme, ..test_ui(Vec::new()) };
+                let styles: Vec<Style> = states.iter().map(|state| ui.fg(state.color(&theme))).collect();
+                for (i, a) in styles.iter().enumerate() {
+                    for (j, b) in styles.iter().enumerate().skip(i + 1) {
+                        assert_ne!(a, b, "{:?} {:?}: states {} and {} look the same", color_depth, theme.completed, i, j);
+                    }
                 }
             }
         }
+    }
 
-        Self {
-            url,
-            output_dir,
-            temp_dir,
-            client,
-            concurrent_limit,
-            custom_headers,
-        }
+    #[test]
+    fn hidden_panels_are_not_drawn_and_small_terminals_fall_back() {
+        let stats = DownloadStats::new(10, 10.0);
+        let full = render(120, 40, &stats, &test_ui(Vec::new()));
+        assert!(full.contains("Peak:"));
+        let without_speed = render(120, 40, &stats, &test_ui(vec![Panel::Speed]));
+        assert!(!without_speed.contains("Peak:"));
+        assert!(without_speed.contains("URL:"));
+
+        let compact = render(40, 10, &stats, &test_ui(Vec::new()));
+        assert!(compact.contains("0/10"));
+        assert!(!compact.contains("URL:"));
     }
 
-    fn build_request(&self, url: &str) -> wreq::RequestBuilder {
-        let mut req = self.client.get(url);
-        if self.custom_headers.is_empty() {
-            let referer = if let Ok(parsed) = Url::parse(url) {
-                format!("{}://{}/", parsed.scheme(), parsed.host_str().unwrap_or(""))
-            } else {
-                String::new()
-            };
-            req = req
-                .header("Referer", &referer)
-                .header("Origin", referer.trim_end_matches('/'));
-        } else {
-            for (key, value) in &self.custom_headers {
-                req = req.header(key, value);
-            }
+    #[test]
+    fn panels_render_into_tiny_areas_without_panicking() {
+        let mut stats = DownloadStats::new(300, 300.0);
+        stats.speed_history.extend([0.5, 3.0, 1.2, 0.0, 2.2]);
+        stats.log_activity("segment_00001.ts".to_string(), ActivityStatus::Success);
+        let ui = test_ui(Vec::new());
+
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(4, 4)).unwrap();
+        for (width, height) in [(0, 0), (1, 1), (3, 2), (4, 4)] {
+            let area = Rect::new(0, 0, width, height);
+            terminal
+                .draw(|f| {
+                    draw_info_panel(f, area, &stats, "https://example.com/index.m3u8", "video.mp4", &ui);
+                    draw_graph_panel(f, area, &stats, &ui);
+                    draw_activity_panel(f, area, &stats, &ui);
+                    draw_stats_panel(f, area, &stats, &ui);
+                    draw_chunkmap_panel(f, area, &stats, &ui);
+                })
+                .unwrap();
+        }
+        for (width, height) in [(0, 0), (1, 1)] {
+            render(width, height, &stats, &ui);
         }
-        req
     }
 
-    async fn fetch_m3u8(&self) -> Result<Vec<String>> {
-        println!("📡 正在解析M3U8文件...");
+    #[test]
+    fn stdout_output_uses_streamable_formats() {
+        assert!(is_stdout("-"));
+        assert!(!is_stdout("-video"));
+        assert!(Container::Mp4.pipe_format_args().contains(&"frag_keyframe+empty_moov"));
+        assert_eq!(Container::Mkv.pipe_format_args(), ["-f", "matroska"]);
+    }
 
-        let response = self.build_request(&self.url)
-            .header("Accept", "*/*")
-            .send()
-            .await?;
+    #[test]
+    fn formats_bytes_with_adaptive_units() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(512 * 1024), "512.0 KB");
+        assert_eq!(format_bytes(1536 * 1024), "1.5 MB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GB");
+        assert_eq!(format_bytes(5000 * 1024 * 1024 * 1024), "5000.0 GB");
+    }
 
-        let status = response.status();
-        if !status.is_success() {
-            anyhow::bail!("HTTP request failed with status: {}", status);
-        }
+    #[test]
+    fn truncates_by_display_width_on_char_boundaries() {
+        use unicode_width::UnicodeWidthStr;
 
-        let content = response.text().await?;
+        assert_eq!(truncate_display("segment_00001.ts", 20), "segment_00001.ts");
+        assert_eq!(truncate_display("https://example.com/very/long/path.m3u8", 25), "https://example.com/ve...");
 
-        if content.trim_start().starts_with('<') {
-            if content.contains("cloudflare") || content.contains("Cloudflare") {
-                anyhow::bail!(
-                    "Request blocked by Cloudflare. The server is rejecting automated requests.\n\
-                     Try using a different network, VPN, or add custom headers with --header."
-                );
-            }
-            anyhow::bail!("Server returned HTML instead of M3U8 content. The URL may require authentication or is geo-restricted.");
-        }
+        let url = "https://视频.example.com/直播/频道🎬/index.m3u8";
+        let shown = truncate_display(url, 25);
+        assert!(shown.ends_with("..."));
+        assert!(shown.width() <= 25, "{} is {} columns wide", shown, shown.width());
+        assert!(url.starts_with(shown.trim_end_matches("...")));
 
-        let parsed = m3u8_rs::parse_playlist_res(content.as_bytes())
-            .map_err(|e| anyhow::anyhow!("Failed to parse M3U8: {:?}", e))?;
+        let name = "第一集🎬第二部分_segment_00001.ts";
+        let shown = truncate_display(name, 20);
+        assert!(shown.width() <= 20);
+        assert!(shown.starts_with("第一集🎬"));
+    }
 
-        let segments = match parsed {
-            Playlist::MasterPlaylist(pl) => {
-                let best_variant = pl.variants.iter().max_by_key(|v| v.bandwidth)
-                    .context("No variants found")?;
+    #[test]
+    fn eta_follows_recent_speed() {
+        let mut stats = DownloadStats::new(10, 0.0);
+        stats.downloaded_segments = 5;
+        stats.downloaded_bytes = 5 * 1024 * 1024;
 
-                let variant_url = self.resolve_url(&best_variant.uri)?;
-                println!("  ✓ 选择最高质量流");
+        // 开头很慢，最近 10 次采样稳定在 1 MB/s：剩余 5 MB 约 5 秒
+        stats.speed_history.extend([0.01; 20]);
+        stats.speed_history.extend([1.0; ETA_SPEED_SAMPLES]);
+        assert_eq!(stats.eta(), Some(Duration::from_secs(5)));
+
+        stats.live = true;
+        assert_eq!(stats.eta(), None);
+    }
 
-                let response = self.build_request(&variant_url)
-                    .send()
-                    .await?;
-                let content = response.text().await?;
-                let parsed = m3u8_rs::parse_playlist_res(content.as_bytes())
-                    .map_err(|e| anyhow::anyhow!("Failed to parse: {:?}", e))?;
+    #[test]
+    fn speed_counts_bytes_of_unfinished_segments() {
+        let mut stats = DownloadStats::new(1, 0.0);
+        stats.start(0);
+        stats.last_update = Instant::now() - Duration::from_secs(1);
+        // 片段还没下载完，已经收到 2 MB
+        stats.transferred.fetch_add(2 * 1024 * 1024, Ordering::Relaxed);
+        stats.sample_speed();
 
-                match parsed {
-                    Playlist::MediaPlaylist(media_pl) => media_pl.segments.iter()
-                        .map(|seg| self.resolve_url(&seg.uri))
-                        .collect::<Result<Vec<_>>>()?,
-                    _ => anyhow::bail!("Invalid media playlist"),
-                }
-            }
-            Playlist::MediaPlaylist(pl) => {
-                pl.segments.iter()
-                    .map(|seg| self.resolve_url(&seg.uri))
-                    .collect::<Result<Vec<_>>>()?
-            }
-        };
+        assert_eq!(stats.downloaded_segments, 0);
+        assert!((1.9..=2.0).contains(&stats.current_speed), "{}", stats.current_speed);
+        assert_eq!(stats.speed_history.len(), 1);
+    }
 
-        println!("  ✓ 找到 {} 个视频片段\n", segments.len());
-        Ok(segments)
+    #[test]
+    fn time_progress_weighs_segments_by_duration() {
+        let mut stats = DownloadStats::new(4, 0.0);
+        stats.total_duration = 40.0;
+        stats.update(0, 1, 30.0, Duration::ZERO, String::new());
+
+        assert_eq!(stats.progress_percent(), 25.0);
+        assert_eq!(stats.time_progress_percent(), 75.0);
+        assert_eq!(format_clock(Duration::from_secs(5025)), "01:23:45");
+
+        // 没有时长信息时退回按片段数
+        stats.total_duration = 0.0;
+        assert_eq!(stats.time_progress_percent(), 25.0);
     }
 
-    fn resolve_url(&self, uri: &str) -> Result<String> {
-        let base_url = Url::parse(&self.url)?;
-        let resolved = base_url.join(uri)?;
-        Ok(resolved.to_string())
+    #[test]
+    fn failure_limit_trips_once_exceeded() {
+        let mut stats = DownloadStats::new(100, 100.0);
+        stats.max_failures = Some(2);
+        stats.fail(0, 1.0, String::new());
+        stats.fail(1, 1.0, String::new());
+        assert!(!stats.failure_limit_exceeded);
+        stats.fail(2, 1.0, String::new());
+        assert!(stats.failure_limit_exceeded);
+
+        let mut stats = DownloadStats::new(100, 100.0);
+        stats.max_failure_pct = Some(parse_percent("1%").unwrap());
+        stats.fail(0, 1.0, String::new());
+        assert!(!stats.failure_limit_exceeded);
+        stats.fail(1, 1.0, String::new());
+        assert!(stats.failure_limit_exceeded);
+
+        assert!(parse_percent("101").is_err());
     }
 
-    async fn download_segments(
-        &self,
-        segments: Vec<String>,
-        stats: Arc<Mutex<DownloadStats>>,
-    ) -> Result<()> {
-        fs::create_dir_all(&self.temp_dir).await?;
+    #[test]
+    fn chunk_is_completed_only_when_all_its_segments_are() {
+        let mut stats = DownloadStats::new(250, 0.0);
+        // 250 个片段分成 100 组，第 0 组是片段 0..2，第 1 组是片段 2..5
+        stats.update(0, 1, 1.0, Duration::ZERO, String::new());
+        stats.update(2, 1, 1.0, Duration::ZERO, String::new());
+        stats.update(3, 1, 1.0, Duration::ZERO, String::new());
+        stats.update(4, 1, 1.0, Duration::ZERO, String::new());
+        stats.fail(5, 1.0, String::new());
 
-        let downloader = Arc::new(self);
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(self.concurrent_limit));
+        let chunks = stats.chunk_states();
+        assert_eq!(chunks.len(), 100);
+        assert!(chunks[0] == ChunkState::Partial);
+        assert!(chunks[1] == ChunkState::Completed);
+        assert!(chunks[2] == ChunkState::Failed);
+        assert!(chunks[3] == ChunkState::Pending);
+    }
 
-        stream::iter(segments.into_iter().enumerate())
-            .for_each_concurrent(None, |(i, url)| {
-                let downloader = Arc::clone(&downloader);
-                let stats = Arc::clone(&stats);
-                let semaphore = Arc::clone(&semaphore);
+    #[test]
+    fn scrolled_activity_log_stays_on_the_same_entries() {
+        let mut stats = DownloadStats::new(10, 0.0);
+        stats.activity_capacity = 5;
+        for i in 0..5 {
+            stats.log_activity(format!("#{}", i), ActivityStatus::Success);
+        }
+        stats.scroll_activity(2);
+        stats.log_activity("#5".to_string(), ActivityStatus::Success);
+        assert_eq!(stats.activity_log.len(), 5);
+        assert_eq!(stats.activity_scroll, 3);
 
-                async move {
-                    let _permit = semaphore.acquire().await.unwrap();
-                    let output_path = downloader.temp_dir.join(format!("segment_{:05}.ts", i));
-                    let segment_name = format!("segment_{:05}.ts", i);
+        stats.scroll_activity(100);
+        assert_eq!(stats.activity_scroll, 4);
+        stats.scroll_activity(-100);
+        assert_eq!(stats.activity_scroll, 0);
+        // 停在最新一条时不跟随滚动
+        stats.log_activity("#6".to_string(), ActivityStatus::Success);
+        assert_eq!(stats.activity_scroll, 0);
+    }
 
-                    match downloader.download_segment(&url, &output_path).await {
-                        Ok(bytes) => {
-                            let mut stats = stats.lock().await;
-                            stats.update(i, bytes, segment_name);
-                        }
-                        Err(_) => {
-                            let mut stats = stats.lock().await;
-                            stats.fail(i, segment_name);
-                        }
-                    }
-                }
-            })
-            .await;
+    #[tokio::test]
+    async fn lists_init_segment_once_before_its_fmp4_segments() {
+        let dir = test_dir("init");
+        fs::create_dir_all(&dir).await.unwrap();
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), dir.clone());
 
-        Ok(())
+        let segment = |url: &str| Segment {
+            url: url.to_string(),
+            duration: 1.0,
+            discontinuity: false,
+            byte_range: None,
+            init: Some(InitSegment { url: "data:video/mp4,ftyp".to_string(), byte_range: None }),
+        };
+        let segments = [segment("data:video/mp4,moof0"), segment("data:video/mp4,moof1")];
+        downloader.register_segments(0, &segments);
+        assert_eq!(downloader.prefetch_init_segments(&segments).await.unwrap(), 1);
+        fs::create_dir_all(&downloader.temp_dir).await.unwrap();
+        for (i, segment) in segments.iter().enumerate() {
+            // 字节数只算片段本身，不含初始化片段
+            assert_eq!(downloader.download_segment(i, segment).await.unwrap(), 5);
+        }
+        assert_eq!(fs::read(downloader.segment_path(0).unwrap()).await.unwrap(), b"moof0");
+        assert_eq!(fs::read(downloader.temp_dir.join("init_00.mp4")).await.unwrap(), b"ftyp");
+
+        let inputs = downloader.temp_files().unwrap().merge_inputs(0..2).await.unwrap();
+        assert_eq!(inputs.len(), 1);
+        let list = inputs[0].to_str().unwrap().strip_prefix("concatf:").unwrap();
+        let entries: Vec<String> = std::fs::read_to_string(list)
+            .unwrap()
+            .lines()
+            .map(|line| Path::new(line).file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(entries, ["init_00.mp4", "segment_00000.m4s", "segment_00001.m4s"]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
     }
 
-    async fn download_segment(&self, url: &str, output_path: &PathBuf) -> Result<u64> {
-        let response = self.build_request(url)
-            .send()
-            .await?;
-        let bytes = response.bytes().await?;
-        let len = bytes.len() as u64;
+    #[tokio::test]
+    async fn byte_range_without_offset_continues_previous_segment() {
+        let dir = test_dir("byterange");
+        fs::create_dir_all(&dir).await.unwrap();
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), dir.clone());
+
+        let ranged = |uri: &str, length, offset| m3u8_rs::MediaSegment {
+            uri: uri.to_string(),
+            duration: 1.0,
+            byte_range: Some(m3u8_rs::ByteRange { length, offset }),
+            ..Default::default()
+        };
+        let playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![
+                ranged("data:video/mp2t,abcdefgh", 3, Some(1)),
+                ranged("data:video/mp2t,abcdefgh", 2, None),
+                ranged("data:video/mp2t,other", 2, None),
+            ],
+            ..Default::default()
+        };
+        let segments = downloader.collect_segments(&playlist, "https://example.com/index.m3u8").unwrap();
+        let ranges: Vec<_> = segments.iter().map(|s| s.byte_range.unwrap()).collect();
+        assert_eq!(ranges, [
+            ByteRange { offset: 1, length: 3 },
+            ByteRange { offset: 4, length: 2 },
+            ByteRange { offset: 0, length: 2 },
+        ]);
+        assert_eq!(ranges[1].header(), "bytes=4-5");
 
-        let mut file = File::create(output_path).await?;
-        file.write_all(&bytes).await?;
+        fs::create_dir_all(&downloader.temp_dir).await.unwrap();
+        downloader.download_segment(1, &segments[1]).await.unwrap();
+        let path = downloader.segment_path(1).unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), b"ef");
 
-        Ok(len)
+        fs::remove_dir_all(&dir).await.unwrap();
     }
 
-    async fn merge_to_mp4(&self, output_name: &str) -> Result<PathBuf> {
-        let filelist_path = self.temp_dir.join("filelist.txt");
+    #[test]
+    fn reads_partial_segments_including_the_unfinished_tail() {
+        let content = "#EXTM3U\n\
+            #EXT-X-TARGETDURATION:4\n\
+            #EXT-X-PART-INF:PART-TARGET=1.0\n\
+            #EXT-X-MEDIA-SEQUENCE:10\n\
+            #EXT-X-PART:DURATION=1.0,URI=\"seg10.part0.m4s\",INDEPENDENT=YES\n\
+            #EXT-X-PART:DURATION=1.0,URI=\"seg10.part1.m4s\"\n\
+            #EXTINF:2.0,\n\
+            seg10.m4s\n\
+            #EXT-X-DISCONTINUITY\n\
+            #EXT-X-PART:DURATION=0.5,URI=\"tail.mp4\",BYTERANGE=\"100@0\"\n\
+            #EXT-X-PART:DURATION=0.5,URI=\"tail.mp4\",BYTERANGE=\"50\"\n\
+            #EXT-X-PART:DURATION=0.5,URI=\"gap.m4s\",GAP=YES\n";
+        let parts = partial_segments(content, 10);
+        let summary: Vec<_> = parts.iter().map(|p| (p.sequence, p.uri.as_str(), p.discontinuity, p.gap)).collect();
+        assert_eq!(summary, [
+            (10, "seg10.part0.m4s", false, false),
+            (10, "seg10.part1.m4s", false, false),
+            (11, "tail.mp4", true, false),
+            (11, "tail.mp4", false, false),
+            (11, "gap.m4s", false, true),
+        ]);
+        assert_eq!(parts[3].byte_range, Some(ByteRange { offset: 100, length: 50 }));
+        assert_eq!(tag_attribute("URI=\"a,b.m4s\",DURATION=1.0", "DURATION"), Some("1.0"));
+    }
 
-        let mut ts_files = Vec::new();
-        let mut read_dir = fs::read_dir(&self.temp_dir).await?;
-        while let Some(entry) = read_dir.next_entry().await? {
-            ts_files.push(entry);
-        }
+    #[test]
+    fn live_parts_replace_the_parent_segment_once_it_completes() {
+        let segment = |url: &str| Segment { url: url.to_string(), duration: 1.0, discontinuity: false, byte_range: None, init: None };
+        let urls = |segments: Vec<Segment>| segments.into_iter().map(|s| s.url).collect::<Vec<_>>();
+        let mut live = LivePlaylist {
+            playlist_url: "https://example.com/live.m3u8".to_string(),
+            target_duration: 4.0,
+            part_target: Some(1.0),
+            next_sequence: 11,
+            next_part: 0,
+        };
 
-        ts_files.sort_by_key(|e| e.file_name());
+        // 第 11 个片段还没完成，先下载已有的两个部分片段
+        let parts = [(11, Some(segment("11.0"))), (11, Some(segment("11.1")))];
+        assert_eq!(urls(live.take_new(10, vec![segment("10")], &parts)), ["11.0", "11.1"]);
+        assert_eq!((live.next_sequence, live.next_part), (11, 2));
 
-        let mut filelist_content = String::new();
-        for entry in ts_files {
-            if entry.path().extension().and_then(|s| s.to_str()) == Some("ts") {
-                let abs_path = entry.path().canonicalize()?;
-                filelist_content.push_str(&format!("file '{}'\n", abs_path.display()));
-            }
-        }
+        // 第 11 个片段完成后只补下剩余的部分片段，GAP 跳过；第 12 个片段整个下载
+        let parts = [
+            (11, Some(segment("11.0"))),
+            (11, Some(segment("11.1"))),
+            (11, Some(segment("11.2"))),
+            (11, None),
+        ];
+        let new = live.take_new(10, vec![segment("10"), segment("11"), segment("12")], &parts);
+        assert_eq!(urls(new), ["11.2", "12"]);
+        assert_eq!((live.next_sequence, live.next_part), (13, 0));
 
-        tokio::fs::write(&filelist_path, filelist_content).await?;
+        // 不是低延迟时没有部分片段，只取新的完整片段
+        assert_eq!(urls(live.take_new(12, vec![segment("12"), segment("13")], &[])), ["13"]);
+    }
 
-        let output_path = self.output_dir.join(format!("{}.mp4", output_name));
+    #[test]
+    fn resolves_segments_against_a_variant_in_another_directory() {
+        let downloader = test_downloader("https://example.com/live/master.m3u8".to_string(), test_dir("resolve"));
+        let variant_url = downloader.resolve_url("../hd/v1/index.m3u8").unwrap();
+        assert_eq!(variant_url, "https://example.com/hd/v1/index.m3u8");
 
-        println!("\n🎬 正在合并视频片段...");
+        let segment = |uri: &str| m3u8_rs::MediaSegment { uri: uri.to_string(), duration: 1.0, ..Default::default() };
+        let playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![segment("seg0.ts"), segment("../shared/seg1.ts"), segment("/cdn/seg2.ts")],
+            ..Default::default()
+        };
+        let segments = downloader.collect_segments(&playlist, &variant_url).unwrap();
+        let urls: Vec<&str> = segments.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, [
+            "https://example.com/hd/v1/seg0.ts",
+            "https://example.com/hd/shared/seg1.ts",
+            "https://example.com/cdn/seg2.ts",
+        ]);
+    }
 
-        let status = Command::new("ffmpeg")
-            .args(&[
-                "-f", "concat",
-                "-safe", "0",
-                "-i", &filelist_path.to_string_lossy(),
-                "-c", "copy",
-                "-y",
-                &output_path.to_string_lossy(),
-            ])
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()?;
+    #[test]
+    fn counts_discontinuities_after_the_first_segment() {
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), test_dir("discontinuity"));
+        let segment = |discontinuity| m3u8_rs::MediaSegment {
+            uri: "seg.ts".to_string(),
+            duration: 1.0,
+            discontinuity,
+            ..Default::default()
+        };
+        let playlist = m3u8_rs::MediaPlaylist {
+            segments: vec![segment(true), segment(false), segment(true), segment(false), segment(true)],
+            ..Default::default()
+        };
+        let segments = downloader.collect_segments(&playlist, "https://example.com/index.m3u8").unwrap();
+        assert_eq!(count_discontinuities(&segments), 2);
+    }
 
-        if !status.success() {
-            anyhow::bail!("FFmpeg failed");
-        }
+    #[test]
+    fn reencode_fallback_keeps_the_trim_and_reports_ffmpeg_output() {
+        let copy = TranscodeOptions { trim_start: 5.0, allow_reencode: true, ..Default::default() };
+        assert_eq!(copy.ffmpeg_args(), ["-ss", "5.000", "-c", "copy"]);
+        assert_eq!(copy.reencoded().ffmpeg_args(), ["-ss", "5.000", "-c:v", "libx264", "-c:a", "aac"]);
 
-        println!("✓ 成功: {}\n", output_path.display());
+        let stderr: String = (1..=12).map(|i| format!("line {}\n", i)).collect();
+        let error = ffmpeg_failure(format!("frame=1\rframe=2\r{}", stderr).as_bytes()).to_string();
+        assert!(error.starts_with("FFmpeg failed:\nline 3\n"), "{}", error);
+        assert!(error.ends_with("line 12"), "{}", error);
+        assert_eq!(ffmpeg_failure(b"").to_string(), "FFmpeg failed");
 
-        Ok(output_path)
+        // --stream-merge 的片段已经送进 ffmpeg，没有可以重来的输入
+        assert!(Args::try_parse_from(["surge-wave", "https://example.com/a.m3u8", "-o", "out", "--stream-merge", "--allow-reencode"]).is_err());
     }
 
-    async fn cleanup(&self) -> Result<()> {
-        if self.temp_dir.exists() {
-            tokio::fs::remove_dir_all(&self.temp_dir).await?;
-        }
-        Ok(())
+    #[tokio::test]
+    async fn collected_stderr_keeps_only_the_tail() {
+        let noise = vec![b'.'; FFMPEG_STDERR_KEEP * 3];
+        let stderr = [noise.as_slice(), b"\nInvalid data found when processing input\n"].concat();
+        let collected = collect_stderr(stderr.as_slice(), false).await;
+        assert!(collected.len() <= FFMPEG_STDERR_KEEP * 2);
+        assert!(ffmpeg_failure(&collected).to_string().ends_with("Invalid data found when processing input"));
     }
-}
 
-async fn run_tui(
-    stats: Arc<Mutex<DownloadStats>>,
-    url: String,
-    output: String,
-) -> Result<()> {
-    enable_raw_mode()?;
-    let mut stdout = std::io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    #[test]
+    fn clip_selects_segments_overlapping_the_window() {
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), test_dir("clip"));
+        let playlist = m3u8_rs::MediaPlaylist {
+            segments: (0..5)
+                .map(|i| m3u8_rs::MediaSegment { uri: format!("{}.ts", i), duration: 4.0, ..Default::default() })
+                .collect(),
+            ..Default::default()
+        };
+        let segments = downloader.collect_segments(&playlist, "https://example.com/index.m3u8").unwrap();
 
-    let tick_rate = Duration::from_millis(250);
-    let mut last_tick = Instant::now();
+        // 5 秒落在第 2 个片段 [4, 8) 中间，10 秒落在第 3 个片段 [8, 12) 中间
+        assert_eq!(select_clip(&segments, 5.0, Some(10.0)), (1..3, 4.0));
+        assert_eq!(select_clip(&segments, 8.0, Some(12.0)), (2..3, 8.0));
+        assert_eq!(select_clip(&segments, 0.0, None), (0..5, 0.0));
+        assert!(select_clip(&segments, 25.0, None).0.is_empty());
 
-    loop {
-        {
-            let stats_guard = stats.lock().await;
-            terminal.draw(|f| draw_ui(f, &stats_guard, &url, &output))?;
+        assert_eq!(parse_clip_time("90"), Ok(90.0));
+        assert_eq!(parse_clip_time("1:02:03.5"), Ok(3723.5));
+        assert!(parse_clip_time("1:75").is_err());
+        assert_eq!(parse_clip_start("START"), Ok(ClipStart::Playlist));
 
-            // 检查是否完成
-            if stats_guard.downloaded_segments + stats_guard.failed_segments >= stats_guard.total_segments {
-                break;
-            }
+        // 裁剪会作用到追加前已有的内容
+        for clip in ["--from", "--to"] {
+            assert!(Args::try_parse_from(["surge-wave", "https://example.com/a.m3u8", "-o", "out", "--append", clip, "10"]).is_err());
         }
+    }
 
-        let timeout = tick_rate
-            .checked_sub(last_tick.elapsed())
-            .unwrap_or_else(|| Duration::from_secs(0));
+    #[tokio::test]
+    async fn ramp_up_releases_permits_one_at_a_time() {
+        let options = DownloadOptions {
+            concurrent_limit: 5,
+            ramp_up: Duration::from_millis(40),
+            ..Default::default()
+        };
+        let downloader = M3U8Downloader::new("https://example.com/index.m3u8".to_string(), test_dir("ramp"), options);
+        let semaphore = tokio::sync::Semaphore::new(1);
+        let stats = Mutex::new(DownloadStats::new(10, 10.0));
+        stats.lock().await.concurrency = 1;
 
-        if crossterm::event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                if key.code == KeyCode::Char('q') {
-                    break;
-                }
-            }
-        }
+        let started = Instant::now();
+        downloader.ramp_up_concurrency(&semaphore, &stats).await;
+        assert_eq!(semaphore.available_permits(), 5);
+        assert_eq!(stats.lock().await.concurrency, 5);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
 
-        if last_tick.elapsed() >= tick_rate {
-            last_tick = Instant::now();
+    #[tokio::test]
+    async fn concurrency_keys_resize_the_semaphore() {
+        let options = DownloadOptions { concurrent_limit: 4, ..Default::default() };
+        let downloader = M3U8Downloader::new("https://example.com/index.m3u8".to_string(), test_dir("keys"), options);
+        let semaphore = tokio::sync::Semaphore::new(4);
+        let stats = Mutex::new(DownloadStats::new(10, 10.0));
+        {
+            let mut stats = stats.lock().await;
+            stats.concurrency = 4;
+            stats.max_concurrency = 5;
+            // 可以超过起始的 --concurrent，但不会超过 --max-concurrent
+            stats.request_concurrency(1);
+            stats.request_concurrency(1);
+            assert_eq!(stats.concurrency_target, Some(5));
+            stats.request_concurrency(-3);
         }
+
+        let follow = downloader.follow_concurrency_target(&semaphore, &stats);
+        let _ = tokio::time::timeout(CONCURRENCY_KEY_POLL * 2, follow).await;
+        assert_eq!(semaphore.available_permits(), 2);
+
+        stats.lock().await.request_concurrency(1);
+        let follow = downloader.follow_concurrency_target(&semaphore, &stats);
+        let _ = tokio::time::timeout(CONCURRENCY_KEY_POLL * 2, follow).await;
+        assert_eq!(semaphore.available_permits(), 3);
+        assert_eq!(stats.lock().await.concurrency, 3);
     }
 
-    // 恢复终端
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    #[tokio::test]
+    async fn creates_download_futures_lazily() {
+        let dir = test_dir("lazy");
+        fs::create_dir_all(&dir).await.unwrap();
+        let segments: Vec<Segment> = (0..200)
+            .map(|_| Segment {
+                url: "data:video/mp2t;base64,RwARECAAAAA=".to_string(),
+                duration: 1.0,
+                discontinuity: false,
+                byte_range: None,
+                init: None,
+            })
+            .collect();
+        let stats = Arc::new(Mutex::new(DownloadStats::new(segments.len(), 200.0)));
+        // 每个已创建的下载 future 都持有一份 stats，收到片段时记录当时的引用数
+        let max_refs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = {
+            let stats = Arc::downgrade(&stats);
+            let max_refs = Arc::clone(&max_refs);
+            let received = Arc::clone(&received);
+            std::sync::Mutex::new(move |index: usize, bytes: Bytes| {
+                max_refs.fetch_max(stats.strong_count(), std::sync::atomic::Ordering::SeqCst);
+                assert_eq!(bytes.len(), 8);
+                received.lock().unwrap().push(index);
+            })
+        };
+        let options = DownloadOptions {
+            concurrent_limit: 4,
+            max_concurrency: 8,
+            sink: Some(Arc::new(sink)),
+            ..Default::default()
+        };
+        let downloader = Arc::new(M3U8Downloader::new("https://example.com/index.m3u8".to_string(), dir.clone(), options));
+        downloader
+            .download_segments(segments, 0, Arc::clone(&stats), CancellationToken::new(), None)
+            .await
+            .unwrap();
 
-    Ok(())
-}
+        assert_eq!(stats.lock().await.downloaded_segments, 200);
+        // 测试和 download_segments 参数各持有一份，其余来自同时存在的下载任务，最多为按 + 能调到的并发数
+        let max_refs = max_refs.load(std::sync::atomic::Ordering::SeqCst);
+        assert!(max_refs <= 2 + downloader.max_concurrency, "{} download futures alive at once", max_refs - 2);
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+        // 片段只交给了 sink，临时目录里没有文件，依赖文件的步骤直接报错
+        let mut received = received.lock().unwrap().clone();
+        received.sort_unstable();
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+        assert!(!downloader.temp_dir.exists());
+        assert!(downloader.temp_files().is_err());
+        assert!(downloader.resume_existing(&[], &stats).await.is_err());
 
-    let output_dir = expand_path(&args.dir);
-    fs::create_dir_all(&output_dir).await?;
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 
-    let downloader = M3U8Downloader::new(
-        args.url.clone(),
-        output_dir,
-        args.concurrent,
-        args.headers,
-    );
+    #[tokio::test]
+    async fn emits_progress_events_for_each_segment() {
+        let dir = test_dir("events");
+        fs::create_dir_all(&dir).await.unwrap();
+        let mut downloader = test_downloader("https://example.com/index.m3u8".to_string(), dir.clone());
+        downloader.retries = 0;
+        let mut events = downloader.subscribe();
 
-    let segments = downloader.fetch_m3u8().await?;
-    let stats = Arc::new(Mutex::new(DownloadStats::new(segments.len())));
+        let segment = |url: &str| Segment {
+            url: url.to_string(),
+            duration: 1.0,
+            discontinuity: false,
+            byte_range: None,
+            init: None,
+        };
+        let segments = vec![segment("data:video/mp2t,ok"), segment("data:video/mp2t;base64,!!!")];
+        let stats = Arc::new(Mutex::new(DownloadStats::new(segments.len(), 2.0)));
+        let downloader = Arc::new(downloader);
+        downloader
+            .download_segments(segments, 0, stats, CancellationToken::new(), None)
+            .await
+            .unwrap();
+        downloader.emit(ProgressEvent::Finished);
 
-    // 启动 TUI
-    let tui_stats = Arc::clone(&stats);
-    let tui_url = args.url.clone();
-    let tui_output = args.output.clone();
-    let tui_handle = tokio::spawn(async move {
-        run_tui(tui_stats, tui_url, tui_output).await
-    });
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert!(received.contains(&ProgressEvent::SegmentStarted { index: 0 }));
+        assert!(received.contains(&ProgressEvent::SegmentCompleted { index: 0, bytes: 2 }));
+        assert!(received.contains(&ProgressEvent::SegmentFailed { index: 1 }));
+        assert_eq!(received.last(), Some(&ProgressEvent::Finished));
 
-    // 下载
-    downloader.download_segments(segments, Arc::clone(&stats)).await?;
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 
-    // 等待 TUI 完成
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    tui_handle.abort();
+    #[test]
+    fn relative_uris_inherit_the_playlist_query() {
+        let base = "https://cdn.example.com/live/index.m3u8?token=abc%2F1&exp=9#frag";
+        let resolve = |uri, inherit| resolve_against(base, uri, inherit).unwrap();
 
-    let final_stats = stats.lock().await;
-    if final_stats.failed_segments > 0 {
-        println!("⚠ 警告: {} 个片段下载失败", final_stats.failed_segments);
+        assert_eq!(resolve("seg0.ts", false), "https://cdn.example.com/live/seg0.ts");
+        assert_eq!(resolve("seg0.ts", true), "https://cdn.example.com/live/seg0.ts?token=abc%2F1&exp=9");
+        assert_eq!(resolve("/hd/seg0.ts#t=1", true), "https://cdn.example.com/hd/seg0.ts?token=abc%2F1&exp=9#t=1");
+        // 片段自带的参数优先，只补上缺少的
+        assert_eq!(resolve("seg0.ts?exp=10", true), "https://cdn.example.com/live/seg0.ts?exp=10&token=abc%2F1");
+        // 绝对地址原样保留
+        assert_eq!(resolve("https://other.example.com/seg0.ts", true), "https://other.example.com/seg0.ts");
+        assert_eq!(resolve("https://other.example.com/seg0.ts?sig=1", true), "https://other.example.com/seg0.ts?sig=1");
+        assert_eq!(
+            resolve_against("https://cdn.example.com/index.m3u8", "seg0.ts", true).unwrap(),
+            "https://cdn.example.com/seg0.ts"
+        );
     }
 
-    drop(final_stats);
+    #[tokio::test]
+    async fn downloads_data_url_segment_without_http() {
+        let dir = test_dir("data-url");
+        fs::create_dir_all(&dir).await.unwrap();
+        let downloader = test_downloader("https://example.com/index.m3u8".to_string(), dir.clone());
 
-    let output_file = downloader.merge_to_mp4(&args.output).await?;
-    downloader.cleanup().await?;
+        let uri = "data:video/mp2t;base64,RwARECAAAAA=";
+        assert_eq!(downloader.resolve_url(uri).unwrap(), uri);
 
-    let size_mb = output_file.metadata()?.len() as f64 / (1024.0 * 1024.0);
-    println!("✓ 文件: {}", output_file.display());
-    println!("✓ 大小: {:.2} MB", size_mb);
+        fs::create_dir_all(&downloader.temp_dir).await.unwrap();
+        let segment = Segment { url: uri.to_string(), duration: 1.0, discontinuity: false, byte_range: None, init: None };
+        let len = downloader.download_segment(0, &segment).await.unwrap();
+        let path = downloader.segment_path(0).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(fs::read(&path).await.unwrap()[0], 0x47);
 
-    Ok(())
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 }